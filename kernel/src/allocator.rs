@@ -1,50 +0,0 @@
-use linked_list_allocator::LockedHeap;
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
-    VirtAddr,
-};
-
-use crate::memory::{MAPPER, FRAME_ALLOCATOR};
-
-use crate::size::*;
-
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = Size::MiB(16).bytes(); // 100 KiB
-
-
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
-
-pub fn init_heap(
-) {
-    // […] map all heap pages to physical frames
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    let mut mapper = MAPPER.try_get().unwrap().lock();
-    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .expect("failed to allocate frame");
-
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, &mut *frame_allocator).expect("failed to map head to frame").flush()
-        };
-    }
-
-    // new
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
-    }
-
-}
\ No newline at end of file
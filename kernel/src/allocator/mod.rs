@@ -0,0 +1,208 @@
+pub mod slab;
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory::{MAPPER, FRAME_ALLOCATOR};
+
+use crate::size::*;
+use self::slab::{Slab, SlabStats, SLAB_SIZES};
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Mapped at boot, the heap grows from there
+pub const HEAP_SIZE: usize = Size::MiB(16).bytes();
+/// The heap never grows past `HEAP_START + HEAP_MAX_SIZE`
+pub const HEAP_MAX_SIZE: usize = Size::GiB(1).bytes();
+/// Smallest amount the heap grows by at once
+const HEAP_GROW_STEP: usize = Size::MiB(1).bytes();
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator(Mutex::new(HeapState::new()));
+
+/// Wraps the heap so its lock is never held by a preempted thread
+pub struct Allocator(Mutex<HeapState>);
+
+/// Small allocations come from the slab caches, everything else and the
+/// slabs themselves from the linked list heap
+struct HeapState {
+    heap: Heap,
+    slabs: [Slab; SLAB_SIZES.len()],
+    /// End of the mapped part of the heap region
+    mapped_end: usize,
+    /// Bytes callers asked for and have not freed
+    requested: usize,
+}
+
+unsafe impl Send for HeapState {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes of the heap region backed by frames
+    pub mapped: usize,
+    /// Bytes the linked list heap has handed out, slab caches included
+    pub used: usize,
+    /// Bytes callers asked for
+    pub requested: usize,
+    pub slabs: [SlabStats; SLAB_SIZES.len()],
+}
+
+impl HeapStats {
+    /// Bytes used but not asked for: slab rounding, cached slab blocks and
+    /// linked list padding
+    pub fn overhead(&self) -> usize {
+        self.used.saturating_sub(self.requested)
+    }
+}
+
+impl HeapState {
+    const fn new() -> HeapState {
+        HeapState {
+            heap: Heap::empty(),
+            slabs: [
+                Slab::new(16),
+                Slab::new(32),
+                Slab::new(64),
+                Slab::new(128),
+                Slab::new(256),
+                Slab::new(512),
+                Slab::new(1024),
+                Slab::new(2048),
+                Slab::new(4096),
+            ],
+            mapped_end: HEAP_START,
+            requested: 0,
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match slab::slab_index(&layout) {
+            Some(index) => self.allocate_slab(index),
+            None => self.allocate_large(layout),
+        };
+        if !ptr.is_null() {
+            self.requested += layout.size();
+        }
+        ptr
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.requested -= layout.size();
+        match slab::slab_index(&layout) {
+            Some(index) => self.slabs[index].deallocate(ptr),
+            None => self.heap.deallocate(NonNull::new_unchecked(ptr), layout),
+        }
+    }
+
+    fn allocate_slab(&mut self, index: usize) -> *mut u8 {
+        if let Some(block) = self.slabs[index].allocate() {
+            return block;
+        }
+
+        let chunk = self.allocate_large(self.slabs[index].refill_layout());
+        if chunk.is_null() {
+            return ptr::null_mut();
+        }
+        unsafe { self.slabs[index].refill(chunk) };
+        self.slabs[index].allocate().unwrap_or(ptr::null_mut())
+    }
+
+    /// First fit from the linked list, growing the heap when nothing fits
+    fn allocate_large(&mut self, layout: Layout) -> *mut u8 {
+        loop {
+            if let Ok(ptr) = self.heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if !self.grow(layout.size() + layout.align()) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    /// Map at least `bytes` more at the end of the heap
+    fn grow(&mut self, bytes: usize) -> bool {
+        let by = bytes.max(HEAP_GROW_STEP).next_multiple_of(4096);
+        if self.mapped_end + by > HEAP_START + HEAP_MAX_SIZE {
+            return false;
+        }
+        if map_heap(self.mapped_end, by).is_err() {
+            return false;
+        }
+
+        log::trace!("heap grew by {} KiB", by / 1024);
+        self.mapped_end += by;
+        unsafe { self.heap.extend(by) };
+        true
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut slabs = [SlabStats::default(); SLAB_SIZES.len()];
+        for (stats, slab) in slabs.iter_mut().zip(&self.slabs) {
+            *stats = slab.stats();
+        }
+        HeapStats {
+            mapped: self.mapped_end - HEAP_START,
+            used: self.heap.used(),
+            requested: self.requested,
+            slabs,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.0.lock().allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.lock().deallocate(ptr, layout))
+    }
+}
+
+/// Back `[start, start + size)` of the heap region with fresh frames
+fn map_heap(start: usize, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            mapper.map_to(page, frame, flags, &mut *frame_allocator)?.flush()
+        };
+    }
+    Ok(())
+}
+
+pub fn init_heap() {
+    map_heap(HEAP_START, HEAP_SIZE).expect("failed to map the initial heap");
+
+    let mut state = ALLOCATOR.0.lock();
+    unsafe {
+        state.heap.init(HEAP_START, HEAP_SIZE);
+    }
+    state.mapped_end = HEAP_START + HEAP_SIZE;
+}
+
+pub fn stats() -> HeapStats {
+    without_interrupts(|| ALLOCATOR.0.lock().stats())
+}
@@ -0,0 +1,87 @@
+use core::alloc::Layout;
+use core::ptr;
+
+/// Block sizes of the slab caches, every allocation up to 4 KiB is rounded
+/// up to one of these
+pub const SLAB_SIZES: [usize; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// How much memory a cache takes from the heap when it runs empty
+const REFILL_SIZE: usize = 16 * 1024;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    pub size: usize,
+    pub in_use: usize,
+    pub free: usize,
+}
+
+/// A cache of equally sized blocks kept on an intrusive free list
+pub struct Slab {
+    size: usize,
+    free_list: *mut FreeBlock,
+    in_use: usize,
+    free: usize,
+}
+
+impl Slab {
+    pub const fn new(size: usize) -> Slab {
+        Slab { size, free_list: ptr::null_mut(), in_use: 0, free: 0 }
+    }
+
+    /// The layout of the memory the cache wants for a refill. Blocks are
+    /// aligned to their size, so the chunk is too.
+    pub fn refill_layout(&self) -> Layout {
+        Layout::from_size_align(REFILL_SIZE.max(self.size), self.size).unwrap()
+    }
+
+    /// Cut a chunk from the heap into blocks
+    ///
+    /// # Safety
+    ///
+    /// `chunk` must be valid for `refill_layout()` and not used by anything else.
+    pub unsafe fn refill(&mut self, chunk: *mut u8) {
+        let blocks = self.refill_layout().size() / self.size;
+        for index in (0..blocks).rev() {
+            let block = chunk.add(index * self.size) as *mut FreeBlock;
+            (*block).next = self.free_list;
+            self.free_list = block;
+        }
+        self.free += blocks;
+    }
+
+    pub fn allocate(&mut self) -> Option<*mut u8> {
+        if self.free_list.is_null() {
+            return None;
+        }
+        let block = self.free_list;
+        self.free_list = unsafe { (*block).next };
+        self.free -= 1;
+        self.in_use += 1;
+        Some(block as *mut u8)
+    }
+
+    /// # Safety
+    ///
+    /// `block` must have come from `allocate` on this cache.
+    pub unsafe fn deallocate(&mut self, block: *mut u8) {
+        let block = block as *mut FreeBlock;
+        (*block).next = self.free_list;
+        self.free_list = block;
+        self.free += 1;
+        self.in_use -= 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats { size: self.size, in_use: self.in_use, free: self.free }
+    }
+}
+
+/// The cache that serves `layout`, `None` for allocations too big for a slab
+pub fn slab_index(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SLAB_SIZES.iter().position(|&slab| size <= slab)
+}
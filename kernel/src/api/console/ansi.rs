@@ -0,0 +1,272 @@
+//! A VT100/xterm escape sequence parser, after the state machine at
+//! https://vt100.net/emu/dec_ansi_parser reduced to what the console uses.
+//! OSC and DCS strings are skipped.
+
+/// Parameters one sequence can have, more are dropped
+const MAX_PARAMS: usize = 16;
+
+/// Numeric parameters of a control sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+    /// Bit `i` is set when value `i` came after a `:`, a sub-parameter of
+    /// the one before
+    sub: u16,
+}
+
+impl Params {
+    const fn new() -> Self {
+        Params { values: [0; MAX_PARAMS], len: 0, sub: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Parameter `index`, with a missing or zero parameter read as `default`
+    pub fn get(&self, index: usize, default: u16) -> u16 {
+        match self.values[..self.len].get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+
+    /// Each parameter together with its sub-parameters, `38:2::1:2:3` is
+    /// one group and `38;2;1;2;3` five
+    pub fn groups(&self) -> impl Iterator<Item = &[u16]> + '_ {
+        let mut start = 0;
+        core::iter::from_fn(move || {
+            if start >= self.len {
+                return None;
+            }
+            let mut end = start + 1;
+            while end < self.len && self.sub & (1 << end) != 0 {
+                end += 1;
+            }
+            let group = &self.values[start..end];
+            start = end;
+            Some(group)
+        })
+    }
+}
+
+/// Something the console has to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Put a character at the cursor
+    Print(char),
+    /// A C0 control like `\n` or `\x08`
+    Control(char),
+    /// `ESC [ <prefix> <params> <intermediate> <final>`
+    Csi {
+        /// A private marker like the `?` in `ESC [ ? 25 h`
+        prefix: Option<char>,
+        params: Params,
+        intermediate: Option<char>,
+        action: char,
+    },
+    /// `ESC <intermediate> <final>`
+    Esc {
+        intermediate: Option<char>,
+        action: char,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    /// A malformed sequence, skipped up to its final byte
+    CsiIgnore,
+    /// An OSC, DCS or similar string, skipped up to BEL or ST
+    String,
+    /// `ESC` inside a string, the start of ST
+    StringEscape,
+}
+
+pub struct Parser {
+    state: State,
+    params: Params,
+    /// The parameter being read, `None` until it has a digit or separator
+    current: Option<u16>,
+    /// Whether `current` follows a `:`
+    colon: bool,
+    prefix: Option<char>,
+    intermediate: Option<char>,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            params: Params::new(),
+            current: None,
+            colon: false,
+            prefix: None,
+            intermediate: None,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.params = Params::new();
+        self.current = None;
+        self.colon = false;
+        self.prefix = None;
+        self.intermediate = None;
+    }
+
+    fn finish_param(&mut self) {
+        if self.params.len < MAX_PARAMS {
+            self.params.values[self.params.len] = self.current.unwrap_or(0);
+            if self.colon && self.params.len > 0 {
+                self.params.sub |= 1 << self.params.len;
+            }
+            self.params.len += 1;
+        }
+        self.current = None;
+        self.colon = false;
+    }
+
+    /// Feed the next character of the output
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        // these work in the middle of any sequence
+        match c {
+            '\x18' | '\x1a' => {
+                self.state = State::Ground;
+                return None;
+            }
+            '\x1b' if self.state == State::String => {
+                self.state = State::StringEscape;
+                return None;
+            }
+            '\x1b' => {
+                self.clear();
+                self.state = State::Escape;
+                return None;
+            }
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => match c {
+                '\x00'..='\x1f' => Some(Action::Control(c)),
+                '\x7f' => None,
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '\x00'..='\x1f' => Some(Action::Control(c)),
+                '[' => {
+                    self.state = State::CsiEntry;
+                    None
+                }
+                ']' | 'P' | 'X' | '^' | '_' => {
+                    self.state = State::String;
+                    None
+                }
+                '\x20'..='\x2f' => {
+                    self.intermediate = Some(c);
+                    self.state = State::EscapeIntermediate;
+                    None
+                }
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Esc { intermediate: None, action: c })
+                }
+            },
+            State::EscapeIntermediate => match c {
+                '\x00'..='\x1f' => Some(Action::Control(c)),
+                '\x20'..='\x2f' => None,
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Esc { intermediate: self.intermediate, action: c })
+                }
+            },
+            State::CsiEntry | State::CsiParam => match c {
+                '\x00'..='\x1f' => Some(Action::Control(c)),
+                '0'..='9' => {
+                    let digit = c as u16 - '0' as u16;
+                    self.current = Some(self.current.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                    self.state = State::CsiParam;
+                    None
+                }
+                // `:` starts a sub-parameter, like the ones in `38:2::r:g:b`
+                ';' | ':' => {
+                    self.finish_param();
+                    // the next parameter exists even if it stays empty
+                    self.current = Some(0);
+                    self.colon = c == ':';
+                    self.state = State::CsiParam;
+                    None
+                }
+                '<'..='?' if self.state == State::CsiEntry => {
+                    self.prefix = Some(c);
+                    self.state = State::CsiParam;
+                    None
+                }
+                '<'..='?' => {
+                    self.state = State::CsiIgnore;
+                    None
+                }
+                '\x20'..='\x2f' => {
+                    if self.current.is_some() {
+                        self.finish_param();
+                    }
+                    self.intermediate = Some(c);
+                    self.state = State::CsiIntermediate;
+                    None
+                }
+                _ => self.dispatch_csi(c),
+            },
+            State::CsiIntermediate => match c {
+                '\x00'..='\x1f' => Some(Action::Control(c)),
+                '\x20'..='\x2f' => None,
+                '0'..='?' => {
+                    self.state = State::CsiIgnore;
+                    None
+                }
+                _ => self.dispatch_csi(c),
+            },
+            State::CsiIgnore => match c {
+                '\x00'..='\x1f' => Some(Action::Control(c)),
+                '\x40'..='\x7e' => {
+                    self.state = State::Ground;
+                    None
+                }
+                _ => None,
+            },
+            State::String => {
+                if c == '\x07' {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::StringEscape => {
+                // anything but `\` is a broken ST, treat it as the end too
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+
+    fn dispatch_csi(&mut self, c: char) -> Option<Action> {
+        if self.current.is_some() {
+            self.finish_param();
+        }
+        self.state = State::Ground;
+        Some(Action::Csi {
+            prefix: self.prefix,
+            params: self.params,
+            intermediate: self.intermediate,
+            action: c,
+        })
+    }
+}
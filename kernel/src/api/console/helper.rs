@@ -38,9 +38,35 @@ pub enum Colour {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct ScreenChar {
-    pub ascii_character: u8,
+    pub character: char,
     pub fg: u32,
     pub bg: u32,
+    pub underline: bool,
+}
+
+/// Stored in the cell right of a wide character, the wide character draws
+/// over both
+pub const WIDE_TAIL: char = '\0';
+
+impl ScreenChar {
+    pub fn is_wide_tail(&self) -> bool {
+        self.character == WIDE_TAIL
+    }
+}
+
+/// Cells `c` takes up: 0 for combining marks and other invisible
+/// characters, 2 for East Asian wide and fullwidth characters
+pub fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036f | 0x0483..=0x0489 | 0x0591..=0x05bd | 0x1ab0..=0x1aff | 0x1dc0..=0x1dff
+        | 0x200b..=0x200f | 0x2028..=0x202e | 0x2060..=0x2064 | 0x20d0..=0x20ff | 0xfe00..=0xfe0f
+        | 0xfe20..=0xfe2f | 0xfeff => 0,
+        0x1100..=0x115f | 0x231a..=0x231b | 0x2329..=0x232a | 0x2e80..=0x303e | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xa000..=0xa4cf | 0xa960..=0xa97f | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff | 0xfe10..=0xfe19 | 0xfe30..=0xfe6f | 0xff00..=0xff60 | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x2fffd | 0x30000..=0x3fffd => 2,
+        _ => 1,
+    }
 }
 
 
@@ -55,9 +81,10 @@ impl TextBuffer {
     pub fn new(width: usize, height: usize) -> TextBuffer {
         let buffer_size = width * height;
         let default_char = ScreenChar {
-            ascii_character: b' ',
+            character: ' ',
             fg: 0xFFFFFFFF,
             bg: 0x00000000,
+            underline: false,
         };
         TextBuffer {
             width,
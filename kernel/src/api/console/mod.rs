@@ -1,99 +1,452 @@
 use core::{fmt, ptr};
+use core::ops::Range;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
+use alloc::sync::Arc;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
 
 use crate::io::vga;
+use crate::sync::IrqSpinlock;
 
 mod helper;
 pub use helper::*;
 
+pub mod ansi;
 pub mod palette;
 pub use palette::Palette;
 
-use crate::io::vga::font;
+use crate::io::vga::font::{self, Font, FontError};
 
-pub static FONT_CONFIG: font::Config = font::cozette::CONFIG;
+/// Lines kept after they scroll off the top, until `set_scrollback` changes it
+pub const DEFAULT_SCROLLBACK: usize = 2000;
+/// Most lines `set_scrollback` keeps, so the history cannot fill the heap
+pub const MAX_SCROLLBACK: usize = 10_000;
 
 lazy_static! {
-    pub static ref CONSOLE: Mutex<Console> = Mutex::new(Console {
+    pub static ref CONSOLE: IrqSpinlock<Console> = IrqSpinlock::new(Console {
         col: 0,
         row: 0,
         buffer: TextBuffer::new(1, 1),
         colour_palette: palette::Flat,
+        parser: ansi::Parser::new(),
+        attributes: Attributes::default(),
+        saved: None,
+        scroll_top: 0,
+        scroll_bottom: 0,
+        scrollback: VecDeque::new(),
+        scrollback_limit: DEFAULT_SCROLLBACK,
+        view_offset: 0,
+        dirty: false,
+        cursor_shown: false,
+        font: Arc::new(font::Cozette),
     });
 }
 
+/// A colour as the escape sequences set it, resolved against the palette
+/// when a character is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum TermColour {
+    #[default]
+    Default,
+    /// An entry of the xterm 256 colour table
+    Indexed(u8),
+    /// `0xRRGGBBAA`
+    Rgb(u32),
+}
+
+/// What SGR sequences set for the characters that follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Attributes {
+    fg: TermColour,
+    bg: TermColour,
+    bold: bool,
+    underline: bool,
+    reverse: bool,
+}
+
+/// Cursor and attributes stored by `ESC 7` or `CSI s`
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    col: usize,
+    row: usize,
+    attributes: Attributes,
+}
+
 pub struct Console {
     col: usize,
     row: usize,
     buffer: TextBuffer,
     colour_palette: Palette,
+    parser: ansi::Parser,
+    attributes: Attributes,
+    saved: Option<SavedCursor>,
+    /// First row of the scroll region
+    scroll_top: usize,
+    /// Last row of the scroll region, inclusive
+    scroll_bottom: usize,
+    /// Rows that scrolled off the top of the screen, oldest first
+    scrollback: VecDeque<Box<[ScreenChar]>>,
+    scrollback_limit: usize,
+    /// How many rows the view is scrolled back, 0 shows the live screen
+    view_offset: usize,
+    /// The screen has to be drawn again from the buffers
+    dirty: bool,
+    /// The text cursor is drawn over a cell
+    cursor_shown: bool,
+    font: Arc<dyn Font>,
 }
 
 impl Console {
 
     pub fn init(&mut self, palette: Palette) {
         self.colour_palette = palette;
-        let width = vga::width()/FONT_CONFIG.width;
-        let height = (vga::height()/FONT_CONFIG.height)+1;
+        let config = self.font.metrics();
+        let width = vga::width()/config.width;
+        let height = (vga::height()/config.height)+1;
 
         self.buffer = TextBuffer::new(width, height);
+        self.scroll_top = 0;
+        self.scroll_bottom = self.rows() - 1;
+    }
+
+    /// Rows on screen, the last row of the buffer is only partly visible
+    fn rows(&self) -> usize {
+        self.buffer.height.saturating_sub(1).max(1)
     }
 
     fn new_line(&mut self) {
-        self.row += 1;
         self.col = 0;
+        self.line_feed();
+    }
 
-        if self.row >= self.buffer.height-1 {
-            for row in 1..self.buffer.height {
-                for col in 0..self.buffer.width {
-                    let character = self.buffer.get_char(col, row).expect("uh oh");
-                    self.buffer.set_char(col, row - 1, character);
-                }
-            }
+    /// Move down a row, scrolling at the bottom of the scroll region
+    fn line_feed(&mut self) {
+        if self.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.row + 1 < self.rows() {
+            self.row += 1;
+        }
+    }
+
+    /// Move up a row, scrolling down at the top of the scroll region
+    fn reverse_line_feed(&mut self) {
+        if self.row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.row > 0 {
             self.row -= 1;
-            vga::shift_y(13);
-            vga::rect(0, 0, vga::width(), 1, self.colour_palette.black); //TODO: fix jank
-            vga::rect(0, vga::height()-13, vga::width(), FONT_CONFIG.height+5, self.colour_palette.black);
         }
-        
+    }
+
+    /// Scroll the scroll region up by `count` rows. Rows leaving the top of
+    /// the screen go to the scrollback.
+    fn scroll_up(&mut self, count: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let count = count.min(bottom + 1 - top);
+        if top == 0 {
+            for row in 0..count {
+                let line = self.buffer.get_row(row).expect("row on screen").into();
+                self.push_scrollback(line);
+            }
+        }
+        for row in top..bottom + 1 - count {
+            for col in 0..self.buffer.width {
+                let character = self.buffer.get_char(col, row + count).expect("uh oh");
+                self.buffer.set_char(col, row, character);
+            }
+        }
+        for row in bottom + 1 - count..=bottom {
+            self.clear_cells(row, 0, self.buffer.width);
+        }
+        self.scroll_screen(count as isize, bottom + 1 - count..bottom + 1);
+    }
+
+    /// Move what is drawn of the scroll region like its rows just moved in
+    /// the buffer, up by `count` rows or down for a negative one, and draw
+    /// the `fresh` rows. With the view in the scrollback, a cursor that
+    /// would move along or a full redraw pending anyway, the screen is left
+    /// for `render`.
+    fn scroll_screen(&mut self, count: isize, fresh: Range<usize>) {
+        if self.view_offset > 0 || self.cursor_shown || self.dirty {
+            self.dirty = true;
+            return;
+        }
+        let height = self.font.metrics().height;
+        vga::move_rows(self.scroll_top * height, (self.scroll_bottom + 1) * height, count * height as isize);
+        for row in fresh {
+            for col in 0..self.buffer.width {
+                let character = self.buffer.get_char(col, row).expect("row on screen");
+                self.draw_char(col, row, character);
+            }
+        }
+    }
+
+    fn push_scrollback(&mut self, line: Box<[ScreenChar]>) {
+        if self.scrollback_limit == 0 {
+            return;
+        }
+        while self.scrollback.len() >= self.scrollback_limit {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(line);
+        // a view into the scrollback stays on the same lines
+        if self.view_offset > 0 {
+            self.view_offset = (self.view_offset + 1).min(self.scrollback.len());
+        }
+    }
+
+    fn set_scrollback(&mut self, lines: usize) {
+        self.scrollback_limit = lines;
+        while self.scrollback.len() > lines {
+            self.scrollback.pop_front();
+        }
+        self.view_offset = self.view_offset.min(self.scrollback.len());
+        self.dirty = true;
+    }
+
+    /// Move the view `lines` rows back into the scrollback, or forward
+    /// towards the live screen for negative `lines`
+    fn scroll_view(&mut self, lines: isize) {
+        let offset = (self.view_offset as isize + lines).clamp(0, self.scrollback.len() as isize) as usize;
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.render();
+            vga::flip();
+        }
+    }
+
+    /// Go back to the live screen if the view is in the scrollback
+    fn follow_output(&mut self) {
+        if self.view_offset > 0 {
+            self.view_offset = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Draw every row of the view from the scrollback and the text buffer
+    fn render(&mut self) {
+        let history = self.scrollback.len();
+        // scrollback lines from before a font change can be shorter
+        let empty = ScreenChar {
+            character: ' ',
+            fg: self.colour_palette.black,
+            bg: self.colour_palette.black,
+            underline: false,
+        };
+        for row in 0..self.rows() {
+            let line = history - self.view_offset + row;
+            for col in 0..self.buffer.width {
+                let character = match line.checked_sub(history) {
+                    None => self.scrollback[line].get(col).copied(),
+                    Some(row) => self.buffer.get_char(col, row),
+                };
+                self.draw_char(col, row, character.unwrap_or(empty));
+            }
+        }
+        self.dirty = false;
+        self.cursor_shown = false;
+    }
+
+    /// Draw with `font` from now on, laying the text out again for its cells
+    fn set_font(&mut self, font: Arc<dyn Font>) -> Result<(), FontError> {
+        let config = font.metrics();
+        if config.width == 0 || config.height == 0 || vga::width() / config.width < 2 || vga::height() / config.height < 2 {
+            return Err(FontError::Unsupported);
+        }
+        self.font = font;
+        self.reflow();
+        Ok(())
+    }
+
+    /// Resize the text buffer to the cells of the font. The rows up to the
+    /// cursor stay at the top of the screen, with any that no longer fit
+    /// going to the scrollback, and each row is cut or padded to the new
+    /// width.
+    fn reflow(&mut self) {
+        let config = self.font.metrics();
+        let buffer = TextBuffer::new(vga::width() / config.width, vga::height() / config.height + 1);
+        let old = core::mem::replace(&mut self.buffer, buffer);
+        let (width, rows) = (self.buffer.width, self.rows());
+        self.view_offset = 0;
+
+        let used = (self.row + 1).min(old.height);
+        let gone = used.saturating_sub(rows);
+        for row in 0..gone {
+            let line = old.get_row(row).expect("row on screen").into();
+            self.push_scrollback(line);
+        }
+        let blank = self.blank();
+        for row in gone..used {
+            for col in 0..width.min(old.width) {
+                let character = old.get_char(col, row).expect("cell on screen");
+                self.buffer.set_char(col, row - gone, character);
+            }
+            // a wide character cut in half by the new right edge
+            if matches!(old.get_char(width, row), Some(cell) if cell.is_wide_tail()) {
+                self.buffer.set_char(width - 1, row - gone, blank);
+            }
+        }
+
+        self.row -= gone;
+        self.col = self.col.min(width - 1);
+        self.saved = None;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        vga::clear(self.colour_palette.black);
+        self.render();
+    }
+
+    /// Scroll the scroll region down by `count` rows
+    fn scroll_down(&mut self, count: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let count = count.min(bottom + 1 - top);
+        for row in (top + count..=bottom).rev() {
+            for col in 0..self.buffer.width {
+                let character = self.buffer.get_char(col, row - count).expect("uh oh");
+                self.buffer.set_char(col, row, character);
+            }
+        }
+        for row in top..top + count {
+            self.clear_cells(row, 0, self.buffer.width);
+        }
+        self.scroll_screen(-(count as isize), top..top + count);
+    }
+
+    fn is_wide_tail(&self, col: usize, row: usize) -> bool {
+        matches!(self.buffer.get_char(col, row), Some(cell) if cell.is_wide_tail())
+    }
+
+    /// `[from, to)` grown to not cut a wide character in half
+    fn whole_cells(&self, row: usize, from: usize, to: usize) -> (usize, usize) {
+        let from = match from > 0 && self.is_wide_tail(from, row) {
+            true => from - 1,
+            false => from,
+        };
+        let to = match self.is_wide_tail(to, row) {
+            true => to + 1,
+            false => to,
+        };
+        (from, to)
+    }
+
+    /// Blank `[from, to)` of `row` in the buffer only
+    fn clear_cells(&mut self, row: usize, from: usize, to: usize) {
+        let (from, to) = self.whole_cells(row, from, to);
+        let blank = self.blank();
+        for col in from..to.min(self.buffer.width) {
+            self.buffer.set_char(col, row, blank);
+        }
+    }
+
+    /// Blank `[from, to)` of `row` and draw it
+    fn erase_cells(&mut self, row: usize, from: usize, to: usize) {
+        let (from, to) = self.whole_cells(row, from, to);
+        self.clear_cells(row, from, to);
+        let blank = self.blank();
+        for col in from..to.min(self.buffer.width) {
+            self.write_char(col, row, blank);
+        }
+    }
+
+    /// An empty cell in the current background, like xterm erases
+    fn blank(&self) -> ScreenChar {
+        let (_, bg) = self.colours();
+        ScreenChar { character: ' ', fg: bg, bg, underline: false }
+    }
+
+    /// Foreground and background the current attributes draw with
+    fn colours(&self) -> (u32, u32) {
+        let attributes = self.attributes;
+        let fg = match attributes.fg {
+            TermColour::Default => self.colour_palette.white,
+            // bold makes the eight basic colours bright
+            TermColour::Indexed(index) if attributes.bold && index < 8 => self.colour_palette.ansi(index + 8),
+            TermColour::Indexed(index) => self.colour_palette.ansi(index),
+            TermColour::Rgb(colour) => colour,
+        };
+        let bg = match attributes.bg {
+            TermColour::Default => self.colour_palette.black,
+            TermColour::Indexed(index) => self.colour_palette.ansi(index),
+            TermColour::Rgb(colour) => colour,
+        };
+        match attributes.reverse {
+            true => (bg, fg),
+            false => (fg, bg),
+        }
+    }
+
+    fn move_to(&mut self, col: usize, row: usize) {
+        self.col = col.min(self.buffer.width - 1);
+        self.row = row.min(self.rows() - 1);
     }
 
     pub fn back_space(&mut self) {
+        self.follow_output();
+        if self.dirty {
+            self.render();
+            vga::flip();
+        }
         if self.col > 0 {
+            let end = self.col;
             self.col -= 1;
+            // a wide character is taken out whole
+            if self.col > 0 && self.is_wide_tail(self.col, self.row) {
+                self.col -= 1;
+            }
             let default_char = ScreenChar {
-                ascii_character: b' ',
+                character: ' ',
                 fg: self.colour_palette.black,
                 bg: self.colour_palette.black,
+                underline: false,
             };
-            self.write_char(self.col, self.row, default_char);
+            for col in self.col..end {
+                self.buffer.set_char(col, self.row, default_char);
+                self.write_char(col, self.row, default_char);
+            }
             //self.col -= 1;
             vga::flip();
         }
-        
+    }
+
+    /// Draw the text cursor at the current position, or put back what it
+    /// covered
+    fn draw_cursor(&mut self, visible: bool) {
+        let Some(under) = self.buffer.get_char(self.col, self.row) else {
+            return;
+        };
+        let (col, cursor) = match visible {
+            true => (self.col, ScreenChar { character: '_', fg: self.colour_palette.white, bg: under.bg, underline: false }),
+            // a wide character is drawn from its left half
+            false if under.is_wide_tail() && self.col > 0 => {
+                (self.col - 1, self.buffer.get_char(self.col - 1, self.row).unwrap_or(under))
+            }
+            false => (self.col, under),
+        };
+        self.write_char(col, self.row, cursor);
+        self.cursor_shown = visible;
     }
 
     fn fill(&mut self, c: char, fg_colour: u32, bg_colour: u32) {
         self.col = 0;
         self.row = 0;
+        self.view_offset = 0;
 
         let default_char = ScreenChar {
-            ascii_character: b' ',
+            character: ' ',
             fg: fg_colour,
             bg: bg_colour,
+            underline: false,
         };
         
         for row in 1..self.buffer.height {
             for col in 0..self.buffer.width {
                 if c != ' ' {
-                    self.write_byte(c as u8, fg_colour, bg_colour);
+                    self.put_char(c, fg_colour, bg_colour);
                 } else {
                     self.buffer.set_char(col, row, default_char);
                 }
@@ -103,55 +456,273 @@ impl Console {
         vga::clear(bg_colour);
     }
 
-    fn write_byte(&mut self, byte: u8, fg_colour: u32, bg_colour: u32) {
-        match byte {
-            b'\n' => self.new_line(),
+    fn put_char(&mut self, c: char, fg_colour: u32, bg_colour: u32) {
+        match c {
+            '\n' => self.new_line(),
 
-            byte => {
-                if self.col >= self.buffer.width {
+            c => {
+                let width = char_width(c);
+                if width == 0 {
+                    // combining marks would need the character before them
+                    return;
+                }
+                if self.col + width > self.buffer.width {
                     self.new_line();
                 }
 
                 let screen_char = ScreenChar {
-                    ascii_character: byte,
+                    character: c,
                     fg: fg_colour,
                     bg: bg_colour,
+                    underline: self.attributes.underline,
                 };
 
-                self.buffer.set_char(self.col, self.row, screen_char);
-                self.write_char(self.col, self.row, screen_char);
-                self.col += 1;
+                self.put_cell(self.col, self.row, screen_char);
+                if width == 2 {
+                    self.put_cell(self.col + 1, self.row, ScreenChar { character: WIDE_TAIL, ..screen_char });
+                }
+                self.col += width;
             }
         }
+    }
 
-        // self.write_char(0, self.buffer.height-2, ScreenChar {
-        //     ascii_character: b'#',
-        //     fg: self.colour_palette.white,
-        //     bg: self.colour_palette.white,
-        // });
-
-        // self.write_char(self.buffer.width-1, self.buffer.height-2, ScreenChar {
-        //     ascii_character: b'#',
-        //     fg: self.colour_palette.white,
-        //     bg: self.colour_palette.white,
-        // });
-
-        // self.write_char(self.buffer.width-1, 0, ScreenChar {
-        //     ascii_character: b'#',
-        //     fg: self.colour_palette.white,
-        //     bg: self.colour_palette.white,
-        // });
+    /// Store and draw a cell, blanking what is left of a wide character it
+    /// lands on
+    fn put_cell(&mut self, col: usize, row: usize, screen_char: ScreenChar) {
+        if col > 0 && self.is_wide_tail(col, row) {
+            self.erase_cells(row, col - 1, col);
+        }
+        if self.is_wide_tail(col + 1, row) {
+            self.erase_cells(row, col + 1, col + 2);
+        }
+        self.buffer.set_char(col, row, screen_char);
+        self.write_char(col, row, screen_char);
     }
 
+    /// Draw a cell of the live screen, unless the view is in the scrollback
     fn write_char(&mut self, x: usize, y: usize, screen_char: ScreenChar) {
-        let x_pos = x * FONT_CONFIG.width;
-        let y_pos = y * FONT_CONFIG.height;
-        vga::char_bitmap(x_pos, y_pos, 1, screen_char.fg, screen_char.bg, screen_char.ascii_character as char);
+        if self.view_offset == 0 {
+            self.draw_char(x, y, screen_char);
+        }
+    }
+
+    fn draw_char(&mut self, x: usize, y: usize, screen_char: ScreenChar) {
+        if screen_char.is_wide_tail() {
+            return;
+        }
+        let config = self.font.metrics();
+        let x_pos = x * config.width;
+        let y_pos = y * config.height;
+        let cells = char_width(screen_char.character).max(1);
+        if cells == 2 {
+            // no font has wide glyphs, the narrow one goes in the middle
+            vga::rect(x_pos, y_pos, 2 * config.width, config.height, screen_char.bg);
+            vga::glyph(x_pos + config.width / 2, y_pos, &*self.font, screen_char.character, screen_char.fg, screen_char.bg);
+        } else {
+            vga::glyph(x_pos, y_pos, &*self.font, screen_char.character, screen_char.fg, screen_char.bg);
+        }
+        if screen_char.underline {
+            vga::rect(x_pos, y_pos + config.height - 1, cells * config.width, 1, screen_char.fg);
+        }
     }
 
     pub fn get_palette(&mut self) -> Palette {
         self.colour_palette
     }
+
+    fn perform(&mut self, action: ansi::Action) {
+        use ansi::Action;
+
+        match action {
+            Action::Print(c) => {
+                let (fg, bg) = self.colours();
+                self.put_char(c, fg, bg);
+            }
+            Action::Control(c) => self.control(c),
+            Action::Esc { intermediate: None, action } => self.escape(action),
+            Action::Esc { .. } => {}
+            Action::Csi { prefix, params, intermediate: None, action } => self.csi(prefix, &params, action),
+            Action::Csi { .. } => {}
+        }
+    }
+
+    fn control(&mut self, c: char) {
+        match c {
+            // kernel output only ever sends `\n`, so it returns too
+            '\n' | '\x0b' | '\x0c' => self.new_line(),
+            '\r' => self.col = 0,
+            '\x08' => self.col = self.col.min(self.buffer.width - 1).saturating_sub(1),
+            '\t' => self.col = ((self.col / 8 + 1) * 8).min(self.buffer.width - 1),
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, action: char) {
+        match action {
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            'D' => self.line_feed(),
+            'E' => self.new_line(),
+            'M' => self.reverse_line_feed(),
+            'c' => self.reset(),
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, prefix: Option<char>, params: &ansi::Params, action: char) {
+        if prefix.is_some() {
+            // private modes like cursor visibility are not supported
+            return;
+        }
+        let count = |index| params.get(index, 1) as usize;
+        match action {
+            'A' => self.move_to(self.col, self.row.saturating_sub(count(0))),
+            'B' | 'e' => self.move_to(self.col, self.row + count(0)),
+            'C' | 'a' => self.move_to(self.col + count(0), self.row),
+            'D' => self.move_to(self.col.saturating_sub(count(0)), self.row),
+            'E' => self.move_to(0, self.row + count(0)),
+            'F' => self.move_to(0, self.row.saturating_sub(count(0))),
+            'G' | '`' => self.move_to(count(0) - 1, self.row),
+            'd' => self.move_to(self.col, count(0) - 1),
+            'H' | 'f' => self.move_to(count(1) - 1, count(0) - 1),
+            'J' => self.erase_display(params.get(0, 0)),
+            'K' => self.erase_line(params.get(0, 0)),
+            'S' => self.scroll_up(count(0)),
+            'T' => self.scroll_down(count(0)),
+            'm' => self.select_graphic_rendition(params),
+            'r' => {
+                let top = count(0) - 1;
+                let bottom = (params.get(1, self.rows() as u16) as usize).min(self.rows()) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.move_to(0, 0);
+                }
+            }
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let (width, rows) = (self.buffer.width, self.rows());
+        match mode {
+            0 => {
+                self.erase_cells(self.row, self.col, width);
+                for row in self.row + 1..rows {
+                    self.erase_cells(row, 0, width);
+                }
+            }
+            1 => {
+                for row in 0..self.row {
+                    self.erase_cells(row, 0, width);
+                }
+                self.erase_cells(self.row, 0, self.col + 1);
+            }
+            2 | 3 => {
+                for row in 0..rows {
+                    self.erase_cells(row, 0, width);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        match mode {
+            0 => self.erase_cells(self.row, self.col, self.buffer.width),
+            1 => self.erase_cells(self.row, 0, self.col + 1),
+            2 => self.erase_cells(self.row, 0, self.buffer.width),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &ansi::Params) {
+        if params.is_empty() {
+            self.attributes = Attributes::default();
+            return;
+        }
+
+        let mut groups = params.groups();
+        while let Some(group) = groups.next() {
+            let attributes = &mut self.attributes;
+            let param = group[0];
+            match param {
+                0 => *attributes = Attributes::default(),
+                1 => attributes.bold = true,
+                // `4:0` is no underline, any other style is drawn plain
+                4 => attributes.underline = group.get(1) != Some(&0),
+                7 => attributes.reverse = true,
+                22 => attributes.bold = false,
+                24 => attributes.underline = false,
+                27 => attributes.reverse = false,
+                30..=37 => attributes.fg = TermColour::Indexed(param as u8 - 30),
+                38 => attributes.fg = extended_colour(group, &mut groups).unwrap_or(attributes.fg),
+                39 => attributes.fg = TermColour::Default,
+                40..=47 => attributes.bg = TermColour::Indexed(param as u8 - 40),
+                48 => attributes.bg = extended_colour(group, &mut groups).unwrap_or(attributes.bg),
+                49 => attributes.bg = TermColour::Default,
+                90..=97 => attributes.fg = TermColour::Indexed(param as u8 - 90 + 8),
+                100..=107 => attributes.bg = TermColour::Indexed(param as u8 - 100 + 8),
+                _ => {}
+            }
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = Some(SavedCursor { col: self.col, row: self.row, attributes: self.attributes });
+    }
+
+    fn restore_cursor(&mut self) {
+        match self.saved {
+            Some(saved) => {
+                self.move_to(saved.col, saved.row);
+                self.attributes = saved.attributes;
+            }
+            None => {
+                self.move_to(0, 0);
+                self.attributes = Attributes::default();
+            }
+        }
+    }
+
+    /// Back to the state after `init`, with a cleared screen
+    fn reset(&mut self) {
+        self.attributes = Attributes::default();
+        self.saved = None;
+        self.scroll_top = 0;
+        self.scroll_bottom = self.rows() - 1;
+        self.erase_display(2);
+        self.move_to(0, 0);
+    }
+}
+
+/// Read a `38;5;<index>` or `38;2;<r>;<g>;<b>` colour from the parameters
+/// after `group`, or one in xterm's colon form `38:5:<index>` or
+/// `38:2:<colour space>:<r>:<g>:<b>` from the sub-parameters in `group`
+fn extended_colour<'a>(group: &[u16], rest: &mut impl Iterator<Item = &'a [u16]>) -> Option<TermColour> {
+    let channel = |value: u16| value.min(255) as u8;
+    if group.len() > 1 {
+        return match group[1..] {
+            [5, index, ..] => Some(TermColour::Indexed(channel(index))),
+            // the colour space is often left out
+            [2, _, red, green, blue, ..] | [2, red, green, blue] => {
+                Some(TermColour::Rgb(palette::rgb(channel(red), channel(green), channel(blue))))
+            }
+            _ => None,
+        };
+    }
+
+    let mut next = || rest.next().map(|group| channel(group[0]));
+    match next()? {
+        5 => Some(TermColour::Indexed(next()?)),
+        2 => {
+            let (red, green, blue) = (next()?, next()?, next()?);
+            Some(TermColour::Rgb(palette::rgb(red, green, blue)))
+        }
+        _ => None,
+    }
 }
 
 unsafe impl Send for Console {}
@@ -159,59 +730,16 @@ unsafe impl Sync for Console {}
 
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let mut mode = 0;
-        let mut colourmode = true;
-        let mut fg = self.colour_palette.white;
-        let mut bg = self.colour_palette.black;
+        self.follow_output();
         for c in s.chars() {
-            match mode {
-                0 => match c {
-                    '\x1b' => mode = 1,
-                    _ => self.write_byte(c as u8, fg, bg),
-                },
-                1 => match c {
-                    ';' => mode = 2,
-                    'm' => mode = 0,
-                    '0' => {
-                        fg = self.colour_palette.white;
-                        bg = self.colour_palette.black;
-                    },
-                    '1' => colourmode = false,
-                    '2' => colourmode = true,
-                    _ => {
-                        mode = 0;
-                        self.write_byte(c as u8, fg, bg);
-                    },
-                },
-                2 => match c {
-                    'm' => mode = 0,
-                    'r' => match colourmode { true => { fg = self.colour_palette.clear; }, false => { bg = self.colour_palette.clear; } },
-                    '0' => match colourmode { true => { fg = self.colour_palette.black; }, false => { bg = self.colour_palette.black; } },
-                    '1' => match colourmode { true => { fg = self.colour_palette.blue; }, false => { bg = self.colour_palette.blue; } },
-                    '2' => match colourmode { true => { fg = self.colour_palette.green; }, false => { bg = self.colour_palette.green; } },
-                    '3' => match colourmode { true => { fg = self.colour_palette.cyan; }, false => { bg = self.colour_palette.cyan; } },
-                    '4' => match colourmode { true => { fg = self.colour_palette.red; }, false => { bg = self.colour_palette.red; } },
-                    '5' => match colourmode { true => { fg = self.colour_palette.magenta; }, false => { bg = self.colour_palette.magenta; } },
-                    '6' => match colourmode { true => { fg = self.colour_palette.brown; }, false => { bg = self.colour_palette.brown; } },
-                    '7' => match colourmode { true => { fg = self.colour_palette.lightgray; }, false => { bg = self.colour_palette.lightgray; } },
-                    '8' => match colourmode { true => { fg = self.colour_palette.darkgray; }, false => { bg = self.colour_palette.darkgray; } },
-                    '9' => match colourmode { true => { fg = self.colour_palette.lightblue; }, false => { bg = self.colour_palette.lightblue; } },
-                    'a' => match colourmode { true => { fg = self.colour_palette.lightgreen; }, false => { bg = self.colour_palette.lightgreen; } },
-                    'b' => match colourmode { true => { fg = self.colour_palette.lightcyan; }, false => { bg = self.colour_palette.lightcyan; } },
-                    'c' => match colourmode { true => { fg = self.colour_palette.lightred; }, false => { bg = self.colour_palette.lightred; } },
-                    'd' => match colourmode { true => { fg = self.colour_palette.pink; }, false => { bg = self.colour_palette.pink; } },
-                    'e' => match colourmode { true => { fg = self.colour_palette.yellow; }, false => { bg = self.colour_palette.yellow; } },
-                    'f' => match colourmode { true => { fg = self.colour_palette.white; }, false => { bg = self.colour_palette.white; } },
-
-                    _ => {
-                        mode = 0;
-                        self.write_byte(c as u8, fg, bg);
-                    },
-                }
-                
-                _ => {},
+            if let Some(action) = self.parser.advance(c) {
+                self.perform(action);
             }
         }
+        // scrolling only moves the buffers, the screen is drawn once here
+        if self.dirty {
+            self.render();
+        }
         vga::flip();
         Ok(())
     }
@@ -219,11 +747,8 @@ impl fmt::Write for Console {
 
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        CONSOLE.lock().write_fmt(args).unwrap();
-    });
+    CONSOLE.lock().write_fmt(args).unwrap();
 }
 
 #[macro_export]
@@ -241,24 +766,6 @@ macro_rules! println {
         concat!($fmt, "\n"), $($arg)*));
 }
 
-fn parse_color_string(color_string: &str) -> Option<(u8, u8, u8)> {
-    if let Some(start_index) = color_string.find("[38;2;") {
-        let end_index = color_string.find("m").unwrap_or(color_string.len());
-        let color_values = &color_string[start_index + 7..end_index];
-
-        let values: Vec<u8> = color_values
-            .split(';')
-            .filter_map(|value| value.parse().ok())
-            .collect();
-
-        if values.len() == 3 {
-            return Some((values[0], values[1], values[2]));
-        }
-    }
-
-    None
-}
-
 pub fn init(palette: Palette) {
     CONSOLE.lock().init(palette);
 }
@@ -276,6 +783,48 @@ pub fn back_space() {
     CONSOLE.lock().back_space();
 }
 
+/// Scroll the view a page back into the scrollback
+pub fn page_up() {
+    let mut console = CONSOLE.lock();
+    let page = console.rows() as isize - 1;
+    console.scroll_view(page.max(1));
+}
+
+/// Scroll the view a page towards the live screen
+pub fn page_down() {
+    let mut console = CONSOLE.lock();
+    let page = console.rows() as isize - 1;
+    console.scroll_view(-page.max(1));
+}
+
+/// Keep up to `lines` rows that scrolled off the screen, at most
+/// `MAX_SCROLLBACK`
+pub fn set_scrollback(lines: usize) {
+    CONSOLE.lock().set_scrollback(lines.min(MAX_SCROLLBACK));
+}
+
+/// How many rows that scrolled off the screen are kept
+pub fn scrollback() -> usize {
+    CONSOLE.lock().scrollback_limit
+}
+
+/// Draw the console with `font` from now on
+pub fn set_font(font: Arc<dyn Font>) -> Result<(), FontError> {
+    CONSOLE.lock().set_font(font)?;
+    vga::flip();
+    Ok(())
+}
+
+/// The font the console draws with
+pub fn font() -> Arc<dyn Font> {
+    CONSOLE.lock().font.clone()
+}
+
+pub fn show_cursor(visible: bool) {
+    CONSOLE.lock().draw_cursor(visible);
+    vga::flip();
+}
+
 pub fn set_position(row: usize, col: usize) {
     //CONSOLE.lock().set_position(row, col);
 }
@@ -19,6 +19,46 @@ pub struct Palette {
     pub white: u32,
 }
 
+impl Palette {
+    /// Colour `index` of the xterm 256 colour table. The first 16 come from
+    /// the palette, in ANSI order, the rest are the colour cube and the
+    /// grey ramp.
+    pub fn ansi(&self, index: u8) -> u32 {
+        const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+        match index {
+            0 => self.black,
+            1 => self.red,
+            2 => self.green,
+            3 => self.brown,
+            4 => self.blue,
+            5 => self.magenta,
+            6 => self.cyan,
+            7 => self.lightgray,
+            8 => self.darkgray,
+            9 => self.lightred,
+            10 => self.lightgreen,
+            11 => self.yellow,
+            12 => self.lightblue,
+            13 => self.pink,
+            14 => self.lightcyan,
+            15 => self.white,
+            16..=231 => {
+                let cube = index - 16;
+                rgb(LEVELS[(cube / 36) as usize], LEVELS[(cube / 6 % 6) as usize], LEVELS[(cube % 6) as usize])
+            }
+            232..=255 => {
+                let grey = 8 + (index - 232) * 10;
+                rgb(grey, grey, grey)
+            }
+        }
+    }
+}
+
+/// An opaque colour in the `0xRRGGBBAA` format the palettes use
+pub const fn rgb(red: u8, green: u8, blue: u8) -> u32 {
+    (red as u32) << 24 | (green as u32) << 16 | (blue as u32) << 8 | 0xFF
+}
+
 pub const Flat: Palette = Palette {
     clear: 0x00_00_00_00,
     black: 0x2C_3E_50_FF,
@@ -1 +1,2 @@
-pub mod console;
\ No newline at end of file
+pub mod console;
+pub mod syscall;
\ No newline at end of file
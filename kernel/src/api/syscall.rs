@@ -0,0 +1,195 @@
+//! System call numbers, error codes and the user space calling convention.
+//!
+//! This file only depends on `core` so a user space support library can
+//! include it as-is (`#[path = ".../api/syscall.rs"] mod syscall;`).
+//!
+//! Calling convention (same as Linux): the number goes in `rax`, arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, the result comes back in `rax`.
+//! `rcx` and `r11` are clobbered by `syscall`. Negative results are errors.
+//! `int 0x24` is accepted as a fallback with the same registers.
+
+use core::arch::asm;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    /// read(fd, buf, len) -> bytes read
+    Read = 0,
+    /// write(fd, buf, len) -> bytes written
+    Write = 1,
+    /// open(path, path_len, flags) -> fd
+    Open = 2,
+    /// close(fd) -> 0
+    Close = 3,
+    /// exit(code) -> never returns
+    Exit = 4,
+    /// getpid() -> pid
+    GetPid = 5,
+    /// sleep(milliseconds) -> 0
+    Sleep = 6,
+    /// mmap(addr, len, prot) -> addr, `addr` 0 lets the kernel choose
+    Mmap = 7,
+    /// munmap(addr, len) -> 0
+    Munmap = 8,
+    /// yield() -> 0
+    Yield = 9,
+    /// mprotect(addr, len, prot) -> 0
+    Mprotect = 10,
+}
+
+impl Syscall {
+    pub fn from_u64(number: u64) -> Option<Syscall> {
+        Some(match number {
+            0 => Syscall::Read,
+            1 => Syscall::Write,
+            2 => Syscall::Open,
+            3 => Syscall::Close,
+            4 => Syscall::Exit,
+            5 => Syscall::GetPid,
+            6 => Syscall::Sleep,
+            7 => Syscall::Mmap,
+            8 => Syscall::Munmap,
+            9 => Syscall::Yield,
+            10 => Syscall::Mprotect,
+            _ => return None,
+        })
+    }
+}
+
+/// Errors are returned as the negated value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    NoEntry = 2,
+    BadFd = 9,
+    NoMemory = 12,
+    Fault = 14,
+    Exists = 17,
+    NotDirectory = 20,
+    IsDirectory = 21,
+    Invalid = 22,
+    NoSpace = 28,
+    NoSys = 38,
+    NotEmpty = 39,
+}
+
+impl SyscallError {
+    pub fn from_result(value: i64) -> Result<u64, SyscallError> {
+        if value >= 0 {
+            return Ok(value as u64);
+        }
+        Err(match -value {
+            2 => SyscallError::NoEntry,
+            9 => SyscallError::BadFd,
+            12 => SyscallError::NoMemory,
+            14 => SyscallError::Fault,
+            17 => SyscallError::Exists,
+            20 => SyscallError::NotDirectory,
+            21 => SyscallError::IsDirectory,
+            28 => SyscallError::NoSpace,
+            38 => SyscallError::NoSys,
+            39 => SyscallError::NotEmpty,
+            _ => SyscallError::Invalid,
+        })
+    }
+
+    pub fn as_result(self) -> i64 {
+        -(self as i64)
+    }
+}
+
+// memory protection bits for `mmap`
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+// flags for `open`
+pub const O_READ: u64 = 1;
+pub const O_WRITE: u64 = 2;
+pub const O_CREATE: u64 = 4;
+pub const O_TRUNCATE: u64 = 8;
+pub const O_APPEND: u64 = 16;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// # Safety
+///
+/// The arguments must be valid for the given system call.
+pub unsafe fn syscall6(number: Syscall, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as u64 => result,
+        in("rdi") a,
+        in("rsi") b,
+        in("rdx") c,
+        in("r10") d,
+        in("r8") e,
+        in("r9") f,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// # Safety
+///
+/// The arguments must be valid for the given system call.
+pub unsafe fn syscall3(number: Syscall, a: u64, b: u64, c: u64) -> i64 {
+    syscall6(number, a, b, c, 0, 0, 0)
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+    let result = unsafe { syscall3(Syscall::Read, fd, buf.as_mut_ptr() as u64, buf.len() as u64) };
+    SyscallError::from_result(result).map(|n| n as usize)
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, SyscallError> {
+    let result = unsafe { syscall3(Syscall::Write, fd, buf.as_ptr() as u64, buf.len() as u64) };
+    SyscallError::from_result(result).map(|n| n as usize)
+}
+
+pub fn open(path: &str, flags: u64) -> Result<u64, SyscallError> {
+    let result = unsafe { syscall3(Syscall::Open, path.as_ptr() as u64, path.len() as u64, flags) };
+    SyscallError::from_result(result)
+}
+
+pub fn close(fd: u64) -> Result<(), SyscallError> {
+    let result = unsafe { syscall3(Syscall::Close, fd, 0, 0) };
+    SyscallError::from_result(result).map(|_| ())
+}
+
+pub fn exit(code: u64) -> ! {
+    unsafe { syscall3(Syscall::Exit, code, 0, 0) };
+    unreachable!()
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall3(Syscall::GetPid, 0, 0, 0) as u64 }
+}
+
+pub fn sleep(milliseconds: u64) {
+    unsafe { syscall3(Syscall::Sleep, milliseconds, 0, 0) };
+}
+
+pub fn mmap(addr: u64, len: u64, prot: u64) -> Result<u64, SyscallError> {
+    let result = unsafe { syscall3(Syscall::Mmap, addr, len, prot) };
+    SyscallError::from_result(result)
+}
+
+pub fn munmap(addr: u64, len: u64) -> Result<(), SyscallError> {
+    let result = unsafe { syscall3(Syscall::Munmap, addr, len, 0) };
+    SyscallError::from_result(result).map(|_| ())
+}
+
+pub fn mprotect(addr: u64, len: u64, prot: u64) -> Result<(), SyscallError> {
+    let result = unsafe { syscall3(Syscall::Mprotect, addr, len, prot) };
+    SyscallError::from_result(result).map(|_| ())
+}
+
+pub fn yield_now() {
+    unsafe { syscall3(Syscall::Yield, 0, 0, 0) };
+}
@@ -0,0 +1,131 @@
+use alloc::format;
+use alloc::string::String;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use crate::process::{self, address_space};
+use crate::task::{stack, thread};
+
+/// Exit code of a process killed by a fault, what shells show for SIGSEGV
+pub const EXIT_FAULT: u64 = 139;
+/// Exit code for an invalid instruction, SIGILL
+pub const EXIT_ILLEGAL: u64 = 132;
+/// Exit code for a bad bus access such as a misaligned one, SIGBUS
+pub const EXIT_BUS: u64 = 135;
+/// Exit code for a failed division or floating point operation, SIGFPE
+pub const EXIT_ARITHMETIC: u64 = 136;
+
+/// Sort out a page fault: back reserved user memory, kill a process that
+/// touched memory it does not own and panic on anything the kernel cannot
+/// recover from. Returns only if the faulting access can be retried.
+pub fn page_fault(frame: &InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let addr = Cr2::read();
+    let user_mode = error_code.contains(PageFaultErrorCode::USER_MODE);
+    let user_addr = address_space::is_user_range(addr, 1);
+
+    if user_addr && !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let backed = process::with_current(|process| process.address_space_mut().handle_fault(addr, write));
+        match backed {
+            Some(Ok(true)) => return,
+            Some(Err(err)) => {
+                log::error!("out of memory backing {:?}: {:?}", addr, err);
+                kill_current(addr, frame);
+            }
+            _ => {}
+        }
+    }
+
+    // a kernel access to user memory on behalf of a process is the process' fault
+    if user_mode || (user_addr && thread::current_pid().is_some()) {
+        if address_space::is_stack_guard(addr) {
+            log::error!("stack overflow in process {}", describe_current());
+        } else {
+            log::error!(
+                "process {} faulted at {:?} ({:?}) from {:?}",
+                describe_current(),
+                addr,
+                error_code,
+                frame.instruction_pointer,
+            );
+        }
+        kill_current(addr, frame);
+    }
+
+    if stack::is_guard_page(addr) {
+        stack_overflow(addr, frame);
+    }
+
+    panic!("EXCEPTION: PAGE FAULT at {:?} ({:?})\n{:#?}", addr, error_code, frame);
+}
+
+/// Sort out a general protection fault
+pub fn general_protection(frame: &InterruptStackFrame, error_code: u64) -> ! {
+    // `syscall` returning past the end of user space, see `syscall_entry`
+    if super::syscall::is_bad_return(frame.instruction_pointer) {
+        log::error!("process {} returned from a system call to a non-canonical address", describe_current());
+        process::exit_current(EXIT_FAULT);
+    }
+    exception("GENERAL PROTECTION FAULT", frame, Some(error_code), EXIT_FAULT);
+}
+
+/// Sort out an exception other than a page fault. Retrying the instruction
+/// would only raise it again, so a process that raised it is killed with
+/// `exit_code` and the kernel panics.
+pub fn exception(name: &str, frame: &InterruptStackFrame, error_code: Option<u64>, exit_code: u64) -> ! {
+    if is_user_mode(frame) && thread::current_pid().is_some() {
+        log::error!(
+            "process {} raised {} ({:?}) at {:?}",
+            describe_current(),
+            name,
+            error_code,
+            frame.instruction_pointer,
+        );
+        process::exit_current(exit_code);
+    }
+    match error_code {
+        Some(error_code) => panic!("EXCEPTION: {} ({:#x})\n{:#?}", name, error_code, frame),
+        None => panic!("EXCEPTION: {}\n{:#?}", name, frame),
+    }
+}
+
+/// Whether the CPU was running ring 3 code when `frame` was pushed
+fn is_user_mode(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment & 3 == 3
+}
+
+/// Called from the double fault handler, a kernel stack overflow usually
+/// ends up there because the page fault frame cannot be pushed
+pub fn double_fault(frame: &InterruptStackFrame) -> ! {
+    let addr = Cr2::read();
+    if stack::is_guard_page(addr) {
+        stack_overflow(addr, frame);
+    }
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", frame);
+}
+
+fn stack_overflow(addr: VirtAddr, frame: &InterruptStackFrame) -> ! {
+    let name = thread::stack_owner(addr);
+    panic!(
+        "kernel stack overflow in thread '{}' at {:?}\n{:#?}",
+        name.as_deref().unwrap_or("<unknown>"),
+        addr,
+        frame,
+    );
+}
+
+fn describe_current() -> String {
+    process::with_current(|process| format!("{} ({})", process.pid().as_u64(), process.name()))
+        .unwrap_or_else(|| String::from("<none>"))
+}
+
+/// End the faulting process. The thread is switched away from inside the
+/// handler and never returns to the faulting instruction, so kernel code
+/// must not touch user memory while it holds a lock, see `copy_from_user`.
+fn kill_current(addr: VirtAddr, frame: &InterruptStackFrame) -> ! {
+    if thread::current_pid().is_none() {
+        panic!("EXCEPTION: PAGE FAULT at {:?} in a kernel thread\n{:#?}", addr, frame);
+    }
+    process::exit_current(EXIT_FAULT);
+}
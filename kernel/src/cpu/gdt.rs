@@ -1,56 +1,85 @@
-use spin::Lazy;
+use alloc::vec;
+use conquer_once::spin::OnceCell;
 use x86_64::registers::segmentation::{DS, ES, SS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use super::percpu;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+const STACK_SIZE: usize = 4096 * 5;
+
+/// A TSS with fresh double fault and privilege stacks, one per CPU
+pub(super) fn new_tss() -> TaskStateSegment {
+    let double_fault_stack = vec![0u8; STACK_SIZE].leak();
+    // used for ring 3 -> ring 0 switches until the scheduler installs the
+    // kernel stack of the running thread
+    let privilege_stack = vec![0u8; STACK_SIZE].leak();
+
     let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        let stack_end = stack_start + STACK_SIZE;
-        stack_end
-    };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(double_fault_stack.as_ptr()) + STACK_SIZE;
+    tss.privilege_stack_table[0] = VirtAddr::from_ptr(privilege_stack.as_ptr()) + STACK_SIZE;
     tss
-});
+}
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Set the stack the CPU switches to when an interrupt arrives in ring 3
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    percpu::current().tss().privilege_stack_table[0] = stack_top;
 }
 
-static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+pub fn kernel_stack() -> VirtAddr {
+    percpu::current().kernel_stack()
+}
+
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+/// Every CPU builds its GDT the same way, so the selectors are shared
+static SELECTORS: OnceCell<Selectors> = OnceCell::uninit();
+
+// the user data segment has to come right before the user code segment for sysret
+pub(super) fn new_gdt(tss: &'static TaskStateSegment) -> GlobalDescriptorTable {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
     let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    SELECTORS.init_once(|| Selectors {
+        code_selector,
+        data_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
+    });
+    gdt
+}
 
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-    (
-        gdt,
-        Selectors {
-            code_selector,
-            data_selector,
-            tss_selector,
-        },
-    )
-});
+pub fn selectors() -> &'static Selectors {
+    SELECTORS.try_get().expect("no GDT has been built")
+}
 
+/// Load the GDT and TSS of the current CPU
 pub fn init_gdt() {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    let selectors = selectors();
+    percpu::current().gdt().load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
-        SS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        DS::set_reg(selectors.data_selector);
+        ES::set_reg(selectors.data_selector);
+        SS::set_reg(selectors.data_selector);
+        load_tss(selectors.tss_selector);
     }
-}
\ No newline at end of file
+}
@@ -6,9 +6,11 @@ use x86_64::instructions::port::PortReadOnly;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::PrivilegeLevel;
 
+use crate::cpu::fault;
 use crate::println;
-use crate::x2apic::LAPIC;
+use crate::x2apic;
 
 use crate::print;
 use crate::serial_println;
@@ -28,7 +30,11 @@ pub enum InterruptIndex {
     Mouse,
     ApicError,
     Syscall,
-    ApicSpurious
+    ApicSpurious,
+    /// Another CPU queued work for this one
+    Reschedule,
+    /// Another CPU changed kernel mappings
+    TlbShootdown,
 }
 
 impl InterruptIndex {
@@ -44,7 +50,19 @@ impl InterruptIndex {
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_handler);
     idt.general_protection_fault.set_handler_fn(general_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
     unsafe {
         idt.double_fault
             .set_handler_fn(double_fault_handler)
@@ -56,7 +74,13 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     idt[InterruptIndex::ApicError.as_usize()].set_handler_fn(lapic_error);
     idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(spurious_interrupt);
     idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt);
-    idt[InterruptIndex::Syscall.as_usize()].set_handler_fn(syscall_handler);
+    idt[InterruptIndex::Reschedule.as_usize()].set_handler_fn(reschedule_interrupt);
+    idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_interrupt);
+    unsafe {
+        idt[InterruptIndex::Syscall.as_usize()]
+            .set_handler_addr(crate::cpu::syscall::interrupt_entry())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
     idt
 });
 
@@ -70,40 +94,47 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let mut port = PortReadOnly::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::keyboard::add_scancode(scancode);
-    unsafe {
-        LAPIC.try_get().unwrap().lock()
-            .end_of_interrupt()
-    }
+    x2apic::end_of_interrupt();
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    if let Ok(func) = crate::TIMER_FN.try_get() {
-        func();
+    crate::time::on_timer_interrupt();
+    // the rest only needs doing once per tick, not on every CPU
+    if crate::cpu::percpu::current().is_bsp() {
+        crate::task::timer::on_tick();
+        if let Ok(func) = crate::TIMER_FN.try_get() {
+            func();
+        }
     }
 
-    unsafe {
-        LAPIC.try_get().unwrap().lock()
-            .end_of_interrupt()
-    }
+    x2apic::end_of_interrupt();
+
+    // may switch to another thread, so the EOI has to be sent first
+    crate::task::scheduler::tick();
 }
 
-extern "x86-interrupt" fn mouse_interrupt(_frame: InterruptStackFrame) {
-    unsafe { LAPIC.try_get().unwrap().lock().end_of_interrupt() }
+extern "x86-interrupt" fn reschedule_interrupt(_frame: InterruptStackFrame) {
+    x2apic::end_of_interrupt();
+    crate::task::scheduler::preempt();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt(_frame: InterruptStackFrame) {
+    crate::cpu::smp::handle_shootdown();
+    x2apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn syscall_handler(_frame: InterruptStackFrame) {
-    log::debug!("Syscall interrupt!");
-    unsafe { LAPIC.try_get().unwrap().lock().end_of_interrupt() }
+extern "x86-interrupt" fn mouse_interrupt(_frame: InterruptStackFrame) {
+    x2apic::end_of_interrupt();
 }
 
 extern "x86-interrupt" fn spurious_interrupt(_frame: InterruptStackFrame) {
     log::debug!("Received spurious interrupt!");
-    unsafe { LAPIC.try_get().unwrap().lock().end_of_interrupt() }
+    x2apic::end_of_interrupt();
 }
 
 extern "x86-interrupt" fn lapic_error(_frame: InterruptStackFrame) {
     println!("Local APIC error!");
-    unsafe { LAPIC.try_get().unwrap().lock().end_of_interrupt() }
+    x2apic::end_of_interrupt();
 }
 
 
@@ -111,26 +142,68 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    fault::double_fault(&stack_frame);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn general_fault_handler(stack_frame: InterruptStackFrame, some_num: u64) {
-    log::error!("EXCEPTION: GENERAL FAULT\n{:#?}\n\n{}", stack_frame, some_num);
+extern "x86-interrupt" fn general_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fault::general_protection(&stack_frame, error_code);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    fault::exception("DIVIDE ERROR", &stack_frame, None, fault::EXIT_ARITHMETIC);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    fault::exception("OVERFLOW", &stack_frame, None, fault::EXIT_FAULT);
+}
+
+extern "x86-interrupt" fn bound_range_handler(stack_frame: InterruptStackFrame) {
+    fault::exception("BOUND RANGE EXCEEDED", &stack_frame, None, fault::EXIT_FAULT);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    fault::exception("INVALID OPCODE", &stack_frame, None, fault::EXIT_ILLEGAL);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    fault::exception("DEVICE NOT AVAILABLE", &stack_frame, None, fault::EXIT_ILLEGAL);
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fault::exception("INVALID TSS", &stack_frame, Some(error_code), fault::EXIT_FAULT);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fault::exception("SEGMENT NOT PRESENT", &stack_frame, Some(error_code), fault::EXIT_BUS);
+}
+
+extern "x86-interrupt" fn stack_segment_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fault::exception("STACK SEGMENT FAULT", &stack_frame, Some(error_code), fault::EXIT_BUS);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fault::exception("x87 FLOATING POINT", &stack_frame, None, fault::EXIT_ARITHMETIC);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fault::exception("ALIGNMENT CHECK", &stack_frame, Some(error_code), fault::EXIT_BUS);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fault::exception("SIMD FLOATING POINT", &stack_frame, None, fault::EXIT_ARITHMETIC);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    loop {}
-}
\ No newline at end of file
+    fault::page_fault(&stack_frame, error_code);
+}
@@ -1,11 +1,43 @@
+pub mod fault;
 pub mod gdt;
 pub mod interrupts;
+pub mod percpu;
+pub mod smp;
+pub mod syscall;
+
+use alloc::string::String;
+use core::arch::x86_64::__cpuid;
+use x86_64::VirtAddr;
 
 pub fn init() {
     gdt::init_gdt();
     log::debug!("init'd gdt");
+    syscall::init();
+    log::debug!("init'd syscalls");
     interrupts::init_idt();
     log::debug!("init'd idt");
 //    x86_64::instructions::interrupts::enable();
     log::debug!("enabled interrupts");
-}
\ No newline at end of file
+}
+
+/// Set the stack used when entering the kernel from ring 3
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    gdt::set_kernel_stack(stack_top);
+    syscall::set_kernel_stack(stack_top);
+}
+
+/// Processor name from the extended CPUID leaves
+pub fn brand_string() -> Option<String> {
+    if unsafe { __cpuid(0x8000_0000) }.eax < 0x8000_0004 {
+        return None;
+    }
+    let mut bytes = [0u8; 48];
+    for (leaf, chunk) in (0x8000_0002..=0x8000_0004).zip(bytes.chunks_mut(16)) {
+        let result = unsafe { __cpuid(leaf) };
+        for (register, dest) in [result.eax, result.ebx, result.ecx, result.edx].iter().zip(chunk.chunks_mut(4)) {
+            dest.copy_from_slice(&register.to_le_bytes());
+        }
+    }
+    let name = core::str::from_utf8(&bytes).ok()?;
+    Some(String::from(name.trim_matches(|c: char| c == '\0' || c.is_whitespace())))
+}
@@ -0,0 +1,186 @@
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use conquer_once::spin::OnceCell;
+use x2apic::lapic::LocalApic;
+use x86_64::registers::model_specific::KernelGsBase;
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use super::gdt;
+use crate::io::x2apic as apic;
+use crate::sync::IrqSpinlock;
+
+/// CPUs the kernel will bring up at most
+pub const MAX_CPUS: usize = 64;
+
+/// Scratch space of `syscall_entry`, found through the kernel GS base
+#[repr(C)]
+pub struct SyscallScratch {
+    /// Kernel stack of the thread running on this CPU
+    pub kernel_stack: u64,
+    /// The user stack pointer until it is on the kernel stack
+    pub user_stack: u64,
+    /// Index of the CPU, for `current_index`
+    pub cpu_index: u64,
+}
+
+/// Everything that exists once per CPU
+pub struct Cpu {
+    index: usize,
+    apic_id: u32,
+    syscall: UnsafeCell<SyscallScratch>,
+    tss: UnsafeCell<TaskStateSegment>,
+    gdt: OnceCell<GlobalDescriptorTable>,
+    lapic: OnceCell<IrqSpinlock<LocalApic>>,
+    online: AtomicBool,
+    /// Set by `smp::shootdown` until this CPU flushed its TLB
+    pub(super) tlb_pending: AtomicBool,
+    /// Timer interrupts on this CPU
+    ticks: AtomicU64,
+    /// Armed deadline in TSC-deadline mode
+    pub(crate) timer_deadline: AtomicU64,
+    /// `time::TIMER_GENERATION` the LAPIC timer was last programmed for
+    pub(crate) timer_generation: AtomicU64,
+}
+
+// the TSS and the syscall scratch space are only touched by their own CPU
+unsafe impl Sync for Cpu {}
+
+const NO_CPU: AtomicPtr<Cpu> = AtomicPtr::new(null_mut());
+static CPUS: [AtomicPtr<Cpu>; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+const NO_APIC_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+/// Local APIC ID of every CPU by index, to find the current CPU before
+/// `syscall::init` ran on it
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl Cpu {
+    /// Set up the data of the next CPU, which lives for the rest of the
+    /// uptime. Returns `None` once there are `MAX_CPUS`.
+    pub fn register(apic_id: u32) -> Option<&'static Cpu> {
+        let index = COUNT.load(Ordering::Acquire);
+        if index >= MAX_CPUS {
+            return None;
+        }
+
+        let cpu: &'static Cpu = Box::leak(Box::new(Cpu {
+            index,
+            apic_id,
+            syscall: UnsafeCell::new(SyscallScratch { kernel_stack: 0, user_stack: 0, cpu_index: index as u64 }),
+            tss: UnsafeCell::new(gdt::new_tss()),
+            gdt: OnceCell::uninit(),
+            lapic: OnceCell::uninit(),
+            online: AtomicBool::new(false),
+            tlb_pending: AtomicBool::new(false),
+            ticks: AtomicU64::new(0),
+            timer_deadline: AtomicU64::new(0),
+            timer_generation: AtomicU64::new(0),
+        }));
+        cpu.gdt.init_once(|| gdt::new_gdt(unsafe { &*cpu.tss.get() }));
+        cpu.syscall_scratch().kernel_stack = cpu.kernel_stack().as_u64();
+
+        CPUS[index].store(cpu as *const Cpu as *mut Cpu, Ordering::Release);
+        APIC_IDS[index].store(apic_id, Ordering::Release);
+        COUNT.store(index + 1, Ordering::Release);
+        Some(cpu)
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.index == 0
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub(super) fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn gdt(&self) -> &GlobalDescriptorTable {
+        self.gdt.try_get().expect("GDT of a registered CPU")
+    }
+
+    /// This CPU's local APIC
+    pub fn lapic(&self) -> &IrqSpinlock<LocalApic> {
+        self.lapic.try_get().expect("local APIC not initialized")
+    }
+
+    pub fn set_lapic(&self, lapic: LocalApic) {
+        self.lapic.init_once(|| IrqSpinlock::new(lapic));
+    }
+
+    /// Must only be called on this CPU
+    pub(super) fn syscall_scratch(&self) -> &mut SyscallScratch {
+        unsafe { &mut *self.syscall.get() }
+    }
+
+    /// Must only be called on this CPU
+    pub(super) fn tss(&self) -> &mut TaskStateSegment {
+        unsafe { &mut *self.tss.get() }
+    }
+
+    /// Stack used when an interrupt or system call leaves ring 3
+    pub fn kernel_stack(&self) -> VirtAddr {
+        unsafe { (*self.tss.get()).privilege_stack_table[0] }
+    }
+}
+
+/// CPUs that have been registered, started or not
+pub fn count() -> usize {
+    COUNT.load(Ordering::Acquire)
+}
+
+pub fn get(index: usize) -> Option<&'static Cpu> {
+    let cpu = CPUS.get(index)?.load(Ordering::Acquire);
+    unsafe { cpu.as_ref() }
+}
+
+pub fn iter() -> impl Iterator<Item = &'static Cpu> {
+    (0..count()).filter_map(get)
+}
+
+pub fn online_count() -> usize {
+    iter().filter(|cpu| cpu.is_online()).count()
+}
+
+/// Index of the CPU this runs on. Callers that need it to stay correct
+/// have to keep interrupts disabled so the thread cannot move.
+pub fn current_index() -> usize {
+    let count = count();
+    if count <= 1 {
+        return 0;
+    }
+    // the kernel GS base stays on this CPU's `SyscallScratch` once it is set
+    let scratch = KernelGsBase::read();
+    if !scratch.is_null() {
+        return unsafe { (*scratch.as_ptr::<SyscallScratch>()).cpu_index } as usize;
+    }
+    let apic_id = apic::current_id();
+    (0..count)
+        .find(|&index| APIC_IDS[index].load(Ordering::Acquire) == apic_id)
+        .expect("running on an unregistered CPU")
+}
+
+pub fn current() -> &'static Cpu {
+    get(current_index()).expect("CPU data not initialized")
+}
@@ -0,0 +1,385 @@
+use core::arch::global_asm;
+use core::hint::spin_loop;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::{
+    mapper::MapToError, Mapper, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::interrupts::InterruptIndex;
+use super::percpu::{self, Cpu};
+use super::{gdt, syscall};
+use crate::io::{acpi, x2apic as apic};
+use crate::memory::{self, FRAME_ALLOCATOR, MAPPER};
+use crate::task::scheduler;
+use crate::task::stack::KernelStack;
+use crate::time::{self, Duration};
+
+// Application processors start in real mode at the page a startup IPI names.
+// This code is copied to low memory, switches straight to long mode on a
+// temporary GDT and a copy of the kernel's level 4 table that sits below
+// 4 GiB, then loads the real page table and calls `ap_entry`. The slots at
+// the end are filled in by `init`.
+global_asm!(
+    r#"
+.pushsection .rodata.ap_trampoline, "a"
+.balign 16
+.global ap_trampoline_start
+ap_trampoline_start:
+.code16
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    lgdt [ap_gdt_pointer - ap_trampoline_start]
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, dword ptr [ap_temp_cr3 - ap_trampoline_start]
+    mov cr3, eax
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+    .byte 0x66, 0xea
+.global ap_long_jump
+ap_long_jump:
+    .long 0
+    .word 0x08
+.code64
+.global ap_long_mode
+ap_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rax, qword ptr [rip + ap_kernel_cr3]
+    mov cr3, rax
+    mov rsp, qword ptr [rip + ap_stack_top]
+    mov rdi, qword ptr [rip + ap_cpu_index]
+    mov rax, qword ptr [rip + ap_entry_address]
+    call rax
+    ud2
+.balign 8
+.global ap_gdt
+ap_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+.global ap_gdt_pointer
+ap_gdt_pointer:
+    .word 23
+    .long 0
+.global ap_temp_cr3
+ap_temp_cr3:
+    .long 0
+.balign 8
+.global ap_kernel_cr3
+ap_kernel_cr3:
+    .quad 0
+.global ap_stack_top
+ap_stack_top:
+    .quad 0
+.global ap_cpu_index
+ap_cpu_index:
+    .quad 0
+.global ap_entry_address
+ap_entry_address:
+    .quad 0
+.global ap_trampoline_end
+ap_trampoline_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_long_jump: u8;
+    static ap_long_mode: u8;
+    static ap_gdt: u8;
+    static ap_gdt_pointer: u8;
+    static ap_temp_cr3: u8;
+    static ap_kernel_cr3: u8;
+    static ap_stack_top: u8;
+    static ap_cpu_index: u8;
+    static ap_entry_address: u8;
+}
+
+/// Set by an application processor as soon as it runs Rust code
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// Stack handed to the application processor being started
+static AP_STACK: Mutex<Option<KernelStack>> = Mutex::new(None);
+/// Control registers of the boot processor for the others to copy
+static BSP_CR0: AtomicU64 = AtomicU64::new(0);
+static BSP_CR4: AtomicU64 = AtomicU64::new(0);
+
+/// Serializes TLB shootdowns
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_END: AtomicU64 = AtomicU64::new(0);
+/// CPUs that still have to flush for the current shootdown
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+/// Ranges larger than this flush the whole TLB instead
+const SHOOTDOWN_MAX_PAGES: u64 = 64;
+
+/// Offset of a trampoline symbol from the start of the trampoline
+fn offset_of(symbol: *const u8) -> usize {
+    symbol as usize - unsafe { addr_of!(ap_trampoline_start) } as usize
+}
+
+/// Write `value` into the copy of the trampoline at `symbol`
+unsafe fn patch<T>(trampoline: PhysFrame, symbol: *const u8, value: T) {
+    let dest = memory::phys_to_virt(trampoline.start_address()).as_mut_ptr::<u8>();
+    (dest.add(offset_of(symbol)) as *mut T).write_unaligned(value);
+}
+
+/// Copy the startup code into low memory and fill in its slots. Returns
+/// whether the code page had to be identity mapped for the switch.
+fn prepare_trampoline(trampoline: PhysFrame) -> Option<bool> {
+    let base = trampoline.start_address().as_u64();
+    let temp_table = trampoline + 1;
+
+    let size = offset_of(unsafe { addr_of!(ap_trampoline_end) });
+    assert!(size <= 4096, "AP trampoline does not fit in a page");
+    unsafe {
+        let dest = memory::phys_to_virt(trampoline.start_address()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(addr_of!(ap_trampoline_start), dest, size);
+
+        let linear = |symbol: *const u8| (base + offset_of(symbol) as u64) as u32;
+        // the base of the GDT pointer follows its 16 bit limit
+        patch(trampoline, addr_of!(ap_gdt_pointer).add(2), linear(addr_of!(ap_gdt)));
+        patch(trampoline, addr_of!(ap_long_jump), linear(addr_of!(ap_long_mode)));
+        patch(trampoline, addr_of!(ap_temp_cr3), temp_table.start_address().as_u64() as u32);
+        patch(trampoline, addr_of!(ap_kernel_cr3), Cr3::read().0.start_address().as_u64());
+        patch(trampoline, addr_of!(ap_entry_address), ap_entry as usize as u64);
+    }
+
+    // the instruction after enabling paging is fetched from the same
+    // address, so the code page has to be mapped to itself
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base));
+    let mapped = without_interrupts(|| {
+        let mut mapper = MAPPER.try_get().unwrap().lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, trampoline, flags, &mut *frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Some(true)
+            }
+            Err(MapToError::PageAlreadyMapped(_))
+                if mapper.translate_addr(page.start_address()) == Some(PhysAddr::new(base)) => Some(false),
+            Err(err) => {
+                log::warn!("smp: cannot identity map the trampoline: {:?}", err);
+                None
+            }
+        }
+    })?;
+
+    // copied after the identity mapping so the copy has it too
+    let kernel_table = memory::phys_to_virt(Cr3::read().0.start_address()).as_ptr::<PageTable>();
+    let temp = memory::phys_to_virt(temp_table.start_address()).as_mut_ptr::<PageTable>();
+    unsafe { core::ptr::copy_nonoverlapping(kernel_table, temp, 1) };
+
+    Some(mapped)
+}
+
+fn wait_for(flag: impl Fn() -> bool, timeout: Duration) -> bool {
+    let until = time::now() + timeout;
+    while time::now() < until {
+        if flag() {
+            return true;
+        }
+        spin_loop();
+    }
+    flag()
+}
+
+/// How starting a processor went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Startup {
+    Online,
+    /// It never reached kernel code and was sent INIT to keep it parked
+    Failed,
+    /// It runs kernel code but is not online yet, it may still take the
+    /// stack in `AP_STACK`
+    Late,
+}
+
+/// Start the processor `cpu` with INIT, then up to two startup IPIs
+fn start(cpu: &'static Cpu, trampoline: PhysFrame) -> Startup {
+    let Some(stack) = KernelStack::new() else {
+        log::warn!("smp: no memory for the stack of cpu {}", cpu.index());
+        return Startup::Failed;
+    };
+    unsafe {
+        patch(trampoline, addr_of!(ap_stack_top), stack.top().as_u64());
+        patch(trampoline, addr_of!(ap_cpu_index), cpu.index() as u64);
+    }
+    *AP_STACK.lock() = Some(stack);
+    STARTED.store(false, Ordering::Release);
+
+    let vector = (trampoline.start_address().as_u64() >> 12) as u8;
+    let lapic = || percpu::current().lapic();
+    without_interrupts(|| unsafe { lapic().lock().send_init_ipi(cpu.apic_id()) });
+    time::busy_wait(Duration::from_millis(10));
+    for _ in 0..2 {
+        without_interrupts(|| unsafe { lapic().lock().send_sipi(vector, cpu.apic_id()) });
+        if wait_for(|| STARTED.load(Ordering::Acquire), Duration::from_millis(1)) {
+            break;
+        }
+    }
+
+    if wait_for(|| cpu.is_online(), Duration::from_millis(500)) {
+        return Startup::Online;
+    }
+    if STARTED.load(Ordering::Acquire) {
+        return Startup::Late;
+    }
+
+    // the trampoline slots are reused for the next processor, so this one
+    // must not come up later. It may have pushed onto the stack before the
+    // INIT lands, so the stack is never freed.
+    without_interrupts(|| unsafe { lapic().lock().send_init_ipi(cpu.apic_id()) });
+    time::busy_wait(Duration::from_millis(10));
+    core::mem::forget(AP_STACK.lock().take());
+    Startup::Failed
+}
+
+/// Start the application processors the ACPI tables list
+pub fn init() {
+    BSP_CR0.store(Cr0::read().bits(), Ordering::Relaxed);
+    BSP_CR4.store(Cr4::read().bits(), Ordering::Relaxed);
+    percpu::current().set_online();
+
+    let ap_ids = acpi::AP_APIC_IDS.get().map(|ids| ids.as_slice()).unwrap_or(&[]);
+    if ap_ids.is_empty() {
+        log::info!("smp: single processor");
+        return;
+    }
+    let Some(&trampoline) = memory::AP_TRAMPOLINE.get() else {
+        log::warn!("smp: no low memory for the AP trampoline, staying on one CPU");
+        return;
+    };
+    let Some(identity_mapped) = prepare_trampoline(trampoline) else {
+        return;
+    };
+
+    for &apic_id in ap_ids {
+        let Some(cpu) = Cpu::register(apic_id) else {
+            log::warn!("smp: more than {} CPUs, ignoring the rest", percpu::MAX_CPUS);
+            break;
+        };
+        match start(cpu, trampoline) {
+            Startup::Online => {}
+            Startup::Failed => log::warn!("smp: cpu {} (apic {}) did not come up", cpu.index(), apic_id),
+            Startup::Late => {
+                log::warn!("smp: cpu {} (apic {}) is slow to come up, not starting the rest", cpu.index(), apic_id);
+                break;
+            }
+        }
+    }
+
+    if identity_mapped {
+        without_interrupts(|| {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(trampoline.start_address().as_u64()));
+            if let Ok((_, flush)) = MAPPER.try_get().unwrap().lock().unmap(page) {
+                flush.flush();
+            }
+        });
+    }
+    log::info!("smp: {} of {} CPUs online", percpu::online_count(), percpu::count());
+}
+
+/// Where application processors land in long mode, on the stack from
+/// `AP_STACK`
+extern "C" fn ap_entry(index: u64) -> ! {
+    STARTED.store(true, Ordering::Release);
+    unsafe {
+        Cr0::write(Cr0Flags::from_bits_truncate(BSP_CR0.load(Ordering::Relaxed)));
+        Cr4::write(Cr4Flags::from_bits_truncate(BSP_CR4.load(Ordering::Relaxed)));
+    }
+
+    let cpu = percpu::get(index as usize).expect("started an unregistered CPU");
+    gdt::init_gdt();
+    syscall::init();
+    super::interrupts::init_idt();
+    cpu.set_lapic(apic::init_lapic());
+    time::init_cpu();
+
+    let stack = AP_STACK.lock().take();
+    scheduler::init_cpu(cpu.index(), stack);
+    cpu.set_online();
+    log::info!("cpu {} (apic {}) online", cpu.index(), cpu.apic_id());
+
+    interrupts::enable();
+    crate::hlt_loop();
+}
+
+/// Make `cpu` look at its run queue now instead of on its next tick
+pub fn send_reschedule(cpu: usize) {
+    if let Some(cpu) = percpu::get(cpu).filter(|cpu| cpu.is_online()) {
+        apic::send_ipi(cpu.apic_id(), InterruptIndex::Reschedule);
+    }
+}
+
+/// Flush `[start, end)` from the TLB of every CPU after kernel mappings in
+/// it changed. User mappings need no shootdown, a process only ever runs
+/// on one CPU and every CPU reloads CR3 when it switches away from it.
+pub fn shootdown(start: VirtAddr, end: VirtAddr) {
+    if percpu::online_count() <= 1 {
+        return;
+    }
+
+    without_interrupts(|| {
+        let _guard = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+            // whoever holds it may be waiting for this CPU
+            handle_shootdown();
+            spin_loop();
+        };
+
+        SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+        SHOOTDOWN_END.store(end.as_u64(), Ordering::Relaxed);
+        let me = percpu::current_index();
+        for cpu in percpu::iter().filter(|cpu| cpu.is_online() && cpu.index() != me) {
+            SHOOTDOWN_PENDING.fetch_add(1, Ordering::AcqRel);
+            cpu.tlb_pending.store(true, Ordering::Release);
+            apic::send_ipi(cpu.apic_id(), InterruptIndex::TlbShootdown);
+        }
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+    });
+}
+
+/// Flush the range of the running shootdown if it includes this CPU
+pub fn handle_shootdown() {
+    let cpu = percpu::current();
+    if !cpu.tlb_pending.swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    let start = SHOOTDOWN_START.load(Ordering::Relaxed);
+    let end = SHOOTDOWN_END.load(Ordering::Relaxed);
+    if end.saturating_sub(start) / 4096 > SHOOTDOWN_MAX_PAGES {
+        tlb::flush_all();
+    } else {
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end.max(start + 1) - 1));
+        for page in Page::range_inclusive(first, last) {
+            tlb::flush(page.start_address());
+        }
+    }
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+}
@@ -0,0 +1,170 @@
+use core::arch::global_asm;
+use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use super::gdt;
+use super::percpu::{self, SyscallScratch};
+
+/// Registers saved by both system call entry paths, lowest address first
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+}
+
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    // the kernel GS base points at the `SyscallScratch` of this CPU, it is
+    // swapped back before interrupts are on again
+    swapgs
+    mov gs:[8], rsp
+    mov rsp, gs:[0]
+    push qword ptr gs:[8]
+    swapgs
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    mov rdi, rsp
+    sti
+    call syscall_handler_inner
+    cli
+    // sysretq faults in ring 0 on the user stack when the return address in
+    // rcx is not canonical, iretq faults on the kernel stack instead
+    mov rcx, [rsp + 64]
+    shl rcx, 16
+    sar rcx, 16
+    cmp rcx, [rsp + 64]
+    jne 1f
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+1:
+    // the selectors sysretq would load, from the base in STAR
+    mov ecx, 0xC0000081
+    rdmsr
+    shr edx, 16
+    lea rax, [rdx + 8]
+    or rax, 3
+    push rax
+    push qword ptr [rsp + 80]
+    push qword ptr [rsp + 72]
+    lea rax, [rdx + 16]
+    or rax, 3
+    push rax
+    push qword ptr [rsp + 96]
+    mov r9, [rsp + 40]
+    mov r8, [rsp + 48]
+    mov r10, [rsp + 56]
+    mov rdx, [rsp + 64]
+    mov rsi, [rsp + 72]
+    mov rdi, [rsp + 80]
+    mov rax, [rsp + 88]
+    mov r11, [rsp + 96]
+    mov rcx, [rsp + 104]
+.global syscall_bad_return
+syscall_bad_return:
+    iretq
+
+.global syscall_interrupt_entry
+syscall_interrupt_entry:
+    // rcx and r11 too, `syscall` clobbers them but `int 0x24` must not leak
+    // what the handler leaves in them
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    mov rdi, rsp
+    sti
+    call syscall_handler_inner
+    cli
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r11
+    pop rcx
+    iretq
+"#
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_interrupt_entry();
+    fn syscall_bad_return();
+}
+
+#[no_mangle]
+extern "C" fn syscall_handler_inner(frame: &mut SyscallFrame) {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = crate::syscall::dispatch(frame.rax, args) as u64;
+}
+
+/// Address of the `int 0x24` fallback entry point for the IDT
+pub fn interrupt_entry() -> VirtAddr {
+    VirtAddr::new(syscall_interrupt_entry as usize as u64)
+}
+
+/// Whether a fault at `addr` is the `iretq` that `syscall` returns with when
+/// the return address is not canonical. The process ran into the end of
+/// user space and can be killed.
+pub fn is_bad_return(addr: VirtAddr) -> bool {
+    addr.as_u64() == syscall_bad_return as usize as u64
+}
+
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    percpu::current().syscall_scratch().kernel_stack = stack_top.as_u64();
+}
+
+/// Enable `syscall` on the current CPU
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("invalid GDT layout for syscall/sysret");
+
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // interrupts are enabled again once the kernel stack is in place
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    let cpu = percpu::current();
+    set_kernel_stack(cpu.kernel_stack());
+    KernelGsBase::write(VirtAddr::from_ptr(cpu.syscall_scratch() as *const SyscallScratch));
+
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
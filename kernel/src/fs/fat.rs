@@ -0,0 +1,382 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use fatfs::{Read as _, Seek as _, Write as _};
+use spin::Mutex;
+
+use super::{DirEntry, Directory, File, FsError, Inode, Metadata, NodeKind, SeekFrom};
+use crate::io::block::{BlockError, SharedBlockDevice, SECTOR_SIZE};
+
+#[derive(Debug)]
+pub enum IoFailure {
+    Block(BlockError),
+    UnexpectedEof,
+    WriteZero,
+}
+
+impl fatfs::IoError for IoFailure {
+    fn is_interrupted(&self) -> bool {
+        false
+    }
+
+    fn new_unexpected_eof_error() -> Self {
+        IoFailure::UnexpectedEof
+    }
+
+    fn new_write_zero_error() -> Self {
+        IoFailure::WriteZero
+    }
+}
+
+/// Byte stream over a block device, the storage `fatfs` runs on
+pub struct BlockStream {
+    device: SharedBlockDevice,
+    position: u64,
+    size: u64,
+}
+
+impl BlockStream {
+    pub fn new(device: SharedBlockDevice) -> BlockStream {
+        let size = device.lock().sector_count() * SECTOR_SIZE as u64;
+        BlockStream { device, position: 0, size }
+    }
+}
+
+impl fatfs::IoBase for BlockStream {
+    type Error = IoFailure;
+}
+
+impl fatfs::Read for BlockStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoFailure> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        let mut device = self.device.lock();
+
+        while done < buf.len() && self.position < self.size {
+            let lba = self.position / SECTOR_SIZE as u64;
+            let offset = (self.position % SECTOR_SIZE as u64) as usize;
+            let count = core::cmp::min(SECTOR_SIZE - offset, buf.len() - done);
+
+            device.read_sectors(lba, &mut sector).map_err(IoFailure::Block)?;
+            buf[done..done + count].copy_from_slice(&sector[offset..offset + count]);
+
+            done += count;
+            self.position += count as u64;
+        }
+        Ok(done)
+    }
+}
+
+impl fatfs::Write for BlockStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoFailure> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        let mut device = self.device.lock();
+
+        while done < buf.len() && self.position < self.size {
+            let lba = self.position / SECTOR_SIZE as u64;
+            let offset = (self.position % SECTOR_SIZE as u64) as usize;
+            let count = core::cmp::min(SECTOR_SIZE - offset, buf.len() - done);
+
+            // partial sectors need a read-modify-write
+            if count != SECTOR_SIZE {
+                device.read_sectors(lba, &mut sector).map_err(IoFailure::Block)?;
+            }
+            sector[offset..offset + count].copy_from_slice(&buf[done..done + count]);
+            device.write_sectors(lba, &sector).map_err(IoFailure::Block)?;
+
+            done += count;
+            self.position += count as u64;
+        }
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> Result<(), IoFailure> {
+        self.device.lock().flush().map_err(IoFailure::Block)
+    }
+}
+
+impl fatfs::Seek for BlockStream {
+    fn seek(&mut self, pos: fatfs::SeekFrom) -> Result<u64, IoFailure> {
+        let position = match pos {
+            fatfs::SeekFrom::Start(offset) => offset as i64,
+            fatfs::SeekFrom::End(offset) => self.size as i64 + offset,
+            fatfs::SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 || position as u64 > self.size {
+            return Err(IoFailure::UnexpectedEof);
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+/// Stamps created and modified files with the RTC time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RtcTimeProvider;
+
+impl fatfs::TimeProvider for RtcTimeProvider {
+    fn get_current_date(&self) -> fatfs::Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        // FAT can only store 1980 to 2107
+        let Some(now) = crate::time::wall_clock().filter(|now| (1980..=2107).contains(&now.year)) else {
+            return fatfs::DateTime::new(fatfs::Date::new(1980, 1, 1), fatfs::Time::new(0, 0, 0, 0));
+        };
+        fatfs::DateTime::new(
+            fatfs::Date::new(now.year, now.month as u16, now.day as u16),
+            fatfs::Time::new(now.hour as u16, now.minute as u16, now.second as u16, (now.nanosecond / 1_000_000) as u16),
+        )
+    }
+}
+
+type FileSystem = fatfs::FileSystem<BlockStream, RtcTimeProvider>;
+/// An open file. It borrows the `FileSystem` in the `FatFs` its `FatFile`
+/// keeps alive.
+type OpenFile = fatfs::File<'static, BlockStream, RtcTimeProvider>;
+
+/// A mounted FAT volume. `fatfs` is not thread safe, so every operation
+/// goes through the lock.
+pub struct FatFs {
+    fs: Mutex<FileSystem>,
+}
+
+unsafe impl Send for FatFs {}
+unsafe impl Sync for FatFs {}
+
+fn convert_error(err: fatfs::Error<IoFailure>) -> FsError {
+    match err {
+        fatfs::Error::Io(IoFailure::Block(err)) => FsError::Io(err),
+        fatfs::Error::NotFound => FsError::NotFound,
+        fatfs::Error::AlreadyExists => FsError::AlreadyExists,
+        fatfs::Error::DirectoryIsNotEmpty => FsError::NotEmpty,
+        fatfs::Error::NotEnoughSpace => FsError::NoSpace,
+        fatfs::Error::CorruptedFileSystem => FsError::Corrupted,
+        fatfs::Error::InvalidInput
+        | fatfs::Error::InvalidFileNameLength
+        | fatfs::Error::UnsupportedFileNameCharacter => FsError::InvalidPath,
+        _ => FsError::Io(BlockError::DeviceError),
+    }
+}
+
+/// `fatfs` paths are relative to the root directory
+fn fat_path(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+impl FatFs {
+    fn with_dir<R>(
+        &self,
+        path: &str,
+        f: impl FnOnce(fatfs::Dir<'_, BlockStream, RtcTimeProvider>) -> Result<R, fatfs::Error<IoFailure>>,
+    ) -> Result<R, FsError> {
+        let fs = self.fs.lock();
+        let root = fs.root_dir();
+        let dir = match fat_path(path) {
+            "" => root,
+            path => root.open_dir(path).map_err(convert_error)?,
+        };
+        f(dir).map_err(convert_error)
+    }
+
+    /// Look up the file at `path` once, reads and writes go through the
+    /// handle from then on
+    fn open_file(self: &Arc<FatFs>, path: &str) -> Result<FatFile, FsError> {
+        let fs = self.fs.lock();
+        let file = fs.root_dir().open_file(fat_path(path)).map_err(convert_error)?;
+        // the file system stays where it is for as long as `self` lives
+        let file = unsafe { core::mem::transmute::<fatfs::File<'_, BlockStream, RtcTimeProvider>, OpenFile>(file) };
+        Ok(FatFile { file: Some(file), fs: self.clone() })
+    }
+
+    /// Find the entry called `name` in the directory at `dir`
+    fn find_entry(&self, dir: &str, name: &str) -> Result<DirEntry, FsError> {
+        self.entries(dir)?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn entries(&self, dir: &str) -> Result<Vec<DirEntry>, FsError> {
+        self.with_dir(dir, |dir| {
+            let mut entries = Vec::new();
+            for entry in dir.iter() {
+                let entry = entry?;
+                let name = entry.file_name();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let kind = if entry.is_dir() { NodeKind::Directory } else { NodeKind::File };
+                entries.push(DirEntry {
+                    name,
+                    metadata: Metadata { kind, size: entry.len() },
+                });
+            }
+            Ok(entries)
+        })
+    }
+}
+
+fn child_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() || parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// A file or directory on a FAT volume, identified by its path
+struct FatNode {
+    fs: Arc<FatFs>,
+    path: String,
+    kind: NodeKind,
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let (parent, name) = super::path::split_last(&self.path);
+        match name {
+            Some(name) => self.fs.find_entry(&parent, name).map(|entry| entry.metadata),
+            None => Ok(Metadata { kind: NodeKind::Directory, size: 0 }),
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn File>, FsError> {
+        if self.kind == NodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        Ok(Box::new(self.fs.open_file(&self.path)?))
+    }
+
+    fn as_directory(&self) -> Result<Arc<dyn Directory>, FsError> {
+        if self.kind != NodeKind::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(Arc::new(FatDir {
+            fs: self.fs.clone(),
+            path: self.path.clone(),
+        }))
+    }
+}
+
+struct FatDir {
+    fs: Arc<FatFs>,
+    path: String,
+}
+
+impl FatDir {
+    fn node(&self, name: &str, kind: NodeKind) -> Arc<dyn Inode> {
+        Arc::new(FatNode {
+            fs: self.fs.clone(),
+            path: child_path(&self.path, name),
+            kind,
+        })
+    }
+}
+
+impl Directory for FatDir {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let entry = self.fs.find_entry(&self.path, name)?;
+        Ok(self.node(&entry.name, entry.metadata.kind))
+    }
+
+    fn entries(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.fs.entries(&self.path)
+    }
+
+    fn create_file(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.fs.with_dir(&self.path, |dir| dir.create_file(name).map(|_| ()))?;
+        Ok(self.node(name, NodeKind::File))
+    }
+
+    fn create_dir(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if self.fs.find_entry(&self.path, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        self.fs.with_dir(&self.path, |dir| dir.create_dir(name).map(|_| ()))?;
+        Ok(self.node(name, NodeKind::Directory))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        self.fs.with_dir(&self.path, |dir| dir.remove(name))
+    }
+}
+
+/// An open file on a FAT volume, which keeps its position and directory
+/// entry. Changes reach the disk on `flush` or when it is dropped.
+struct FatFile {
+    /// Only touched with the lock of `fs` held, `None` once dropped
+    file: Option<OpenFile>,
+    fs: Arc<FatFs>,
+}
+
+unsafe impl Send for FatFile {}
+
+impl FatFile {
+    fn with_file<R>(
+        &mut self,
+        f: impl FnOnce(&mut OpenFile) -> Result<R, fatfs::Error<IoFailure>>,
+    ) -> Result<R, FsError> {
+        let _fs = self.fs.fs.lock();
+        f(self.file.as_mut().unwrap()).map_err(convert_error)
+    }
+}
+
+impl File for FatFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.with_file(|file| file.read(buf))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        self.with_file(|file| file.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        self.with_file(|file| {
+            file.seek(match pos {
+                SeekFrom::Start(offset) => fatfs::SeekFrom::Start(offset),
+                SeekFrom::End(offset) => fatfs::SeekFrom::End(offset),
+                SeekFrom::Current(offset) => fatfs::SeekFrom::Current(offset),
+            })
+        })
+    }
+
+    fn truncate(&mut self) -> Result<(), FsError> {
+        self.with_file(|file| file.truncate())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.with_file(|file| file.flush())
+    }
+}
+
+impl Drop for FatFile {
+    fn drop(&mut self) {
+        // dropping the handle writes back its directory entry
+        let _fs = self.fs.fs.lock();
+        self.file.take();
+    }
+}
+
+/// Write a fresh FAT filesystem to `device`, the FAT type depends on its size
+pub fn format(device: SharedBlockDevice) -> Result<(), FsError> {
+    let mut stream = BlockStream::new(device);
+    fatfs::format_volume(&mut stream, fatfs::FormatVolumeOptions::new()).map_err(convert_error)
+}
+
+/// Open the FAT filesystem on `device` and return its root directory
+pub fn mount(device: SharedBlockDevice) -> Result<Arc<dyn Inode>, FsError> {
+    let stream = BlockStream::new(device);
+    let fs = fatfs::FileSystem::new(stream, fatfs::FsOptions::new().time_provider(RtcTimeProvider)).map_err(convert_error)?;
+
+    Ok(Arc::new(FatNode {
+        fs: Arc::new(FatFs { fs: Mutex::new(fs) }),
+        path: String::from("/"),
+        kind: NodeKind::Directory,
+    }))
+}
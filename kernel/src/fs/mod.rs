@@ -0,0 +1,245 @@
+pub mod fat;
+pub mod path;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::io::block::{self, BlockError, RamDisk};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotDirectory,
+    IsDirectory,
+    NotEmpty,
+    InvalidPath,
+    NoSpace,
+    Corrupted,
+    Unsupported,
+    Io(BlockError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// Something that lives at a path: a file or a directory
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    /// Open the node for reading and writing
+    fn open(&self) -> Result<Box<dyn File>, FsError>;
+
+    fn as_directory(&self) -> Result<Arc<dyn Directory>, FsError>;
+}
+
+/// An open file with its own position
+pub trait File: Send {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn write(&mut self, buf: &[u8]) -> Result<usize, FsError>;
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError>;
+    /// Cut the file off at the current position
+    fn truncate(&mut self) -> Result<(), FsError>;
+    fn flush(&mut self) -> Result<(), FsError>;
+}
+
+pub trait Directory: Send + Sync {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>;
+    fn entries(&self) -> Result<Vec<DirEntry>, FsError>;
+    fn create_file(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>;
+    fn create_dir(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>;
+    /// Remove a file or an empty directory
+    fn remove(&self, name: &str) -> Result<(), FsError>;
+}
+
+struct Mount {
+    path: String,
+    root: Arc<dyn Inode>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Attach a filesystem at `path`, which has to exist unless it is `/`
+pub fn mount(path: &str, root: Arc<dyn Inode>) -> Result<(), FsError> {
+    let path = path::normalize("/", path);
+    if path != "/" {
+        lookup(&path)?.as_directory()?;
+    }
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::AlreadyExists);
+    }
+    log::info!("mounted filesystem at {}", path);
+    mounts.push(Mount { path, root });
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = path::normalize("/", path);
+    let mut mounts = MOUNTS.lock();
+    let index = mounts.iter().position(|mount| mount.path == path).ok_or(FsError::NotFound)?;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// The mount with the longest path that is a prefix of `path` and the
+/// remainder of the path inside that filesystem
+fn find_mount(path: &str) -> Result<(Arc<dyn Inode>, String), FsError> {
+    let mounts = MOUNTS.lock();
+    mounts.iter()
+        .filter(|mount| {
+            mount.path == "/"
+                || path == mount.path
+                || (path.starts_with(&mount.path) && path.as_bytes()[mount.path.len()] == b'/')
+        })
+        .max_by_key(|mount| mount.path.len())
+        .map(|mount| {
+            let rest = if mount.path == "/" { path } else { &path[mount.path.len()..] };
+            (mount.root.clone(), String::from(rest))
+        })
+        .ok_or(FsError::NotFound)
+}
+
+/// Resolve an absolute, normalized path to its inode
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let (mut node, rest) = find_mount(path)?;
+    for name in path::components(&rest) {
+        node = node.as_directory()?.lookup(name)?;
+    }
+    Ok(node)
+}
+
+/// Resolve `path` relative to `cwd`
+pub fn resolve(cwd: &str, path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    lookup(&path::normalize(cwd, path))
+}
+
+/// The directory that would contain `path` and the name inside it
+fn parent_of(path: &str) -> Result<(Arc<dyn Directory>, String), FsError> {
+    let (parent, name) = path::split_last(path);
+    let name = name.ok_or(FsError::InvalidPath)?;
+    Ok((lookup(&parent)?.as_directory()?, String::from(name)))
+}
+
+pub fn open(path: &str) -> Result<Box<dyn File>, FsError> {
+    lookup(path)?.open()
+}
+
+/// Open a file, creating it if it does not exist yet
+pub fn create(path: &str) -> Result<Box<dyn File>, FsError> {
+    match lookup(path) {
+        Ok(node) => node.open(),
+        Err(FsError::NotFound) => {
+            let (parent, name) = parent_of(path)?;
+            parent.create_file(&name)?.open()
+        }
+        Err(err) => Err(err),
+    }
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = parent_of(path)?;
+    parent.create_dir(&name).map(|_| ())
+}
+
+pub fn remove(path: &str) -> Result<(), FsError> {
+    if MOUNTS.lock().iter().any(|mount| mount.path == path) {
+        return Err(FsError::Unsupported);
+    }
+    let (parent, name) = parent_of(path)?;
+    parent.remove(&name)
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    lookup(path)?.as_directory()?.entries()
+}
+
+/// Read a whole file into memory
+pub fn read_to_end(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut file = open(path)?;
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// Format a RAM disk with FAT and mount it as the root filesystem, so there
+/// always is somewhere to put files
+pub fn init() {
+    const RAMDISK_SECTORS: usize = 8192; // 4 MiB
+
+    let ramdisk: block::SharedBlockDevice = Arc::new(Mutex::new(RamDisk::new(RAMDISK_SECTORS)));
+    block::register("ram0", ramdisk.clone());
+
+    let root = fat::format(ramdisk.clone()).and_then(|_| fat::mount(ramdisk));
+    match root.and_then(|root| mount("/", root)) {
+        Ok(()) => {}
+        Err(err) => log::error!("failed to mount root filesystem: {:?}", err),
+    }
+
+    mount_disks();
+}
+
+/// Mount every disk that holds a FAT filesystem at `/mnt/<device>`
+fn mount_disks() {
+    if let Err(err) = create_dir("/mnt") {
+        log::error!("failed to create /mnt: {:?}", err);
+        return;
+    }
+
+    for (name, _) in block::list() {
+        if name == "ram0" {
+            continue;
+        }
+        let Some(device) = block::get(&name) else {
+            continue;
+        };
+        let root = match fat::mount(device) {
+            Ok(root) => root,
+            Err(err) => {
+                log::debug!("{}: no FAT filesystem ({:?})", name, err);
+                continue;
+            }
+        };
+
+        let path = alloc::format!("/mnt/{}", name);
+        if let Err(err) = create_dir(&path).and_then(|_| mount(&path, root)) {
+            log::error!("failed to mount {} at {}: {:?}", name, path, err);
+        }
+    }
+}
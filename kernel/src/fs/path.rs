@@ -0,0 +1,52 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Turn `path` into an absolute path without `.`, `..` or repeated slashes.
+/// Relative paths are taken from `cwd`, `..` at the root stays at the root.
+pub fn normalize(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+
+    let full = if path.starts_with('/') {
+        path.split('/').chain("".split('/'))
+    } else {
+        cwd.split('/').chain(path.split('/'))
+    };
+
+    for part in full {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            name => parts.push(name),
+        }
+    }
+
+    join(&parts)
+}
+
+/// The components of an absolute, normalized path
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty())
+}
+
+/// Split a normalized path into its parent directory and last component
+pub fn split_last(path: &str) -> (String, Option<&str>) {
+    let parts: Vec<&str> = components(path).collect();
+    match parts.split_last() {
+        Some((last, parent)) => (join(parent), Some(last)),
+        None => (String::from("/"), None),
+    }
+}
+
+fn join(parts: &[&str]) -> String {
+    let mut out = String::new();
+    for part in parts {
+        out.push('/');
+        out.push_str(part);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
@@ -1,8 +1,27 @@
 use core::ptr::NonNull;
+use alloc::vec::Vec;
 use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
 use acpi::InterruptModel;
+use acpi::fadt::Fadt;
+use acpi::mcfg::PciConfigRegions;
 use acpi::platform::interrupt::Apic;
-use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
+use acpi::platform::ProcessorState;
+use acpi::{AcpiHandler, AcpiTables, HpetInfo, PhysicalMapping};
+
+/// Physical ECAM base of every bus in PCI segment 0, indexed by bus number,
+/// if the firmware has an MCFG table
+pub static PCI_ECAM: OnceCell<Vec<Option<u64>>> = OnceCell::uninit();
+
+/// Physical address of the HPET registers, if the firmware has an HPET table
+pub static HPET: OnceCell<u64> = OnceCell::uninit();
+
+/// Local APIC IDs of the processors other than the boot processor that are
+/// waiting to be started
+pub static AP_APIC_IDS: OnceCell<Vec<u32>> = OnceCell::uninit();
+
+/// CMOS register holding the RTC century, if the FADT names one
+pub static RTC_CENTURY: OnceCell<u8> = OnceCell::uninit();
 
 #[derive(Clone)]
 struct AcpiMemHandler;
@@ -27,8 +46,34 @@ pub fn init(boot_info: &'static BootInfo) -> Apic {
     let acpi_tables = unsafe { AcpiTables::from_rsdp(AcpiMemHandler, rsdp_addr as usize) }.unwrap();
 
     log::info!("Find ACPI tables successfully!");
+
+    if let Ok(regions) = PciConfigRegions::new(&acpi_tables) {
+        PCI_ECAM.init_once(|| {
+            (0..=255u8).map(|bus| regions.physical_address(0, bus, 0, 0)).collect()
+        });
+    }
+
+    if let Ok(hpet) = HpetInfo::new(&acpi_tables) {
+        HPET.init_once(|| hpet.base_address as u64);
+    }
+
+    if let Ok(fadt) = acpi_tables.find_table::<Fadt>() {
+        if fadt.century != 0 {
+            RTC_CENTURY.init_once(|| fadt.century);
+        }
+    }
+
     let platform_info = acpi_tables.platform_info().expect("Failed to get platform info!");
 
+    if let Some(processors) = &platform_info.processor_info {
+        AP_APIC_IDS.init_once(|| {
+            processors.application_processors.iter()
+                .filter(|processor| processor.state == ProcessorState::WaitingForSipi)
+                .map(|processor| processor.local_apic_id)
+                .collect()
+        });
+    }
+
     let apic_info = match platform_info.interrupt_model {
         InterruptModel::Unknown => panic!("No APIC support, cannot continue!"),
         InterruptModel::Apic(apic) => apic,
@@ -0,0 +1,343 @@
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::io::pci::{DeviceMatch, PciDevice, PciDriver};
+
+// HBA registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0C;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+/// The HBA takes 64-bit addresses for command lists, FISes and buffers
+const CAP_S64A: u32 = 1 << 31;
+/// Registers of 32 ports, each 0x80 bytes after the generic block
+const HBA_SIZE: u64 = 0x1100;
+
+// port registers, relative to the port
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+/// Task file error interrupt status
+const IS_TFES: u32 = 1 << 30;
+
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// Bounce buffer pages, each gets its own PRDT entry since they do not have
+/// to be physically contiguous
+const BUFFER_PAGES: usize = 16;
+const SECTORS_PER_PAGE: usize = 4096 / SECTOR_SIZE;
+const MAX_SECTORS: usize = BUFFER_PAGES * SECTORS_PER_PAGE;
+
+/// Offset of the received FIS area in the command list page
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+/// Offset of the PRDT in a command table
+const PRDT_OFFSET: usize = 0x80;
+
+const POLL_LIMIT: usize = 10_000_000;
+
+fn phys_to_virt(frame: PhysFrame) -> *mut u8 {
+    crate::memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// A page for the HBA, below `limit` if it cannot reach memory above it
+fn allocate_dma_page(limit: Option<PhysAddr>) -> Result<PhysFrame, BlockError> {
+    match limit {
+        Some(limit) => crate::memory::allocate_dma_below(1, limit),
+        None => crate::memory::allocate_dma(1),
+    }
+    .ok_or(BlockError::DeviceError)
+}
+
+/// Registers of one port in the HBA memory space
+struct PortRegisters {
+    base: VirtAddr,
+}
+
+impl PortRegisters {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { read_volatile((self.base.as_u64() as usize + register) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { write_volatile((self.base.as_u64() as usize + register) as *mut u32, value) }
+    }
+
+    fn wait_clear(&self, register: usize, mask: u32) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            if self.read(register) & mask == 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PORT_CMD, self.read(PORT_CMD) & !(CMD_ST | CMD_FRE));
+        self.wait_clear(PORT_CMD, CMD_CR | CMD_FR)
+    }
+
+    fn start(&self) -> Result<(), BlockError> {
+        self.wait_clear(PORT_CMD, CMD_CR)?;
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_FRE);
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_ST);
+        Ok(())
+    }
+}
+
+/// A SATA drive on an AHCI port. Only command slot 0 is used and data goes
+/// through a bounce buffer.
+pub struct AhciDrive {
+    registers: PortRegisters,
+    command_list: PhysFrame,
+    command_table: PhysFrame,
+    buffer: Vec<PhysFrame>,
+    sectors: u64,
+}
+
+unsafe impl Send for AhciDrive {}
+
+impl AhciDrive {
+    fn new(registers: PortRegisters, dma_limit: Option<PhysAddr>) -> Result<AhciDrive, BlockError> {
+        registers.stop()?;
+
+        let command_list = allocate_dma_page(dma_limit)?;
+        let command_table = allocate_dma_page(dma_limit)?;
+        let buffer = (0..BUFFER_PAGES)
+            .map(|_| allocate_dma_page(dma_limit))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let list = command_list.start_address().as_u64();
+        let received = list + RECEIVED_FIS_OFFSET as u64;
+        registers.write(PORT_CLB, list as u32);
+        registers.write(PORT_CLBU, (list >> 32) as u32);
+        registers.write(PORT_FB, received as u32);
+        registers.write(PORT_FBU, (received >> 32) as u32);
+
+        // slot 0 points at our command table
+        let table = command_table.start_address().as_u64();
+        unsafe {
+            let header = phys_to_virt(command_list) as *mut u32;
+            write_volatile(header.add(2), table as u32);
+            write_volatile(header.add(3), (table >> 32) as u32);
+        }
+
+        registers.write(PORT_SERR, u32::MAX);
+        registers.write(PORT_IS, u32::MAX);
+        registers.write(PORT_IE, 0);
+        registers.start()?;
+
+        let mut drive = AhciDrive {
+            registers,
+            command_list,
+            command_table,
+            buffer,
+            sectors: 0,
+        };
+
+        let mut identify = [0u8; SECTOR_SIZE];
+        drive.command(COMMAND_IDENTIFY, 0, 1, false)?;
+        drive.copy_from_buffer(&mut identify);
+        let words: Vec<u16> = identify.chunks(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]])).collect();
+        drive.sectors = words[100] as u64
+            | (words[101] as u64) << 16
+            | (words[102] as u64) << 32
+            | (words[103] as u64) << 48;
+        if drive.sectors == 0 {
+            drive.sectors = words[60] as u64 | (words[61] as u64) << 16;
+        }
+        Ok(drive)
+    }
+
+    /// Build the command FIS and PRDT in slot 0, issue it and wait for it to
+    /// complete. `count` sectors are moved through the bounce buffer.
+    fn command(&mut self, command: u8, lba: u64, count: usize, write: bool) -> Result<(), BlockError> {
+        let pages = (count * SECTOR_SIZE).div_ceil(4096);
+
+        unsafe {
+            // command header: FIS length in dwords, write flag, PRDT length
+            let header = phys_to_virt(self.command_list) as *mut u32;
+            write_volatile(header, 5 | (write as u32) << 6 | (pages as u32) << 16);
+            write_volatile(header.add(1), 0);
+
+            let table = phys_to_virt(self.command_table);
+            core::ptr::write_bytes(table, 0, PRDT_OFFSET);
+
+            let fis = table;
+            *fis = FIS_TYPE_REG_H2D;
+            *fis.add(1) = 1 << 7; // this is a command
+            *fis.add(2) = command;
+            *fis.add(4) = lba as u8;
+            *fis.add(5) = (lba >> 8) as u8;
+            *fis.add(6) = (lba >> 16) as u8;
+            *fis.add(7) = 1 << 6; // LBA mode
+            *fis.add(8) = (lba >> 24) as u8;
+            *fis.add(9) = (lba >> 32) as u8;
+            *fis.add(10) = (lba >> 40) as u8;
+            *fis.add(12) = count as u8;
+            *fis.add(13) = (count >> 8) as u8;
+
+            let prdt = table.add(PRDT_OFFSET) as *mut u32;
+            let mut remaining = count * SECTOR_SIZE;
+            for (index, frame) in self.buffer.iter().take(pages).enumerate() {
+                let address = frame.start_address().as_u64();
+                let bytes = core::cmp::min(remaining, 4096);
+                let entry = prdt.add(index * 4);
+                write_volatile(entry, address as u32);
+                write_volatile(entry.add(1), (address >> 32) as u32);
+                write_volatile(entry.add(2), 0);
+                write_volatile(entry.add(3), (bytes - 1) as u32);
+                remaining -= bytes;
+            }
+        }
+
+        let registers = &self.registers;
+        registers.wait_clear(PORT_TFD, TFD_BSY | TFD_DRQ)?;
+        registers.write(PORT_IS, u32::MAX);
+        registers.write(PORT_CI, 1);
+
+        for _ in 0..POLL_LIMIT {
+            if registers.read(PORT_IS) & IS_TFES != 0 {
+                return Err(BlockError::DeviceError);
+            }
+            if registers.read(PORT_CI) & 1 == 0 {
+                if registers.read(PORT_TFD) & TFD_ERR != 0 {
+                    return Err(BlockError::DeviceError);
+                }
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn copy_from_buffer(&self, buf: &mut [u8]) {
+        for (chunk, frame) in buf.chunks_mut(4096).zip(&self.buffer) {
+            let page = unsafe { core::slice::from_raw_parts(phys_to_virt(*frame), chunk.len()) };
+            chunk.copy_from_slice(page);
+        }
+    }
+
+    fn copy_to_buffer(&self, buf: &[u8]) {
+        for (chunk, frame) in buf.chunks(4096).zip(&self.buffer) {
+            let page = unsafe { core::slice::from_raw_parts_mut(phys_to_virt(*frame), chunk.len()) };
+            page.copy_from_slice(chunk);
+        }
+    }
+}
+
+impl BlockDevice for AhciDrive {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        for (index, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let start = lba + (index * MAX_SECTORS) as u64;
+            self.command(COMMAND_READ_DMA_EXT, start, chunk.len() / SECTOR_SIZE, false)?;
+            self.copy_from_buffer(chunk);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        for (index, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let start = lba + (index * MAX_SECTORS) as u64;
+            self.copy_to_buffer(chunk);
+            self.command(COMMAND_WRITE_DMA_EXT, start, chunk.len() / SECTOR_SIZE, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.command(COMMAND_FLUSH_EXT, 0, 0, false)
+    }
+}
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &[DeviceMatch::Class { class: 0x01, subclass: 0x06, prog_if: Some(0x01) }],
+    probe,
+};
+
+/// Drives are numbered across all controllers (`ahci0`, `ahci1`, ...)
+static NEXT_DRIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Register a drive for every port of the controller with a SATA disk attached
+fn probe(device: &PciDevice) -> bool {
+    let Some(abar) = device.bar(5).and_then(|bar| bar.memory_address()) else {
+        return false;
+    };
+    device.enable_bus_master();
+
+    let hba = crate::memory::map_mmio(abar, HBA_SIZE);
+    let read = |register: usize| unsafe { read_volatile((hba.as_u64() as usize + register) as *const u32) };
+    let write = |register: usize, value: u32| unsafe { write_volatile((hba.as_u64() as usize + register) as *mut u32, value) };
+
+    write(HBA_GHC, read(HBA_GHC) | GHC_AHCI_ENABLE);
+    let cap = read(HBA_CAP);
+    log::info!("ahci controller at {}, cap {:#x}", device.address, cap);
+    // without 64-bit addressing the upper halves are ignored
+    let dma_limit = match cap & CAP_S64A {
+        0 => Some(PhysAddr::new(1 << 32)),
+        _ => None,
+    };
+
+    let implemented = read(HBA_PI);
+    for port in 0..32 {
+        if implemented & (1 << port) == 0 {
+            continue;
+        }
+        let registers = PortRegisters { base: hba + 0x100u64 + port as u64 * 0x80 };
+
+        // device present and link active
+        let status = registers.read(PORT_SSTS);
+        if status & 0x0F != 3 || (status >> 8) & 0x0F != 1 {
+            continue;
+        }
+        if registers.read(PORT_SIG) != SIG_ATA {
+            continue;
+        }
+
+        match AhciDrive::new(registers, dma_limit) {
+            Ok(drive) => {
+                let name = format!("ahci{}", NEXT_DRIVE.fetch_add(1, Ordering::Relaxed));
+                super::register(&name, Arc::new(Mutex::new(drive)));
+            }
+            Err(err) => log::error!("ahci port {}: {:?}", port, err),
+        }
+    }
+    true
+}
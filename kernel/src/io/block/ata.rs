@@ -0,0 +1,301 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use super::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const COMMAND_READ: u8 = 0x20;
+const COMMAND_READ_EXT: u8 = 0x24;
+const COMMAND_WRITE: u8 = 0x30;
+const COMMAND_WRITE_EXT: u8 = 0x34;
+const COMMAND_FLUSH: u8 = 0xE7;
+const COMMAND_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// Device control: no interrupts, the driver polls
+const CONTROL_NIEN: u8 = 0x02;
+
+/// Highest sector LBA28 can address
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// How often the status register is polled before giving up
+const POLL_LIMIT: usize = 1_000_000;
+
+/// The task file registers of one IDE channel
+struct Channel {
+    data: Port<u16>,
+    error: PortReadOnly<u8>,
+    sector_count: PortWriteOnly<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive: PortWriteOnly<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    alt_status: PortReadOnly<u8>,
+    control: PortWriteOnly<u8>,
+}
+
+impl Channel {
+    const fn new(io_base: u16, control_base: u16) -> Channel {
+        Channel {
+            data: Port::new(io_base),
+            error: PortReadOnly::new(io_base + 1),
+            sector_count: PortWriteOnly::new(io_base + 2),
+            lba_low: Port::new(io_base + 3),
+            lba_mid: Port::new(io_base + 4),
+            lba_high: Port::new(io_base + 5),
+            drive: PortWriteOnly::new(io_base + 6),
+            status: PortReadOnly::new(io_base + 7),
+            command: PortWriteOnly::new(io_base + 7),
+            alt_status: PortReadOnly::new(control_base),
+            control: PortWriteOnly::new(control_base),
+        }
+    }
+
+    /// Reading the alternate status four times gives the drive the 400ns it
+    /// needs after a drive select or command
+    fn delay(&mut self) {
+        for _ in 0..4 {
+            unsafe { self.alt_status.read(); }
+        }
+    }
+
+    fn select(&mut self, slave: bool, lba_mode: bool, head: u8) {
+        let value = 0xA0 | (slave as u8) << 4 | (lba_mode as u8) << 6 | (head & 0x0F);
+        unsafe { self.drive.write(value); }
+        self.delay();
+    }
+
+    fn wait_not_busy(&mut self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = unsafe { self.status.read() };
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Wait until the drive has data for us or wants data from us
+    fn wait_data(&mut self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = unsafe { self.status.read() };
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                log::debug!("ata error {:#x}", unsafe { self.error.read() });
+                return Err(BlockError::DeviceError);
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Program the address registers and issue `command`
+    fn issue(&mut self, slave: bool, lba48: bool, lba: u64, count: u16, command: u8) -> Result<(), BlockError> {
+        self.wait_not_busy()?;
+        unsafe {
+            if lba48 {
+                self.select(slave, true, 0);
+                // high bytes first, the registers are two deep FIFOs
+                self.sector_count.write((count >> 8) as u8);
+                self.lba_low.write((lba >> 24) as u8);
+                self.lba_mid.write((lba >> 32) as u8);
+                self.lba_high.write((lba >> 40) as u8);
+            } else {
+                self.select(slave, true, (lba >> 24) as u8);
+            }
+            self.sector_count.write(count as u8);
+            self.lba_low.write(lba as u8);
+            self.lba_mid.write((lba >> 8) as u8);
+            self.lba_high.write((lba >> 16) as u8);
+            self.command.write(command);
+        }
+        self.delay();
+        Ok(())
+    }
+
+    /// Run IDENTIFY DEVICE, `None` if there is no ATA drive
+    fn identify(&mut self, slave: bool) -> Option<[u16; 256]> {
+        self.select(slave, false, 0);
+        unsafe {
+            self.sector_count.write(0);
+            self.lba_low.write(0);
+            self.lba_mid.write(0);
+            self.lba_high.write(0);
+            self.command.write(COMMAND_IDENTIFY);
+        }
+        self.delay();
+
+        // no drive, or a floating bus without a controller
+        let status = unsafe { self.status.read() };
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+
+        // ATAPI and SATA devices set a signature and abort IDENTIFY
+        if unsafe { self.lba_mid.read() != 0 || self.lba_high.read() != 0 } {
+            return None;
+        }
+        self.wait_data().ok()?;
+
+        let mut words = [0u16; 256];
+        for word in words.iter_mut() {
+            *word = unsafe { self.data.read() };
+        }
+        Some(words)
+    }
+}
+
+/// A drive on one of the legacy IDE channels, accessed with PIO
+pub struct AtaDrive {
+    channel: Arc<Mutex<Channel>>,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+    model: String,
+}
+
+impl AtaDrive {
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn use_lba48(&self, lba: u64, count: u64) -> bool {
+        self.lba48 && lba + count > LBA28_LIMIT
+    }
+}
+
+/// The model name is stored as byte swapped ASCII in words 27 to 46
+fn model_name(identify: &[u16; 256]) -> String {
+    let mut bytes = [0u8; 40];
+    for (index, word) in identify[27..47].iter().enumerate() {
+        bytes[index * 2] = (word >> 8) as u8;
+        bytes[index * 2 + 1] = *word as u8;
+    }
+    String::from(String::from_utf8_lossy(&bytes).trim())
+}
+
+impl BlockDevice for AtaDrive {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        let mut channel = self.channel.lock();
+
+        // one command moves at most 256 sectors
+        for (index, chunk) in buf.chunks_mut(256 * SECTOR_SIZE).enumerate() {
+            let start = lba + index as u64 * 256;
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            let lba48 = self.use_lba48(start, count);
+            let command = if lba48 { COMMAND_READ_EXT } else { COMMAND_READ };
+            channel.issue(self.slave, lba48, start, count as u16, command)?;
+
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                channel.wait_data()?;
+                for bytes in sector.chunks_mut(2) {
+                    let word = unsafe { channel.data.read() };
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+                channel.delay();
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        let mut channel = self.channel.lock();
+
+        for (index, chunk) in buf.chunks(256 * SECTOR_SIZE).enumerate() {
+            let start = lba + index as u64 * 256;
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            let lba48 = self.use_lba48(start, count);
+            let command = if lba48 { COMMAND_WRITE_EXT } else { COMMAND_WRITE };
+            channel.issue(self.slave, lba48, start, count as u16, command)?;
+
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                channel.wait_data()?;
+                for bytes in sector.chunks(2) {
+                    unsafe { channel.data.write(u16::from_le_bytes([bytes[0], bytes[1]])); }
+                }
+                channel.delay();
+            }
+            channel.wait_not_busy()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        let mut channel = self.channel.lock();
+        let command = if self.lba48 { COMMAND_FLUSH_EXT } else { COMMAND_FLUSH };
+        channel.wait_not_busy()?;
+        channel.select(self.slave, false, 0);
+        unsafe { channel.command.write(command); }
+        channel.delay();
+        let status = channel.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::DeviceError);
+        }
+        Ok(())
+    }
+}
+
+fn probe(channel: &Arc<Mutex<Channel>>, slave: bool) -> Option<AtaDrive> {
+    let identify = channel.lock().identify(slave)?;
+
+    let lba48 = identify[83] & (1 << 10) != 0;
+    let sectors = if lba48 {
+        identify[100] as u64
+            | (identify[101] as u64) << 16
+            | (identify[102] as u64) << 32
+            | (identify[103] as u64) << 48
+    } else {
+        identify[60] as u64 | (identify[61] as u64) << 16
+    };
+    if sectors == 0 {
+        return None;
+    }
+
+    Some(AtaDrive {
+        channel: channel.clone(),
+        slave,
+        lba48,
+        sectors,
+        model: model_name(&identify),
+    })
+}
+
+/// Probe both legacy channels and register every ATA drive as `ata0` (primary
+/// master) to `ata3` (secondary slave)
+pub fn init() {
+    let channels = [Channel::new(0x1F0, 0x3F6), Channel::new(0x170, 0x376)];
+
+    for (channel_index, mut channel) in channels.into_iter().enumerate() {
+        unsafe { channel.control.write(CONTROL_NIEN); }
+        let channel = Arc::new(Mutex::new(channel));
+
+        for slave in [false, true] {
+            let Some(drive) = probe(&channel, slave) else {
+                continue;
+            };
+            let name = format!("ata{}", channel_index * 2 + slave as usize);
+            log::info!("{}: {} ({})", name, drive.model(), if drive.lba48 { "LBA48" } else { "LBA28" });
+            super::register(&name, Arc::new(Mutex::new(drive)));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+pub mod ahci;
+pub mod ata;
+pub mod virtio;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request goes past the end of the device
+    OutOfRange,
+    /// The buffer is not a whole number of sectors
+    BadBuffer,
+    /// The device reported an error
+    DeviceError,
+    Timeout,
+    ReadOnly,
+}
+
+/// A disk addressed in `SECTOR_SIZE` byte sectors
+pub trait BlockDevice: Send {
+    fn sector_count(&self) -> u64;
+
+    /// Read `buf.len() / SECTOR_SIZE` sectors starting at `lba`
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / SECTOR_SIZE` sectors starting at `lba`
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+pub type SharedBlockDevice = Arc<Mutex<dyn BlockDevice>>;
+
+/// Check a request against the device size, returning the sector count
+pub fn check_request(device_sectors: u64, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if len % SECTOR_SIZE != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device_sectors => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// A block device backed by kernel heap memory
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    pub fn new(sectors: usize) -> RamDisk {
+        RamDisk {
+            data: vec![0; sectors * SECTOR_SIZE],
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.sector_count(), lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.sector_count(), lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+static DEVICES: Mutex<Vec<(String, SharedBlockDevice)>> = Mutex::new(Vec::new());
+
+/// Make a device available by name (e.g. "ram0", "ata0")
+pub fn register(name: &str, device: SharedBlockDevice) {
+    log::info!("block device {}: {} sectors", name, device.lock().sector_count());
+    DEVICES.lock().push((String::from(name), device));
+}
+
+pub fn get(name: &str) -> Option<SharedBlockDevice> {
+    DEVICES.lock().iter()
+        .find(|(device_name, _)| device_name == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// Names and sizes (in sectors) of all registered devices
+pub fn list() -> Vec<(String, u64)> {
+    DEVICES.lock().iter()
+        .map(|(name, device)| (name.clone(), device.lock().sector_count()))
+        .collect()
+}
+
+/// Probe the storage controllers and register their drives
+pub fn init() {
+    ata::init();
+    crate::io::pci::register_driver(&ahci::DRIVER);
+    crate::io::pci::register_driver(&virtio::DRIVER);
+}
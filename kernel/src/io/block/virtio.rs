@@ -0,0 +1,173 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use super::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::io::pci::{DeviceMatch, PciDevice, PciDriver};
+use crate::io::virtio::{self, pci as virtio_pci, queue::Buffer, queue::VirtQueue, Transport};
+
+const DEVICE_TYPE_BLOCK: u16 = 2;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+
+/// Size of the bounce buffer every request goes through
+const BUFFER_PAGES: usize = 16;
+const MAX_SECTORS: usize = BUFFER_PAGES * 4096 / SECTOR_SIZE;
+
+/// Offset of the status byte in the request page, after the 16 byte header
+const STATUS_OFFSET: u64 = 16;
+
+/// A virtio block device with one request queue, driven by polling
+pub struct VirtioBlk {
+    transport: Box<dyn Transport>,
+    queue: VirtQueue,
+    capacity: u64,
+    read_only: bool,
+    can_flush: bool,
+    /// Request header followed by the status byte
+    request: PhysFrame,
+    buffer: PhysFrame,
+}
+
+impl VirtioBlk {
+    pub fn new(mut transport: Box<dyn Transport>) -> Result<VirtioBlk, virtio::VirtioError> {
+        let features = virtio::begin_init(&mut *transport, F_RO | F_FLUSH)?;
+        let queue = VirtQueue::new(&mut *transport, 0)?;
+        virtio::finish_init(&mut *transport);
+
+        let request = crate::memory::allocate_dma(1).ok_or(virtio::VirtioError::OutOfMemory)?;
+        let buffer = crate::memory::allocate_dma(BUFFER_PAGES).ok_or(virtio::VirtioError::OutOfMemory)?;
+        let capacity = transport.read_config_u64(0);
+
+        Ok(VirtioBlk {
+            transport,
+            queue,
+            capacity,
+            read_only: features & F_RO != 0,
+            can_flush: features & F_FLUSH != 0,
+            request,
+            buffer,
+        })
+    }
+
+    fn buffer_ptr(&self) -> *mut u8 {
+        crate::memory::phys_to_virt(self.buffer.start_address()).as_mut_ptr()
+    }
+
+    /// Send one request, with `sectors` sectors of the bounce buffer as data
+    fn request(&mut self, kind: u32, sector: u64, sectors: usize) -> Result<(), BlockError> {
+        let header = self.request.start_address();
+        let header_ptr = crate::memory::phys_to_virt(header).as_mut_ptr::<u8>();
+        unsafe {
+            (header_ptr as *mut u32).write_volatile(kind);
+            (header_ptr.add(4) as *mut u32).write_volatile(0);
+            (header_ptr.add(8) as *mut u64).write_volatile(sector);
+            header_ptr.add(STATUS_OFFSET as usize).write_volatile(0xFF);
+        }
+
+        let status = Buffer { addr: header + STATUS_OFFSET, len: 1, device_writable: true };
+        let header = Buffer { addr: header, len: 16, device_writable: false };
+        let data = Buffer {
+            addr: self.buffer.start_address(),
+            len: (sectors * SECTOR_SIZE) as u32,
+            device_writable: kind == REQUEST_IN,
+        };
+
+        let result = if sectors == 0 {
+            self.queue.submit_and_wait(&mut *self.transport, &[header, status])
+        } else {
+            self.queue.submit_and_wait(&mut *self.transport, &[header, data, status])
+        };
+        result.map_err(|err| match err {
+            virtio::VirtioError::Timeout => BlockError::Timeout,
+            _ => BlockError::DeviceError,
+        })?;
+
+        match unsafe { header_ptr.add(STATUS_OFFSET as usize).read_volatile() } {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::DeviceError),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.capacity, lba, buf.len())?;
+        for (index, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            self.request(REQUEST_IN, lba + (index * MAX_SECTORS) as u64, chunk.len() / SECTOR_SIZE)?;
+            let data = unsafe { core::slice::from_raw_parts(self.buffer_ptr(), chunk.len()) };
+            chunk.copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.capacity, lba, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        for (index, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let data = unsafe { core::slice::from_raw_parts_mut(self.buffer_ptr(), chunk.len()) };
+            data.copy_from_slice(chunk);
+            self.request(REQUEST_OUT, lba + (index * MAX_SECTORS) as u64, chunk.len() / SECTOR_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        self.request(REQUEST_FLUSH, 0, 0)
+    }
+}
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        DeviceMatch::Id { vendor: virtio::VENDOR_ID, device: 0x1001 },
+        DeviceMatch::Id { vendor: virtio::VENDOR_ID, device: 0x1042 },
+    ],
+    probe,
+};
+
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(device: &PciDevice) -> bool {
+    if virtio_pci::device_type(device) != DEVICE_TYPE_BLOCK {
+        return false;
+    }
+    let Some(transport) = virtio_pci::transport(device) else {
+        log::error!("virtio-blk {}: no usable transport", device.address);
+        return false;
+    };
+
+    match VirtioBlk::new(transport) {
+        Ok(disk) => {
+            let name = format!("virtio{}", NEXT_DISK.fetch_add(1, Ordering::Relaxed));
+            if disk.read_only {
+                log::info!("{} is read only", name);
+            }
+            super::register(&name, Arc::new(Mutex::new(disk)));
+            true
+        }
+        Err(err) => {
+            log::error!("virtio-blk {}: {:?}", device.address, err);
+            false
+        }
+    }
+}
@@ -3,7 +3,9 @@ use futures_util::task::AtomicWaker;
 use core::{pin::Pin, task::{Context, Poll}};
 use crossbeam_queue::{ArrayQueue, PopError};
 use futures_util::{stream::Stream, StreamExt};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode, KeyEvent, KeyState, Modifiers};
+
+use crate::api::console;
 
 const SCANCODE_QUEUE_SIZE: usize = 128;
 
@@ -54,6 +56,29 @@ pub(crate) fn add_scancode(scancode: u8) {
     }
 }
 
+/// Handle the keys the console uses before a program sees them:
+/// Shift+PageUp and Shift+PageDown page through the scrollback. `modifiers`
+/// is the keyboard's state before `event`. Returns whether the key was
+/// used up.
+pub fn console_key(event: &KeyEvent, modifiers: &Modifiers) -> bool {
+    let down = event.state == KeyState::Down;
+    match event.code {
+        KeyCode::PageUp if modifiers.is_shifted() => {
+            if down {
+                console::page_up();
+            }
+            true
+        }
+        KeyCode::PageDown if modifiers.is_shifted() => {
+            if down {
+                console::page_down();
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
 //pub async fn print_keypresses() {}
 
 pub async fn print_keypresses() {
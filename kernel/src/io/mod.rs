@@ -1,5 +1,8 @@
 pub mod acpi;
+pub mod block;
 pub mod vga;
 pub mod keyboard;
+pub mod pci;
 pub mod serial;
+pub mod virtio;
 pub mod x2apic;
\ No newline at end of file
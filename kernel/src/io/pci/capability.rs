@@ -0,0 +1,148 @@
+use alloc::vec::Vec;
+use core::ptr::write_volatile;
+use x86_64::VirtAddr;
+
+use super::{PciAddress, PciDevice};
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCIE: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Where the local APICs receive message signalled interrupts
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability in configuration space
+    pub offset: u16,
+}
+
+/// Walk the capability list, if the device has one
+pub fn read_list(address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if address.read_u16(0x06) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+
+    let mut offset = (address.read_u8(0x34) & 0xFC) as u16;
+    // a broken list could loop, there is only room for 48 entries
+    while offset != 0 && capabilities.len() < 48 {
+        capabilities.push(Capability { id: address.read_u8(offset), offset });
+        offset = (address.read_u8(offset + 1) & 0xFC) as u16;
+    }
+    capabilities
+}
+
+pub fn name(id: u8) -> &'static str {
+    match id {
+        0x01 => "power management",
+        CAP_MSI => "MSI",
+        CAP_VENDOR => "vendor specific",
+        CAP_PCIE => "PCI express",
+        CAP_MSIX => "MSI-X",
+        _ => "unknown",
+    }
+}
+
+fn message(apic_id: u8, vector: u8) -> (u32, u32) {
+    (MSI_ADDRESS_BASE | (apic_id as u32) << 12, vector as u32)
+}
+
+/// The MSI capability: one interrupt vector programmed in config space
+pub struct Msi {
+    address: PciAddress,
+    offset: u16,
+}
+
+impl Msi {
+    pub fn new(address: PciAddress, offset: u16) -> Msi {
+        Msi { address, offset }
+    }
+
+    fn control(&self) -> u16 {
+        self.address.read_u16(self.offset + 2)
+    }
+
+    /// Deliver the device's interrupt as `vector` on the local APIC `apic_id`
+    pub fn enable(&self, apic_id: u8, vector: u8) {
+        let (message_address, data) = message(apic_id, vector);
+        let control = self.control();
+        let is_64 = control & (1 << 7) != 0;
+
+        self.address.write_u32(self.offset + 4, message_address);
+        if is_64 {
+            self.address.write_u32(self.offset + 8, 0);
+            self.address.write_u16(self.offset + 12, data as u16);
+        } else {
+            self.address.write_u16(self.offset + 8, data as u16);
+        }
+
+        // a single message, enabled
+        let control = (control & !(0x7 << 4)) | 1;
+        self.address.write_u16(self.offset + 2, control);
+    }
+
+    pub fn disable(&self) {
+        self.address.write_u16(self.offset + 2, self.control() & !1);
+    }
+}
+
+/// The MSI-X capability: a table of vectors in one of the device's BARs
+pub struct MsiX {
+    address: PciAddress,
+    offset: u16,
+    table: VirtAddr,
+    table_size: u16,
+}
+
+impl MsiX {
+    pub fn new(device: &PciDevice, offset: u16) -> Option<MsiX> {
+        let address = device.address;
+        let table_info = address.read_u32(offset + 4);
+        let bar = device.bar((table_info & 0x7) as usize)?.memory_address()?;
+        let table_size = (address.read_u16(offset + 2) & 0x7FF) + 1;
+
+        let table_address = bar + (table_info & !0x7) as u64;
+        let table = crate::memory::map_mmio(table_address, table_size as u64 * 16);
+        Some(MsiX { address, offset, table, table_size })
+    }
+
+    pub fn table_size(&self) -> u16 {
+        self.table_size
+    }
+
+    /// Turn MSI-X on with every entry masked
+    pub fn enable(&self) {
+        for index in 0..self.table_size {
+            self.mask(index, true);
+        }
+        let control = self.address.read_u16(self.offset + 2);
+        self.address.write_u16(self.offset + 2, (control | 1 << 15) & !(1 << 14));
+    }
+
+    pub fn mask(&self, index: u16, masked: bool) {
+        let entry = self.entry(index);
+        unsafe { write_volatile(entry.add(3), masked as u32) };
+    }
+
+    /// Route entry `index` to `vector` on the local APIC `apic_id` and unmask it
+    pub fn set_entry(&self, index: u16, apic_id: u8, vector: u8) {
+        let (message_address, data) = message(apic_id, vector);
+        let entry = self.entry(index);
+        unsafe {
+            write_volatile(entry, message_address);
+            write_volatile(entry.add(1), 0);
+            write_volatile(entry.add(2), data);
+        }
+        self.mask(index, false);
+    }
+
+    fn entry(&self, index: u16) -> *mut u32 {
+        assert!(index < self.table_size, "MSI-X entry {} out of range", index);
+        (self.table + index as u64 * 16).as_mut_ptr()
+    }
+}
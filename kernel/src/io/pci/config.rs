@@ -0,0 +1,94 @@
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+use super::PciAddress;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Configuration space of one bus in ECAM: 32 devices with 8 functions of 4 KiB
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+/// Memory mapped configuration space, one window per bus
+struct Ecam {
+    physical: Vec<Option<u64>>,
+    mapped: Vec<Option<VirtAddr>>,
+}
+
+static ECAM: OnceCell<Mutex<Ecam>> = OnceCell::uninit();
+/// The legacy mechanism is an address write followed by a data access, which
+/// must not be interleaved
+static LEGACY: Mutex<()> = Mutex::new(());
+
+/// Use ECAM for the buses in `bases` (indexed by bus number) from now on
+pub fn init_ecam(bases: Vec<Option<u64>>) {
+    let buses = bases.iter().filter(|base| base.is_some()).count();
+    log::info!("pci: using ECAM for {} buses", buses);
+    ECAM.init_once(|| Mutex::new(Ecam {
+        mapped: alloc::vec![None; bases.len()],
+        physical: bases,
+    }));
+}
+
+/// The virtual base of a bus's ECAM window, mapping it on first use
+fn ecam_bus(bus: u8) -> Option<VirtAddr> {
+    let mut ecam = ECAM.try_get().ok()?.lock();
+    let bus = bus as usize;
+    if let Some(base) = ecam.mapped.get(bus).copied().flatten() {
+        return Some(base);
+    }
+
+    let physical = ecam.physical.get(bus).copied().flatten()?;
+    let base = crate::memory::map_mmio(physical, ECAM_BUS_SIZE);
+    ecam.mapped[bus] = Some(base);
+    Some(base)
+}
+
+fn ecam_ptr(base: VirtAddr, address: PciAddress, offset: u16) -> *mut u32 {
+    let offset = (address.device as u64) << 15 | (address.function as u64) << 12 | (offset & 0xFFC) as u64;
+    (base + offset).as_mut_ptr()
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    0x8000_0000
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset & 0xFC) as u32
+}
+
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    if let Some(base) = ecam_bus(address.bus) {
+        return unsafe { read_volatile(ecam_ptr(base, address, offset)) };
+    }
+    // the extended space past 256 bytes is only reachable through ECAM
+    if offset >= 0x100 {
+        return u32::MAX;
+    }
+
+    let _guard = LEGACY.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    }
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    if let Some(base) = ecam_bus(address.bus) {
+        unsafe { write_volatile(ecam_ptr(base, address, offset), value) };
+        return;
+    }
+    if offset >= 0x100 {
+        return;
+    }
+
+    let _guard = LEGACY.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA).write(value);
+    }
+}
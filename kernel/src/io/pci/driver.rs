@@ -0,0 +1,63 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{PciDevice, DEVICES};
+
+/// Which devices a driver wants
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceMatch {
+    Id { vendor: u16, device: u16 },
+    /// Every device of a vendor, e.g. virtio
+    Vendor(u16),
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl DeviceMatch {
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            DeviceMatch::Id { vendor, device: id } => device.vendor_id == vendor && device.device_id == id,
+            DeviceMatch::Vendor(vendor) => device.vendor_id == vendor,
+            DeviceMatch::Class { class, subclass, prog_if } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    /// Set up a matching device, returns false if the driver could not use it
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// Add a driver and probe it against every device without a driver yet
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+
+    // the device list is not locked while probing, drivers read it too
+    let candidates: Vec<PciDevice> = DEVICES.lock().iter()
+        .filter(|device| device.driver.is_none())
+        .filter(|device| driver.matches.iter().any(|m| m.matches(device)))
+        .cloned()
+        .collect();
+
+    for device in candidates {
+        log::debug!("pci {}: probing {}", device.address, driver.name);
+        if !(driver.probe)(&device) {
+            continue;
+        }
+        if let Some(entry) = DEVICES.lock().iter_mut().find(|entry| entry.address == device.address) {
+            entry.driver = Some(driver.name);
+        }
+    }
+}
+
+/// Names of all registered drivers
+pub fn drivers() -> Vec<&'static str> {
+    DRIVERS.lock().iter().map(|driver| driver.name).collect()
+}
@@ -0,0 +1,323 @@
+pub mod capability;
+pub mod config;
+pub mod driver;
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub use capability::{Capability, Msi, MsiX};
+pub use driver::{register_driver, DeviceMatch, PciDriver};
+
+/// Command register bits
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// Location of a PCI function in configuration space (segment 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress { bus, device, function }
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config::read_u32(*self, offset)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config::write_u32(*self, offset, value)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(0x00)
+    }
+
+    pub fn header_type(&self) -> u8 {
+        self.read_u8(0x0E)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(0x04)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.write_u16(0x04, command)
+    }
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u32 },
+}
+
+impl Bar {
+    pub fn memory_address(&self) -> Option<u64> {
+        match self {
+            Bar::Memory { address, .. } => Some(*address),
+            Bar::Io { .. } => None,
+        }
+    }
+}
+
+/// Everything the scan learned about one function
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /// Name of the driver that took the device
+    pub driver: Option<&'static str>,
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> PciDevice {
+        let id = address.read_u32(0x00);
+        let class = address.read_u32(0x08);
+        let interrupt = address.read_u32(0x3C);
+        let header_type = address.header_type() & 0x7F;
+
+        PciDevice {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            bars: read_bars(address, header_type),
+            capabilities: capability::read_list(address),
+            driver: None,
+        }
+    }
+
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// Config space offset of the first capability with `id`
+    pub fn capability(&self, id: u8) -> Option<u16> {
+        self.capabilities.iter().find(|cap| cap.id == id).map(|cap| cap.offset)
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        self.capability(capability::CAP_MSI).map(|offset| Msi::new(self.address, offset))
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        self.capability(capability::CAP_MSIX).and_then(|offset| MsiX::new(self, offset))
+    }
+
+    /// Let the device decode memory and I/O accesses and do DMA
+    pub fn enable_bus_master(&self) {
+        let command = self.address.command();
+        self.address.set_command(command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+    }
+}
+
+/// Decode and size the base address registers. Sizing writes all ones and
+/// reads back which bits stick, so decoding is off while it happens.
+fn read_bars(address: PciAddress, header_type: u8) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let count = match header_type {
+        0 => 6,
+        1 => 2,
+        _ => 0,
+    };
+
+    let command = address.command();
+    address.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let mut index = 0;
+    while index < count {
+        let offset = 0x10 + index as u16 * 4;
+        let value = address.read_u32(offset);
+        address.write_u32(offset, u32::MAX);
+        let mask = address.read_u32(offset);
+        address.write_u32(offset, value);
+
+        if value & 1 == 1 {
+            let size = (!(mask & 0xFFFC) & 0xFFFF) + 1;
+            if mask != 0 {
+                bars[index] = Some(Bar::Io { port: (value & 0xFFFC) as u16, size });
+            }
+            index += 1;
+            continue;
+        }
+
+        let prefetchable = value & 0x08 != 0;
+        let is_64 = (value >> 1) & 0x3 == 0x2;
+        let mut base = (value & 0xFFFF_FFF0) as u64;
+        let mut size_mask = (mask & 0xFFFF_FFF0) as u64 | 0xFFFF_FFFF_0000_0000;
+
+        if is_64 && index + 1 < count {
+            let high_offset = offset + 4;
+            let high = address.read_u32(high_offset);
+            address.write_u32(high_offset, u32::MAX);
+            let high_mask = address.read_u32(high_offset);
+            address.write_u32(high_offset, high);
+
+            base |= (high as u64) << 32;
+            size_mask = (mask & 0xFFFF_FFF0) as u64 | (high_mask as u64) << 32;
+        }
+
+        if mask != 0 {
+            bars[index] = Some(Bar::Memory {
+                address: base,
+                size: (!size_mask).wrapping_add(1),
+                prefetchable,
+            });
+        }
+        index += if is_64 { 2 } else { 1 };
+    }
+
+    address.set_command(command);
+    bars
+}
+
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+fn scan_bus(bus: u8, found: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let first = PciAddress::new(bus, device, 0);
+        if first.vendor_id() == 0xFFFF {
+            continue;
+        }
+        let functions = if first.header_type() & 0x80 != 0 { 8 } else { 1 };
+
+        for function in 0..functions {
+            let address = PciAddress::new(bus, device, function);
+            if address.vendor_id() == 0xFFFF {
+                continue;
+            }
+            let pci_device = PciDevice::read(address);
+
+            // continue behind PCI-to-PCI bridges
+            let bridge = pci_device.class == CLASS_BRIDGE && pci_device.subclass == SUBCLASS_PCI_BRIDGE;
+            found.push(pci_device);
+            if bridge {
+                let secondary = address.read_u8(0x19);
+                if secondary > bus {
+                    scan_bus(secondary, found);
+                }
+            }
+        }
+    }
+}
+
+/// Every function found by the scan
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+/// The first function with the given class, subclass and programming interface
+pub fn find_class(class: u8, subclass: u8, prog_if: u8) -> Option<PciDevice> {
+    DEVICES.lock().iter()
+        .find(|device| (device.class, device.subclass, device.prog_if) == (class, subclass, prog_if))
+        .cloned()
+}
+
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    DEVICES.lock().iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+        .cloned()
+}
+
+/// A short description of a class code
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVM controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
+
+/// Enumerate every bus reachable from the host bridge. Uses ECAM when the
+/// ACPI MCFG table describes it, the legacy ports otherwise.
+pub fn init() {
+    if let Some(bases) = crate::io::acpi::PCI_ECAM.get() {
+        config::init_ecam(bases.clone());
+    }
+
+    let mut found = Vec::new();
+    // a multi function host bridge has one host controller per bus
+    let host = PciAddress::new(0, 0, 0);
+    if host.header_type() & 0x80 == 0 {
+        scan_bus(0, &mut found);
+    } else {
+        for function in 0..8 {
+            if PciAddress::new(0, 0, function).vendor_id() != 0xFFFF {
+                scan_bus(function, &mut found);
+            }
+        }
+    }
+
+    for device in &found {
+        log::debug!(
+            "pci {} {:04x}:{:04x} {}",
+            device.address, device.vendor_id, device.device_id,
+            class_name(device.class, device.subclass),
+        );
+    }
+    log::info!("pci: found {} functions", found.len());
+    *DEVICES.lock() = found;
+}
@@ -1,8 +1,9 @@
 use core::fmt::{self, Write};
 
-use spin::Mutex;
 use spin::Lazy;
 
+use crate::sync::IrqSpinlock;
+
 pub struct SerialPort {
     port: uart_16550::SerialPort,
 }
@@ -25,19 +26,25 @@ impl fmt::Write for SerialPort {
     }
 }
 
-pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
+pub static SERIAL1: Lazy<IrqSpinlock<SerialPort>> = Lazy::new(|| {
     let serial_port = unsafe { SerialPort::init() };
-    Mutex::new(serial_port)
+    IrqSpinlock::new(serial_port)
 });
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// Print without taking `SERIAL1`, for reports from code that may be
+/// holding it or any other lock
+#[doc(hidden)]
+pub fn _print_unlocked(args: ::core::fmt::Arguments) {
+    let mut port = unsafe { uart_16550::SerialPort::new(0x3F8) };
+    let _ = port.write_fmt(args);
 }
 
 /// Prints to the host through the serial interface.
@@ -57,6 +64,14 @@ macro_rules! serial_println {
         concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Prints to the host through the serial port without taking its lock.
+#[macro_export]
+macro_rules! serial_print_unlocked {
+    ($($arg:tt)*) => {
+        $crate::serial::_print_unlocked(format_args!($($arg)*));
+    };
+}
+
 // /// Prints to the host through the serial interface.
 // #[macro_export]
 // macro_rules! print {
@@ -0,0 +1,110 @@
+//! Glyph Bitmap Distribution Format, the text format X11 bitmap fonts are
+//! shipped in. Every glyph is placed into a cell the size of the font
+//! bounding box, on the font's baseline.
+
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BitmapFont, Config, FontError, MAX_CELL_SIZE};
+
+pub fn is_bdf(data: &[u8]) -> bool {
+    data.starts_with(b"STARTFONT")
+}
+
+/// A box as `BBX` and `FONTBOUNDINGBOX` give it, the offsets are from the
+/// origin on the baseline to the lower left corner
+#[derive(Debug, Clone, Copy, Default)]
+struct Bounds {
+    width: usize,
+    height: usize,
+    x: isize,
+    y: isize,
+}
+
+pub fn parse(name: &str, data: &[u8]) -> Result<BitmapFont, FontError> {
+    let text = core::str::from_utf8(data).map_err(|_| FontError::Corrupted)?;
+    let mut lines = text.lines().map(str::trim);
+
+    let mut name = String::from(name);
+    let mut cell = None;
+    let mut glyphs = Vec::new();
+    let mut unicode = BTreeMap::new();
+    let mut count = 0;
+
+    let mut encoding = None;
+    let mut bounds = Bounds::default();
+    while let Some(line) = lines.next() {
+        let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "FONT" => name = String::from(args.trim()),
+            "FONTBOUNDINGBOX" => {
+                let bounds = bounding_box(args)?;
+                if bounds.width == 0 || bounds.height == 0 {
+                    return Err(FontError::Corrupted);
+                }
+                cell = Some(bounds);
+            }
+            "STARTCHAR" => {
+                encoding = None;
+                bounds = Bounds::default();
+            }
+            // -1 marks glyphs without a standard encoding
+            "ENCODING" => {
+                let code = args.split_whitespace().next().and_then(|code| code.parse::<u32>().ok());
+                encoding = code.and_then(char::from_u32);
+            }
+            "BBX" => bounds = bounding_box(args)?,
+            "BITMAP" => {
+                let cell = cell.ok_or(FontError::Corrupted)?;
+                let config = Config { width: cell.width, height: cell.height, chars: 0 };
+                let mut glyph = vec![0; config.glyph_size()];
+                // rows from the top of the cell down to the baseline, and
+                // columns from its left edge
+                let top = (cell.height as isize)
+                    .checked_add(cell.y)
+                    .and_then(|top| top.checked_sub(bounds.height as isize))
+                    .and_then(|top| top.checked_sub(bounds.y))
+                    .ok_or(FontError::Corrupted)?;
+                let left = bounds.x.checked_sub(cell.x).ok_or(FontError::Corrupted)?;
+                for row in 0..bounds.height {
+                    let line = lines.next().ok_or(FontError::Corrupted)?;
+                    for x in 0..bounds.width {
+                        let digit = line.as_bytes().get(x / 4).copied().ok_or(FontError::Corrupted)?;
+                        let nibble = (digit as char).to_digit(16).ok_or(FontError::Corrupted)?;
+                        // far off the cell, saturating still leaves it outside
+                        let (cx, cy) = (left.saturating_add(x as isize), top.saturating_add(row as isize));
+                        let inside = (0..cell.width as isize).contains(&cx) && (0..cell.height as isize).contains(&cy);
+                        if inside && nibble & 0x8 >> (x % 4) != 0 {
+                            let (cx, cy) = (cx as usize, cy as usize);
+                            glyph[cy * config.stride() + cx / 8] |= 0x80 >> (cx % 8);
+                        }
+                    }
+                }
+                if let Some(c) = encoding {
+                    unicode.entry(c).or_insert(count);
+                    glyphs.extend_from_slice(&glyph);
+                    count += 1;
+                }
+            }
+            "ENDFONT" => break,
+            _ => {}
+        }
+    }
+
+    let cell = cell.ok_or(FontError::Corrupted)?;
+    let config = Config { width: cell.width, height: cell.height, chars: count };
+    Ok(BitmapFont::new(name, config, Cow::Owned(glyphs), Some(unicode)))
+}
+
+fn bounding_box(args: &str) -> Result<Bounds, FontError> {
+    let mut values = args.split_whitespace().map(|value| value.parse::<isize>().map_err(|_| FontError::Corrupted));
+    let mut next = || values.next().unwrap_or(Err(FontError::Corrupted));
+    let (width, height, x, y) = (next()?, next()?, next()?, next()?);
+    if !(0..=MAX_CELL_SIZE as isize).contains(&width) || !(0..=MAX_CELL_SIZE as isize).contains(&height) {
+        return Err(FontError::Corrupted);
+    }
+    Ok(Bounds { width: width as usize, height: height as usize, x, y })
+}
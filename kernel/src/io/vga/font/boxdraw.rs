@@ -0,0 +1,251 @@
+//! Box drawing (U+2500..U+257F) and block elements (U+2580..U+259F), drawn
+//! for the cell instead of stored so the lines meet the next cell's in any
+//! font
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+const LIGHT: u8 = 1;
+const HEAVY: u8 = 2;
+const DOUBLE: u8 = 3;
+
+/// The lines leaving the middle of U+2500..U+257F, a nibble each for up,
+/// right, down and left. Arcs are drawn as corners, the diagonals are not
+/// in here.
+const ARMS: [u16; 0x80] = [
+    0x0101, 0x0202, 0x1010, 0x2020, 0x0101, 0x0202, 0x1010, 0x2020, // ─━│┃┄┅┆┇
+    0x0101, 0x0202, 0x1010, 0x2020, 0x0110, 0x0210, 0x0120, 0x0220, // ┈┉┊┋┌┍┎┏
+    0x0011, 0x0012, 0x0021, 0x0022, 0x1100, 0x1200, 0x2100, 0x2200, // ┐┑┒┓└┕┖┗
+    0x1001, 0x1002, 0x2001, 0x2002, 0x1110, 0x1210, 0x2110, 0x1120, // ┘┙┚┛├┝┞┟
+    0x2120, 0x2210, 0x1220, 0x2220, 0x1011, 0x1012, 0x2011, 0x1021, // ┠┡┢┣┤┥┦┧
+    0x2021, 0x2012, 0x1022, 0x2022, 0x0111, 0x0112, 0x0211, 0x0212, // ┨┩┪┫┬┭┮┯
+    0x0121, 0x0122, 0x0221, 0x0222, 0x1101, 0x1102, 0x1201, 0x1202, // ┰┱┲┳┴┵┶┷
+    0x2101, 0x2102, 0x2201, 0x2202, 0x1111, 0x1112, 0x1211, 0x1212, // ┸┹┺┻┼┽┾┿
+    0x2111, 0x1121, 0x2121, 0x2112, 0x2211, 0x1122, 0x1221, 0x2212, // ╀╁╂╃╄╅╆╇
+    0x1222, 0x2122, 0x2221, 0x2222, 0x0101, 0x0202, 0x1010, 0x2020, // ╈╉╊╋╌╍╎╏
+    0x0303, 0x3030, 0x0310, 0x0130, 0x0330, 0x0013, 0x0031, 0x0033, // ═║╒╓╔╕╖╗
+    0x1300, 0x3100, 0x3300, 0x1003, 0x3001, 0x3003, 0x1310, 0x3130, // ╘╙╚╛╜╝╞╟
+    0x3330, 0x1013, 0x3031, 0x3033, 0x0313, 0x0131, 0x0333, 0x1303, // ╠╡╢╣╤╥╦╧
+    0x3101, 0x3303, 0x1313, 0x3131, 0x3333, 0x0110, 0x0011, 0x1001, // ╨╩╪╫╬╭╮╯
+    0x1100, 0x0000, 0x0000, 0x0000, 0x0001, 0x1000, 0x0100, 0x0010, // ╰╱╲╳╴╵╶╷
+    0x0002, 0x2000, 0x0200, 0x0020, 0x0201, 0x1020, 0x0102, 0x2010, // ╸╹╺╻╼╽╾╿
+];
+
+/// Draw `c` for a `width` by `height` cell, rows padded to whole bytes
+pub fn glyph(c: char, width: usize, height: usize) -> Option<Vec<u8>> {
+    if width < 3 || height < 3 {
+        return None;
+    }
+    let cell = Cell::new(width, height);
+    let cell = match c {
+        '\u{2504}' | '\u{2505}' => dashed(lines(cell, c), 3, true),
+        '\u{2506}' | '\u{2507}' => dashed(lines(cell, c), 3, false),
+        '\u{2508}' | '\u{2509}' => dashed(lines(cell, c), 4, true),
+        '\u{250a}' | '\u{250b}' => dashed(lines(cell, c), 4, false),
+        '\u{254c}' | '\u{254d}' => dashed(lines(cell, c), 2, true),
+        '\u{254e}' | '\u{254f}' => dashed(lines(cell, c), 2, false),
+        '\u{2571}'..='\u{2573}' => diagonal(cell, c),
+        '\u{2500}'..='\u{257f}' => lines(cell, c),
+        '\u{2580}'..='\u{259f}' => block(cell, c),
+        _ => return None,
+    };
+    Some(cell.data)
+}
+
+/// A glyph being drawn
+struct Cell {
+    width: usize,
+    height: usize,
+    /// Bytes in a row
+    stride: usize,
+    data: Vec<u8>,
+}
+
+impl Cell {
+    fn new(width: usize, height: usize) -> Self {
+        let stride = (width + 7) / 8;
+        Cell { width, height, stride, data: vec![0; stride * height] }
+    }
+
+    fn set(&mut self, x: Range<usize>, y: Range<usize>, on: bool) {
+        for y in y.start..y.end.min(self.height) {
+            for x in x.start..x.end.min(self.width) {
+                let byte = &mut self.data[y * self.stride + x / 8];
+                match on {
+                    true => *byte |= 0x80 >> (x % 8),
+                    false => *byte &= !(0x80 >> (x % 8)),
+                }
+            }
+        }
+    }
+
+    fn fill(&mut self, x: Range<usize>, y: Range<usize>) {
+        self.set(x, y, true);
+    }
+
+    fn cut(&mut self, x: Range<usize>, y: Range<usize>) {
+        self.set(x, y, false);
+    }
+
+    /// Columns covered by `eighths` eighths of the cell from the left
+    fn columns(&self, eighths: usize) -> usize {
+        (eighths * self.width + 4) / 8
+    }
+
+    /// Rows covered by `eighths` eighths of the cell from the bottom
+    fn rows(&self, eighths: usize) -> usize {
+        (eighths * self.height + 4) / 8
+    }
+}
+
+/// 1 for lines three pixels wide, 0 for light ones
+fn half(line: u8) -> usize {
+    match line {
+        HEAVY | DOUBLE => 1,
+        _ => 0,
+    }
+}
+
+/// How far a light arm reaches past the middle to meet a double line
+/// crossing it. It stops at the near line of a double line going straight
+/// through, and runs on to the far line where the double line turns.
+fn reach(first: u8, second: u8) -> isize {
+    match (first == DOUBLE, second == DOUBLE) {
+        (true, true) => -1,
+        (false, false) => 0,
+        _ => 1,
+    }
+}
+
+fn lines(mut cell: Cell, c: char) -> Cell {
+    let arms = ARMS[c as usize - 0x2500];
+    let [up, right, down, left] = [12, 8, 4, 0].map(|shift| ((arms >> shift) & 0xf) as u8);
+    let (width, height) = (cell.width, cell.height);
+    // the column of vertical lines and the row of horizontal ones
+    let (x, y) = (width / 2, height / 2);
+
+    // heavy and double arms are three pixels wide and cover the middle of
+    // a wide line crossing them, the middle of a double line is cut out
+    let across = half(up).max(half(down));
+    let along = half(left).max(half(right));
+    if right >= HEAVY {
+        cell.fill(x - across..width, y - 1..y + 2);
+    }
+    if left >= HEAVY {
+        cell.fill(0..x + across + 1, y - 1..y + 2);
+    }
+    if up >= HEAVY {
+        cell.fill(x - 1..x + 2, 0..y + along + 1);
+    }
+    if down >= HEAVY {
+        cell.fill(x - 1..x + 2, y - along..height);
+    }
+    if right == DOUBLE {
+        cell.cut(x..width, y..y + 1);
+    }
+    if left == DOUBLE {
+        cell.cut(0..x + 1, y..y + 1);
+    }
+    if up == DOUBLE {
+        cell.cut(x..x + 1, 0..y + 1);
+    }
+    if down == DOUBLE {
+        cell.cut(x..x + 1, y..height);
+    }
+
+    let reach_x = reach(up, down);
+    if right == LIGHT {
+        cell.fill((x as isize - reach_x) as usize..width, y..y + 1);
+    }
+    if left == LIGHT {
+        cell.fill(0..(x as isize + reach_x) as usize + 1, y..y + 1);
+    }
+    let reach_y = reach(left, right);
+    if up == LIGHT {
+        cell.fill(x..x + 1, 0..(y as isize + reach_y) as usize + 1);
+    }
+    if down == LIGHT {
+        cell.fill(x..x + 1, (y as isize - reach_y) as usize..height);
+    }
+    cell
+}
+
+/// Cut gaps into a straight line so `dashes` dashes are left
+fn dashed(mut cell: Cell, dashes: usize, horizontal: bool) -> Cell {
+    let (width, height) = (cell.width, cell.height);
+    let length = if horizontal { width } else { height };
+    for at in (0..length).filter(|at| at * dashes * 2 / length % 2 == 1) {
+        match horizontal {
+            true => cell.cut(at..at + 1, 0..height),
+            false => cell.cut(0..width, at..at + 1),
+        }
+    }
+    cell
+}
+
+fn diagonal(mut cell: Cell, c: char) -> Cell {
+    let (width, height) = (cell.width, cell.height);
+    for y in 0..height {
+        let x = y * (width - 1) / (height - 1);
+        if c != '\u{2571}' {
+            cell.fill(x..x + 1, y..y + 1);
+        }
+        if c != '\u{2572}' {
+            cell.fill(width - 1 - x..width - x, y..y + 1);
+        }
+    }
+    cell
+}
+
+fn block(mut cell: Cell, c: char) -> Cell {
+    let (width, height) = (cell.width, cell.height);
+    let (mid_x, mid_y) = (cell.columns(4), height - cell.rows(4));
+    match c as usize - 0x2580 {
+        // ▀
+        0x00 => cell.fill(0..width, 0..mid_y),
+        // ▁ to █
+        eighths @ 0x01..=0x08 => cell.fill(0..width, height - cell.rows(eighths)..height),
+        // ▉ to ▏
+        left @ 0x09..=0x0f => cell.fill(0..cell.columns(0x10 - left), 0..height),
+        // ▐
+        0x10 => cell.fill(mid_x..width, 0..height),
+        // ░ ▒ ▓ as a pattern of a quarter, half and three quarters
+        shade @ 0x11..=0x13 => {
+            for y in 0..height {
+                for x in 0..width {
+                    let on = match shade {
+                        0x11 => x % 2 == 0 && y % 2 == 0,
+                        0x12 => (x + y) % 2 == 0,
+                        _ => x % 2 == 0 || y % 2 == 0,
+                    };
+                    if on {
+                        cell.fill(x..x + 1, y..y + 1);
+                    }
+                }
+            }
+        }
+        // ▔
+        0x14 => cell.fill(0..width, 0..cell.rows(1)),
+        // ▕
+        0x15 => cell.fill(width - cell.columns(1)..width, 0..height),
+        // ▖ to ▟, a bit each for the upper left, upper right, lower left
+        // and lower right quadrant
+        quadrant => {
+            const QUADRANTS: [u8; 10] = [4, 8, 1, 13, 9, 7, 11, 2, 6, 14];
+            let quadrants = QUADRANTS[quadrant - 0x16];
+            let parts = [
+                (0..mid_x, 0..mid_y),
+                (mid_x..width, 0..mid_y),
+                (0..mid_x, mid_y..height),
+                (mid_x..width, mid_y..height),
+            ];
+            for (bit, (x, y)) in parts.into_iter().enumerate() {
+                if quadrants & 1 << bit != 0 {
+                    cell.fill(x, y);
+                }
+            }
+        }
+    }
+    cell
+}
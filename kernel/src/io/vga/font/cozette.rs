@@ -1,6 +1,6 @@
 use crate::io::vga::font::Config;
 
-pub const DATA: [[u8; 13]; 512] = [
+pub static DATA: [[u8; 13]; 512] = [
 //   0 $00 'C0000'
 //	width 6, bbx 0, bby -3, bbw 6, bbh 13
 	[0x00,0x00,0x00,0x00,0x44,0x38,0x28,0x28,0x38,0x44,0x00,0x00,0x00],
@@ -1544,3 +1544,41 @@ pub const CONFIG: Config = Config{
 	height: 13,
 	chars: 512,
 };
+
+/// Glyphs past ASCII are in a mostly code page 437 order with gaps and
+/// some unused slots, these are the ones that can be looked up by `char`.
+/// Sorted for the binary search.
+const UNICODE: [(char, u16); 147] = [
+    ('\u{a0}', 0x20), ('¡', 0xAD), ('¢', 0x9B), ('£', 0x9C), ('¤', 0x00), ('¥', 0x9D), ('¦', 0x01),
+    ('§', 0x15), ('¨', 0x02), ('©', 0x03), ('ª', 0xA6), ('«', 0xAE), ('¬', 0xAA), ('\u{ad}', 0x2D),
+    ('®', 0x04), ('¯', 0x05), ('°', 0xF8), ('±', 0xF1), ('²', 0xFD), ('³', 0x06), ('´', 0x08),
+    ('µ', 0xE6), ('¶', 0x14), ('¸', 0x0A), ('¹', 0x0B), ('º', 0xA7), ('»', 0xAF), ('¼', 0xAC),
+    ('½', 0xAB), ('¿', 0xA8), ('À', 0x0D), ('Á', 0x0E), ('Â', 0x0F), ('Ã', 0x10), ('Ä', 0x8E),
+    ('Å', 0x8F), ('Æ', 0x92), ('Ç', 0x80), ('È', 0x11), ('É', 0x90), ('Ê', 0x13), ('Ë', 0x16),
+    ('Ì', 0x17), ('Í', 0x1C), ('Î', 0x1E), ('Ï', 0x1F), ('Ñ', 0xA5), ('Ò', 0x9E), ('Ó', 0x9F),
+    ('Ô', 0xA9), ('Õ', 0xB5), ('Ö', 0x99), ('×', 0xB6), ('Ø', 0xB7), ('Ù', 0xB8), ('Ú', 0xB9),
+    ('Û', 0xBA), ('Ü', 0x9A), ('Ý', 0xBB), ('Þ', 0xBC), ('ß', 0xE1), ('à', 0x85), ('á', 0xA0),
+    ('â', 0x83), ('ã', 0xBD), ('ä', 0x84), ('å', 0x86), ('æ', 0x91), ('ç', 0x87), ('è', 0x8A),
+    ('é', 0x82), ('ê', 0x88), ('ë', 0x89), ('ì', 0x8D), ('í', 0xA1), ('î', 0x8C), ('ï', 0x8B),
+    ('ð', 0xBE), ('ñ', 0xA4), ('ò', 0x95), ('ó', 0xA2), ('ô', 0x93), ('õ', 0xC6), ('ö', 0x94),
+    ('÷', 0xF6), ('ø', 0xC7), ('ù', 0x97), ('ú', 0xA3), ('û', 0x96), ('ü', 0x81), ('ý', 0xC8),
+    ('þ', 0xC9), ('ÿ', 0x98), ('Α', 0x41), ('Β', 0x42), ('Γ', 0xE2), ('Ε', 0x45), ('Ζ', 0x5A),
+    ('Η', 0x48), ('Θ', 0xE9), ('Ι', 0x49), ('Κ', 0x4B), ('Λ', 0xCF), ('Μ', 0x4D), ('Ν', 0x4E),
+    ('Ξ', 0xD0), ('Ο', 0x4F), ('Π', 0xD1), ('Ρ', 0x50), ('Σ', 0xE4), ('Τ', 0x54), ('Υ', 0x59),
+    ('Φ', 0xE8), ('Χ', 0x58), ('Ψ', 0xD3), ('Ω', 0xEA), ('α', 0xE0), ('β', 0xD7), ('δ', 0xEB),
+    ('ε', 0xEE), ('ζ', 0xEF), ('η', 0xF4), ('θ', 0xF5), ('ι', 0xF7), ('κ', 0xFC), ('λ', 0xFF),
+    ('μ', 0xE6), ('ν', 0x76), ('ο', 0x6F), ('π', 0xE3), ('σ', 0xE5), ('τ', 0xE7), ('φ', 0xED),
+    ('†', 0x115), ('‡', 0x116), ('…', 0x11A), ('←', 0x1B), ('↑', 0x18), ('→', 0x1A), ('↓', 0x19),
+    ('↔', 0x1D), ('√', 0xFB), ('∞', 0xEC), ('≡', 0xF0), ('≤', 0xF3), ('≥', 0xF2), ('■', 0xFE),
+];
+
+/// Index of the glyph for `c` in `DATA`
+pub fn index(c: char) -> Option<usize> {
+    match c {
+        ' '..='~' => Some(c as usize),
+        _ => UNICODE
+            .binary_search_by_key(&c, |&(key, _)| key)
+            .ok()
+            .map(|found| UNICODE[found].1 as usize),
+    }
+}
@@ -1,10 +1,23 @@
+pub mod bdf;
+pub mod boxdraw;
 pub mod cozette;
+pub mod noto;
+pub mod psf;
+pub mod supplement;
 
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
 use font_constants::BACKUP_CHAR;
 
+use crate::fs::{self, FsError};
+
 const LINE_SPACING: usize = 2;
 const LETTER_SPACING: usize = 0;
 const BORDER_PADDING: usize = 1;
@@ -17,8 +30,188 @@ mod font_constants {
     pub const FONT_WEIGHT: FontWeight = FontWeight::Regular;
 }
 
+/// Largest cell width or height a font file may have, bigger ones are
+/// treated as corrupted rather than allocated
+pub const MAX_CELL_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Config {
 	pub width: usize,
 	pub height: usize,
 	pub chars: usize,
-}
\ No newline at end of file
+}
+
+impl Config {
+    /// Bytes in a row of a glyph
+    pub const fn stride(&self) -> usize {
+        (self.width + 7) / 8
+    }
+
+    /// Bytes in a glyph
+    pub const fn glyph_size(&self) -> usize {
+        self.stride() * self.height
+    }
+}
+
+/// A bitmap font with the same cell size for every glyph. A glyph is
+/// `height` rows of `stride()` bytes, the leftmost pixel of a row in the
+/// top bit of its first byte.
+pub trait Font: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Cell size and number of glyphs
+    fn metrics(&self) -> Config;
+
+    /// The font's own glyph for `c`
+    fn glyph(&self, c: char) -> Option<Cow<'_, [u8]>>;
+
+    /// How much of each pixel of the glyph for `c` is covered, a byte per
+    /// pixel from 0 to 255. Fonts that have it are drawn anti-aliased.
+    fn coverage(&self, _c: char) -> Option<Cow<'_, [u8]>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    Fs(FsError),
+    /// Neither PSF nor BDF
+    UnknownFormat,
+    Corrupted,
+    /// The cells are too big for the screen
+    Unsupported,
+}
+
+/// Rows of a cozette glyph
+pub const GLYPH_HEIGHT: usize = 13;
+
+/// A cozette glyph, pixel `x` of a row is the bit `0x80 >> x`
+pub type Glyph = [u8; GLYPH_HEIGHT];
+
+/// The font built into the kernel, with a few glyphs drawn in its style
+/// where it has no slot
+pub struct Cozette;
+
+impl Font for Cozette {
+    fn name(&self) -> &str {
+        "cozette"
+    }
+
+    fn metrics(&self) -> Config {
+        cozette::CONFIG
+    }
+
+    fn glyph(&self, c: char) -> Option<Cow<'_, [u8]>> {
+        match cozette::index(c) {
+            Some(index) => Some(Cow::Borrowed(&cozette::DATA[index][..])),
+            None => supplement::glyph(c).map(|glyph| Cow::Borrowed(&glyph[..])),
+        }
+    }
+}
+
+/// A font of glyphs packed one after another, as read from PSF or BDF
+pub struct BitmapFont {
+    name: String,
+    config: Config,
+    data: Cow<'static, [u8]>,
+    /// Glyph index of each character, without one the index is the
+    /// codepoint
+    unicode: Option<BTreeMap<char, usize>>,
+}
+
+impl BitmapFont {
+    /// `data` holds `config.chars` glyphs of `config.glyph_size()` bytes
+    pub fn new(name: String, config: Config, data: Cow<'static, [u8]>, unicode: Option<BTreeMap<char, usize>>) -> Self {
+        assert!(data.len() >= config.chars * config.glyph_size(), "font data is shorter than its glyphs");
+        BitmapFont { name, config, data, unicode }
+    }
+}
+
+impl Font for BitmapFont {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn metrics(&self) -> Config {
+        self.config
+    }
+
+    fn glyph(&self, c: char) -> Option<Cow<'_, [u8]>> {
+        let index = match &self.unicode {
+            Some(unicode) => *unicode.get(&c)?,
+            None => c as usize,
+        };
+        if index >= self.config.chars {
+            return None;
+        }
+        let size = self.config.glyph_size();
+        Some(Cow::Borrowed(&self.data[index * size..(index + 1) * size]))
+    }
+}
+
+/// The glyph for `c` in `font`. Box drawing the font lacks is drawn for
+/// its cell and anything else comes from noto, with '\u{FFFD}' for what
+/// neither has.
+pub fn lookup(font: &dyn Font, c: char) -> Cow<'_, [u8]> {
+    let config = font.metrics();
+    font.glyph(c)
+        .or_else(|| boxdraw::glyph(c, config.width, config.height).map(Cow::Owned))
+        .or_else(|| noto(c, config).map(Cow::Owned))
+        .or_else(|| font.glyph(BACKUP_CHAR))
+        .or_else(|| noto(BACKUP_CHAR, config).map(Cow::Owned))
+        .unwrap_or_else(|| Cow::Owned(vec![0; config.glyph_size()]))
+}
+
+fn noto(c: char, config: Config) -> Option<Vec<u8>> {
+    get_raster(c, font_constants::FONT_WEIGHT, font_constants::CHAR_RASTER_HEIGHT)
+        .map(|raster| squeeze(raster, config))
+}
+
+/// Fit a noto raster into the cell, a pixel is set where the raster is at
+/// least half covered
+fn squeeze(raster: RasterizedChar, config: Config) -> Vec<u8> {
+    let mut glyph = vec![0; config.glyph_size()];
+    let (width, height) = (raster.width(), raster.height());
+    for (y, row) in glyph.chunks_mut(config.stride()).enumerate() {
+        let line = raster.raster()[y * height / config.height];
+        for x in 0..config.width {
+            if line[x * width / config.width] >= 0x80 {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+    glyph
+}
+
+/// A font compiled into the kernel, by name
+pub fn builtin(name: &str) -> Option<Arc<dyn Font>> {
+    match name {
+        "cozette" => Some(Arc::new(Cozette)),
+        _ => Some(Arc::new(noto::Noto::named(name)?)),
+    }
+}
+
+/// Names `builtin` knows
+pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+    core::iter::once("cozette").chain(noto::Noto::names())
+}
+
+/// Read a PSF or BDF font file
+pub fn load(path: &str) -> Result<Arc<dyn Font>, FontError> {
+    let data = fs::read_to_end(path).map_err(FontError::Fs)?;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    parse(name, Cow::Owned(data))
+}
+
+/// Read a PSF or BDF font from its bytes, embedded ones are used in place
+/// instead of copied
+pub fn parse(name: &str, data: Cow<'static, [u8]>) -> Result<Arc<dyn Font>, FontError> {
+    let font = if psf::is_psf(&data) {
+        psf::parse(name, data)?
+    } else if bdf::is_bdf(&data) {
+        bdf::parse(name, &data)?
+    } else {
+        return Err(FontError::UnknownFormat);
+    };
+    Ok(Arc::new(font))
+}
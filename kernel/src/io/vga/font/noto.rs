@@ -0,0 +1,76 @@
+//! Noto Sans Mono from the rasters compiled into the kernel. The rasters
+//! are grayscale, so besides the 1-bit glyphs every font has it gives the
+//! coverage of each pixel for anti-aliased drawing.
+
+use alloc::borrow::Cow;
+use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar};
+
+use super::{squeeze, Config, Font};
+
+/// The weights and sizes there are rasters for, by name
+const STYLES: [(&str, FontWeight, RasterHeight); 8] = [
+    ("noto", FontWeight::Regular, RasterHeight::Size16),
+    ("noto-20", FontWeight::Regular, RasterHeight::Size20),
+    ("noto-24", FontWeight::Regular, RasterHeight::Size24),
+    ("noto-32", FontWeight::Regular, RasterHeight::Size32),
+    ("noto-bold", FontWeight::Bold, RasterHeight::Size16),
+    ("noto-bold-20", FontWeight::Bold, RasterHeight::Size20),
+    ("noto-bold-24", FontWeight::Bold, RasterHeight::Size24),
+    ("noto-bold-32", FontWeight::Bold, RasterHeight::Size32),
+];
+
+pub struct Noto {
+    name: &'static str,
+    weight: FontWeight,
+    size: RasterHeight,
+}
+
+impl Noto {
+    /// The style called `name`, like "noto-bold-24"
+    pub fn named(name: &str) -> Option<Self> {
+        STYLES
+            .iter()
+            .find(|(style, _, _)| *style == name)
+            .map(|&(name, weight, size)| Noto { name, weight, size })
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        STYLES.iter().map(|(name, _, _)| *name)
+    }
+
+    fn raster(&self, c: char) -> Option<RasterizedChar> {
+        get_raster(c, self.weight, self.size)
+    }
+}
+
+impl Default for Noto {
+    fn default() -> Self {
+        Noto { name: STYLES[0].0, weight: STYLES[0].1, size: STYLES[0].2 }
+    }
+}
+
+impl Font for Noto {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn metrics(&self) -> Config {
+        let height = match self.size {
+            RasterHeight::Size16 => 16,
+            RasterHeight::Size20 => 20,
+            RasterHeight::Size24 => 24,
+            RasterHeight::Size32 => 32,
+        };
+        // there is no glyph table to count
+        Config { width: get_raster_width(self.weight, self.size), height, chars: 0 }
+    }
+
+    fn glyph(&self, c: char) -> Option<Cow<'_, [u8]>> {
+        self.raster(c).map(|raster| Cow::Owned(squeeze(raster, self.metrics())))
+    }
+
+    fn coverage(&self, c: char) -> Option<Cow<'_, [u8]>> {
+        let raster = self.raster(c)?;
+        Some(Cow::Owned(raster.raster().iter().flat_map(|row| row.iter().copied()).collect()))
+    }
+}
@@ -0,0 +1,123 @@
+//! PC Screen Font, the console fonts of Linux. Version 1 has 8 pixel wide
+//! glyphs and a UCS-2 table, version 2 any width and a UTF-8 table. See
+//! https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+use super::{BitmapFont, Config, FontError, MAX_CELL_SIZE};
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE512: u8 = 0x01;
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODESEQ: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_STARTSEQ: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_STARTSEQ: u8 = 0xfe;
+
+pub fn is_psf(data: &[u8]) -> bool {
+    data.starts_with(&PSF1_MAGIC) || data.starts_with(&PSF2_MAGIC)
+}
+
+pub fn parse(name: &str, data: Cow<'static, [u8]>) -> Result<BitmapFont, FontError> {
+    let (config, start, unicode) = match data.starts_with(&PSF2_MAGIC) {
+        true => header2(&data)?,
+        false => header1(&data)?,
+    };
+    let end = start + config.chars * config.glyph_size();
+    let glyphs = match data {
+        Cow::Borrowed(data) => Cow::Borrowed(&data[start..end]),
+        Cow::Owned(data) => Cow::Owned(data[start..end].into()),
+    };
+    Ok(BitmapFont::new(String::from(name), config, glyphs, unicode))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, FontError> {
+    let bytes = data.get(offset..offset + 4).ok_or(FontError::Corrupted)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+type Header = (Config, usize, Option<BTreeMap<char, usize>>);
+
+fn header1(data: &[u8]) -> Result<Header, FontError> {
+    let (mode, height) = match data {
+        [_, _, mode, height, ..] => (*mode, *height as usize),
+        _ => return Err(FontError::Corrupted),
+    };
+    let chars = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+    let config = Config { width: 8, height, chars };
+    let start = 4;
+    let end = start + chars * config.glyph_size();
+    if height == 0 || data.len() < end {
+        return Err(FontError::Corrupted);
+    }
+    if mode & (PSF1_MODEHASTAB | PSF1_MODESEQ) == 0 {
+        return Ok((config, start, None));
+    }
+
+    // a little endian UCS-2 list for each glyph, ended by 0xffff with
+    // sequences of combining characters after a 0xfffe
+    let mut unicode = BTreeMap::new();
+    let mut entries = data[end..].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+    for index in 0..chars {
+        let mut sequence = false;
+        for entry in entries.by_ref() {
+            match entry {
+                PSF1_SEPARATOR => break,
+                PSF1_STARTSEQ => sequence = true,
+                _ if sequence => {}
+                _ => {
+                    if let Some(c) = char::from_u32(entry as u32) {
+                        unicode.entry(c).or_insert(index);
+                    }
+                }
+            }
+        }
+    }
+    Ok((config, start, Some(unicode)))
+}
+
+fn header2(data: &[u8]) -> Result<Header, FontError> {
+    let header_size = u32_at(data, 8)? as usize;
+    let flags = u32_at(data, 12)?;
+    let chars = u32_at(data, 16)? as usize;
+    let glyph_size = u32_at(data, 20)? as usize;
+    let height = u32_at(data, 24)? as usize;
+    let width = u32_at(data, 28)? as usize;
+    let config = Config { width, height, chars };
+    if !(1..=MAX_CELL_SIZE).contains(&width) || !(1..=MAX_CELL_SIZE).contains(&height) {
+        return Err(FontError::Corrupted);
+    }
+    if glyph_size != config.glyph_size() {
+        return Err(FontError::Corrupted);
+    }
+    let end = chars
+        .checked_mul(glyph_size)
+        .and_then(|size| size.checked_add(header_size))
+        .filter(|&end| end <= data.len())
+        .ok_or(FontError::Corrupted)?;
+    if flags & PSF2_HAS_UNICODE_TABLE == 0 {
+        return Ok((config, header_size, None));
+    }
+
+    // UTF-8 for each glyph, ended by 0xff with sequences of combining
+    // characters after a 0xfe
+    let mut unicode = BTreeMap::new();
+    let mut table = &data[end..];
+    for index in 0..chars {
+        let length = table.iter().position(|&byte| byte == PSF2_SEPARATOR).unwrap_or(table.len());
+        let entry = &table[..length];
+        let single = entry.split(|&byte| byte == PSF2_STARTSEQ).next().unwrap_or(&[]);
+        let single = core::str::from_utf8(single).map_err(|_| FontError::Corrupted)?;
+        for c in single.chars() {
+            unicode.entry(c).or_insert(index);
+        }
+        table = table.get(length + 1..).unwrap_or(&[]);
+    }
+    Ok((config, header_size, Some(unicode)))
+}
@@ -0,0 +1,39 @@
+//! Glyphs cozette has no slot for, drawn in its style: 6 pixels wide from
+//! x 1, capitals from row 3, the baseline on row 9.
+
+use super::Glyph;
+
+/// Sorted for the binary search
+static GLYPHS: [(char, Glyph); 12] = [
+    // U+00B7 MIDDLE DOT
+    ('·', [0x00,0x00,0x00,0x00,0x00,0x00,0x10,0x00,0x00,0x00,0x00,0x00,0x00]),
+    // U+00BE VULGAR FRACTION THREE QUARTERS
+    ('¾', [0x00,0xC4,0x28,0x48,0x30,0xD0,0x24,0x2C,0x5C,0x44,0x00,0x00,0x00]),
+    // U+00D0 LATIN CAPITAL LETTER ETH
+    ('Ð', [0x00,0x00,0x00,0x78,0x44,0x44,0xE4,0x44,0x44,0x78,0x00,0x00,0x00]),
+    // U+0394 GREEK CAPITAL LETTER DELTA
+    ('Δ', [0x00,0x00,0x00,0x10,0x10,0x28,0x28,0x44,0x44,0x7C,0x00,0x00,0x00]),
+    // U+03B3 GREEK SMALL LETTER GAMMA
+    ('γ', [0x00,0x00,0x00,0x00,0x44,0x44,0x28,0x28,0x10,0x10,0x28,0x10,0x00]),
+    // U+03BE GREEK SMALL LETTER XI
+    ('ξ', [0x00,0x00,0x3C,0x40,0x38,0x40,0x40,0x40,0x3C,0x04,0x08,0x00,0x00]),
+    // U+03C1 GREEK SMALL LETTER RHO
+    ('ρ', [0x00,0x00,0x00,0x00,0x38,0x44,0x44,0x44,0x44,0x78,0x40,0x40,0x00]),
+    // U+03C2 GREEK SMALL LETTER FINAL SIGMA
+    ('ς', [0x00,0x00,0x00,0x00,0x3C,0x40,0x40,0x40,0x38,0x04,0x08,0x00,0x00]),
+    // U+03C5 GREEK SMALL LETTER UPSILON
+    ('υ', [0x00,0x00,0x00,0x00,0x44,0x44,0x44,0x44,0x44,0x38,0x00,0x00,0x00]),
+    // U+03C7 GREEK SMALL LETTER CHI
+    ('χ', [0x00,0x00,0x00,0x00,0x44,0x44,0x28,0x10,0x10,0x28,0x44,0x44,0x00]),
+    // U+03C8 GREEK SMALL LETTER PSI
+    ('ψ', [0x00,0x00,0x10,0x10,0x54,0x54,0x54,0x54,0x38,0x10,0x10,0x00,0x00]),
+    // U+03C9 GREEK SMALL LETTER OMEGA
+    ('ω', [0x00,0x00,0x00,0x00,0x44,0x44,0x54,0x54,0x54,0x28,0x00,0x00,0x00]),
+];
+
+pub fn glyph(c: char) -> Option<&'static Glyph> {
+    GLYPHS
+        .binary_search_by_key(&c, |&(key, _)| key)
+        .ok()
+        .map(|found| &GLYPHS[found].1)
+}
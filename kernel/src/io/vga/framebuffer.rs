@@ -1,6 +1,5 @@
 use core::{fmt, ptr, slice};
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -13,9 +12,11 @@ use core::cmp::max;
 use bootloader_api::info::{FrameBufferInfo, PixelFormat, FrameBuffer, BootInfo};
 
 use crate::io::vga;
+use crate::io::vga::graphics::Canvas;
+use crate::sync::IrqSpinlock;
 
 lazy_static! {
-    pub static ref VGA: Mutex<Vga> = Mutex::new(Vga {
+    pub static ref VGA: IrqSpinlock<Vga> = IrqSpinlock::new(Vga {
         frontbuffer: &mut [0; 0],
         backbuffer: &mut [0; 0],
         info: FrameBufferInfo {
@@ -26,13 +27,70 @@ lazy_static! {
             bytes_per_pixel: 0,
             stride: 0,
         },
+        damage: [Rect::new(0, 0, 0, 0); MAX_DAMAGE],
+        damaged: 0,
     });
 }
 
+/// Separate rectangles of damage kept for a flip, past this they are merged
+/// into one
+const MAX_DAMAGE: usize = 8;
+
+/// A rectangle of pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect { x, y, width, height }
+    }
+
+    /// The column after the last one
+    pub const fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    /// The row after the last one
+    pub const fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The part inside `other`, `None` if that is nothing
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let (right, bottom) = (self.right().min(other.right()), self.bottom().min(other.bottom()));
+        let rect = Rect::new(x, y, right.saturating_sub(x), bottom.saturating_sub(y));
+        (!rect.is_empty()).then_some(rect)
+    }
+
+    /// The smallest rectangle holding both
+    pub fn union(&self, other: &Rect) -> Rect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Rect::new(x, y, self.right().max(other.right()) - x, self.bottom().max(other.bottom()) - y)
+    }
+
+    /// Overlapping or sharing an edge
+    pub fn touches(&self, other: &Rect) -> bool {
+        self.x <= other.right() && other.x <= self.right() && self.y <= other.bottom() && other.y <= self.bottom()
+    }
+}
+
 pub struct Vga {
     frontbuffer: &'static mut [u8],
     backbuffer: &'static mut [u8],
     info: FrameBufferInfo,
+    /// Parts of the back buffer changed since the last flip
+    damage: [Rect; MAX_DAMAGE],
+    damaged: usize,
 }
 
 impl Vga {
@@ -42,26 +100,102 @@ impl Vga {
 
         let mut back_buffer: Box<[u8]> = vec![0; self.info.byte_len].into_boxed_slice();
         self.backbuffer = Box::leak(back_buffer);
+        self.damaged = 0;
+    }
 
+    fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.info.width, self.info.height)
     }
 
+    /// Bytes from one row of pixels to the next
+    fn row_length(&self) -> usize {
+        self.info.stride * self.info.bytes_per_pixel
+    }
+
+    /// Note that a rectangle of the back buffer changed, so the next flip
+    /// copies it. The drawing functions do this themselves, only `pixel`
+    /// and `pixel_fast` leave it to the caller.
+    pub fn damage(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let Some(rect) = Rect::new(x, y, width, height).intersect(&self.bounds()) else {
+            return;
+        };
+        let damaged = &mut self.damage[..self.damaged];
+        if let Some(near) = damaged.iter_mut().find(|near| near.touches(&rect)) {
+            *near = near.union(&rect);
+        } else if self.damaged < MAX_DAMAGE {
+            self.damage[self.damaged] = rect;
+            self.damaged += 1;
+        } else {
+            self.damage[0] = self.damage.iter().fold(rect, |all, rect| all.union(rect));
+            self.damaged = 1;
+        }
+    }
 
     pub fn clear(&mut self, colour: u32) {
         let rgba = vga::hex_to_rgba(colour);
         let packed_colour = self.pack_colour(rgba.0, rgba.1, rgba.2, 255); // can't have any alpha!
 
+        // fill the first row and copy it down
         let bytes_per_pixel = self.info.bytes_per_pixel;
-        let fill_value = packed_colour[..bytes_per_pixel].repeat(self.info.stride * self.info.height);
+        let row_length = self.row_length();
+        for pixel in self.backbuffer[..row_length].chunks_exact_mut(bytes_per_pixel) {
+            pixel.copy_from_slice(&packed_colour[..bytes_per_pixel]);
+        }
+        for row in 1..self.info.height {
+            self.backbuffer.copy_within(..row_length, row * row_length);
+        }
+        self.damage(0, 0, self.info.width, self.info.height);
+    }
 
-        self.backbuffer[..].copy_from_slice(&fill_value);
+    /// Fill a rectangle, clipped to the screen. Opaque colours are copied in
+    /// whole rows, others blended pixel by pixel.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, colour: u32) {
+        let Some(rect) = Rect::new(x, y, width, height).intersect(&self.bounds()) else {
+            return;
+        };
+        self.fill(rect, colour);
+        self.damage(rect.x, rect.y, rect.width, rect.height);
     }
 
+    /// `fill_rect` for a rectangle on the screen, without the damage
+    fn fill(&mut self, rect: Rect, colour: u32) {
+        let rgba = vga::hex_to_rgba(colour);
+        match rgba.3 {
+            0 => return,
+            255 => {
+                let packed_colour = self.pack_colour(rgba.0, rgba.1, rgba.2, rgba.3);
+                let bytes_per_pixel = self.info.bytes_per_pixel;
+                let row_length = self.row_length();
+                let start = rect.y * row_length + rect.x * bytes_per_pixel;
+                let span = start..start + rect.width * bytes_per_pixel;
+                for pixel in self.backbuffer[span.clone()].chunks_exact_mut(bytes_per_pixel) {
+                    pixel.copy_from_slice(&packed_colour[..bytes_per_pixel]);
+                }
+                for row in 1..rect.height {
+                    self.backbuffer.copy_within(span.clone(), start + row * row_length);
+                }
+            }
+            _ => {
+                for y in rect.y..rect.bottom() {
+                    for x in rect.x..rect.right() {
+                        self.pixel(x, y, colour);
+                    }
+                }
+            }
+        }
+    }
 
+    /// Blend a pixel into the back buffer, the caller marks the damage
     pub fn pixel(&mut self, x: usize, y: usize, colour: u32) {
-        if x >= 0 && x <= self.info.width - 1 && y >= 0 && y <= self.info.height - 1 {
-            let pixel_offset = y * self.info.stride + x;
+        if x < self.info.width && y < self.info.height {
             let rgba = vga::hex_to_rgba(colour);
-    
+            match rgba.3 {
+                0 => return,
+                255 => return self.pixel_fast(x, y, colour),
+                _ => {}
+            }
+            let pixel_offset = y * self.info.stride + x;
+
             let bytes_per_pixel = self.info.bytes_per_pixel;
             let byte_offset = pixel_offset * bytes_per_pixel;
 
@@ -77,8 +211,9 @@ impl Vga {
     }
 
 
+    /// Write a pixel without blending, the caller marks the damage
     pub fn pixel_fast(&mut self, x: usize, y: usize, colour: u32) {
-        if x >= 0 && x <= self.info.width-1 && y >= 0 && y <= self.info.height-1 {
+        if x < self.info.width && y < self.info.height {
             let pixel_offset = y * self.info.stride + x;
             let rgba = vga::hex_to_rgba(colour);
             let c = self.pack_colour(rgba.0, rgba.1, rgba.2, rgba.3);
@@ -131,20 +266,47 @@ impl Vga {
     // }
     
 
+    /// Move the whole frame up by `amount` rows, the rows left at the
+    /// bottom are cleared
     pub fn shift_y(&mut self, amount: usize) {
-        let row_length = self.info.byte_len / self.info.height;
+        let amount = amount.min(self.info.height);
+        let row_length = self.row_length();
+        let end = self.info.height * row_length;
 
-        let shift_amount = amount * row_length;
-        
-        let mut new_buffer = vec![0; self.info.byte_len];
-        new_buffer[0..self.info.byte_len-shift_amount].copy_from_slice(&self.backbuffer[shift_amount..self.info.byte_len]);
+        self.backbuffer.copy_within(amount * row_length..end, 0);
+        self.backbuffer[end - amount * row_length..end].fill(0);
+        self.damage(0, 0, self.info.width, self.info.height);
+    }
 
-        self.backbuffer.copy_from_slice(&new_buffer[0..self.info.byte_len]);
+    /// Move the rows `top..bottom` up by `distance` rows, or down for a
+    /// negative one, inside that band. The rows uncovered keep what they
+    /// had for the caller to draw over.
+    pub fn move_rows(&mut self, top: usize, bottom: usize, distance: isize) {
+        let bottom = bottom.min(self.info.height);
+        let amount = distance.unsigned_abs();
+        if top + amount >= bottom {
+            return;
+        }
+        let row_length = self.row_length();
+        match distance > 0 {
+            true => self.backbuffer.copy_within((top + amount) * row_length..bottom * row_length, top * row_length),
+            false => self.backbuffer.copy_within(top * row_length..(bottom - amount) * row_length, (top + amount) * row_length),
+        }
+        self.damage(0, top, self.info.width, bottom - top);
     }
-    
 
+    /// Copy what changed in the back buffer to the screen
     pub fn flip(&mut self) {
-        self.frontbuffer.copy_from_slice(&self.backbuffer[0..self.info.byte_len]);
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let row_length = self.row_length();
+        for rect in &self.damage[..self.damaged] {
+            for y in rect.y..rect.bottom() {
+                let start = y * row_length + rect.x * bytes_per_pixel;
+                let span = start..start + rect.width * bytes_per_pixel;
+                self.frontbuffer[span.clone()].copy_from_slice(&self.backbuffer[span]);
+            }
+        }
+        self.damaged = 0;
     }
 
     // get functions
@@ -178,3 +340,25 @@ impl Vga {
     }
 
 }
+
+impl Canvas for Vga {
+    fn width(&self) -> usize {
+        self.info.width
+    }
+
+    fn height(&self) -> usize {
+        self.info.height
+    }
+
+    fn blend(&mut self, x: usize, y: usize, colour: u32) {
+        self.pixel(x, y, colour);
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, width: usize, colour: u32) {
+        self.fill(Rect::new(x, y, width, 1), colour);
+    }
+
+    fn damage(&mut self, rect: Rect) {
+        Vga::damage(self, rect.x, rect.y, rect.width, rect.height);
+    }
+}
@@ -0,0 +1,440 @@
+//! 2D drawing on anything that implements `Canvas`, the screen or an
+//! off-screen `Surface`. Shapes take signed coordinates and are clipped to
+//! the canvas and the painter's clip rectangle, colours are `0xRRGGBBAA`
+//! and blended by their alpha.
+
+pub mod surface;
+
+pub use self::surface::Surface;
+pub use super::framebuffer::Rect;
+
+use alloc::vec::Vec;
+
+use super::{hex_to_rgba, rgba_to_hex};
+
+/// Radii are capped here, far past any screen, so rows can be walked one by
+/// one and `half_width` cannot overflow
+const MAX_RADIUS: usize = 1 << 20;
+
+/// Something to draw pixels on. Coordinates passed in are always inside
+/// the canvas.
+pub trait Canvas {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+
+    /// Blend `colour` into a pixel
+    fn blend(&mut self, x: usize, y: usize, colour: u32);
+
+    /// Blend `colour` into `width` pixels of a row
+    fn fill_span(&mut self, x: usize, y: usize, width: usize, colour: u32) {
+        for x in x..x + width {
+            self.blend(x, y, colour);
+        }
+    }
+
+    /// Note that a rectangle was drawn, for canvases that have to know
+    fn damage(&mut self, _rect: Rect) {}
+}
+
+impl<C: Canvas + ?Sized> Canvas for &mut C {
+    fn width(&self) -> usize {
+        (**self).width()
+    }
+
+    fn height(&self) -> usize {
+        (**self).height()
+    }
+
+    fn blend(&mut self, x: usize, y: usize, colour: u32) {
+        (**self).blend(x, y, colour)
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, width: usize, colour: u32) {
+        (**self).fill_span(x, y, width, colour)
+    }
+
+    fn damage(&mut self, rect: Rect) {
+        (**self).damage(rect)
+    }
+}
+
+/// Which way a gradient runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the left edge to the right
+    Horizontal,
+    /// From the top edge to the bottom
+    Vertical,
+}
+
+/// Draws shapes on a canvas, inside a clip rectangle
+pub struct Painter<C: Canvas> {
+    canvas: C,
+    clip: Rect,
+}
+
+impl<C: Canvas> Painter<C> {
+    pub fn new(canvas: C) -> Self {
+        let clip = Rect::new(0, 0, canvas.width(), canvas.height());
+        Painter { canvas, clip }
+    }
+
+    pub fn canvas(&mut self) -> &mut C {
+        &mut self.canvas
+    }
+
+    pub fn into_canvas(self) -> C {
+        self.canvas
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// Only draw inside `clip` from now on, as far as it is on the canvas
+    pub fn set_clip(&mut self, clip: Rect) {
+        let bounds = Rect::new(0, 0, self.canvas.width(), self.canvas.height());
+        self.clip = clip.intersect(&bounds).unwrap_or(Rect::new(0, 0, 0, 0));
+    }
+
+    /// Draw on the whole canvas again
+    pub fn reset_clip(&mut self) {
+        self.clip = Rect::new(0, 0, self.canvas.width(), self.canvas.height());
+    }
+
+    fn plot(&mut self, x: isize, y: isize, colour: u32) {
+        let clip = self.clip;
+        if x >= clip.x as isize && x < clip.right() as isize && y >= clip.y as isize && y < clip.bottom() as isize {
+            self.canvas.blend(x as usize, y as usize, colour);
+        }
+    }
+
+    /// Fill the pixels `from..to` of row `y`
+    fn span(&mut self, from: isize, to: isize, y: isize, colour: u32) {
+        let clip = self.clip;
+        if y < clip.y as isize || y >= clip.bottom() as isize {
+            return;
+        }
+        let from = from.max(clip.x as isize);
+        let to = to.min(clip.right() as isize);
+        if from < to {
+            self.canvas.fill_span(from as usize, y as usize, (to - from) as usize, colour);
+        }
+    }
+
+    /// Report the box from `(left, top)` to `(right, bottom)` inclusive as
+    /// drawn, as far as it is inside the clip rectangle
+    fn damage(&mut self, left: isize, top: isize, right: isize, bottom: isize) {
+        let clip = self.clip;
+        let left = left.max(clip.x as isize);
+        let top = top.max(clip.y as isize);
+        let right = right.min(clip.right() as isize - 1);
+        let bottom = bottom.min(clip.bottom() as isize - 1);
+        if left <= right && top <= bottom {
+            let rect = Rect::new(left as usize, top as usize, (right - left + 1) as usize, (bottom - top + 1) as usize);
+            self.canvas.damage(rect);
+        }
+    }
+
+    /// Fill the whole clip rectangle
+    pub fn clear(&mut self, colour: u32) {
+        let clip = self.clip;
+        self.fill_rect(clip.x as isize, clip.y as isize, clip.width, clip.height, colour);
+    }
+
+    /// A Bresenham line, both ends included
+    pub fn line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, colour: u32) {
+        self.segment(x0, y0, x1, y1, colour, true);
+        self.damage(x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1));
+    }
+
+    /// A line without damage, the end left out for polygons so no corner is
+    /// blended twice
+    fn segment(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, colour: u32, end: bool) {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y) = (x0, y0);
+        let mut error = dx + dy;
+        loop {
+            let last = x == x1 && y == y1;
+            if !last || end {
+                self.plot(x, y, colour);
+            }
+            if last {
+                break;
+            }
+            let double = 2 * error;
+            if double >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if double <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// The outline of a rectangle, one pixel wide
+    pub fn rect(&mut self, x: isize, y: isize, width: usize, height: usize, colour: u32) {
+        self.rounded_rect(x, y, width, height, 0, colour);
+    }
+
+    pub fn fill_rect(&mut self, x: isize, y: isize, width: usize, height: usize, colour: u32) {
+        self.fill_rounded_rect(x, y, width, height, 0, colour);
+    }
+
+    pub fn circle(&mut self, x: isize, y: isize, radius: usize, colour: u32) {
+        self.ellipse(x, y, radius, radius, colour);
+    }
+
+    pub fn fill_circle(&mut self, x: isize, y: isize, radius: usize, colour: u32) {
+        self.fill_ellipse(x, y, radius, radius, colour);
+    }
+
+    /// The outline of an ellipse around `(x, y)`. Each row is drawn as
+    /// spans out to the next row's edge, so the outline has no gaps and no
+    /// pixel is drawn twice.
+    pub fn ellipse(&mut self, x: isize, y: isize, radius_x: usize, radius_y: usize, colour: u32) {
+        let (rx, ry) = (radius_x.min(MAX_RADIUS) as isize, radius_y.min(MAX_RADIUS) as isize);
+        for dy in -ry..=ry {
+            let outer = half_width(rx, ry, dy.abs());
+            let next = match dy.abs() < ry {
+                true => half_width(rx, ry, dy.abs() + 1),
+                false => -1,
+            };
+            let inner = (next + 1).min(outer);
+            if inner == 0 {
+                self.span(x - outer, x + outer + 1, y + dy, colour);
+            } else {
+                self.span(x - outer, x - inner + 1, y + dy, colour);
+                self.span(x + inner, x + outer + 1, y + dy, colour);
+            }
+        }
+        self.damage(x - rx, y - ry, x + rx, y + ry);
+    }
+
+    pub fn fill_ellipse(&mut self, x: isize, y: isize, radius_x: usize, radius_y: usize, colour: u32) {
+        let (rx, ry) = (radius_x.min(MAX_RADIUS) as isize, radius_y.min(MAX_RADIUS) as isize);
+        for dy in -ry..=ry {
+            let half = half_width(rx, ry, dy.abs());
+            self.span(x - half, x + half + 1, y + dy, colour);
+        }
+        self.damage(x - rx, y - ry, x + rx, y + ry);
+    }
+
+    /// The outline of a closed polygon through `points`
+    pub fn polygon(&mut self, points: &[(isize, isize)], colour: u32) {
+        let Some((left, top, right, bottom)) = bounding_box(points) else {
+            return;
+        };
+        if let [(x, y)] = points {
+            self.plot(*x, *y, colour);
+        }
+        for (index, &(x0, y0)) in points.iter().enumerate() {
+            let (x1, y1) = points[(index + 1) % points.len()];
+            if points.len() > 1 {
+                self.segment(x0, y0, x1, y1, colour, false);
+            }
+        }
+        self.damage(left, top, right, bottom);
+    }
+
+    /// Fill a polygon by the even-odd rule, a pixel is inside if its middle
+    /// is
+    pub fn fill_polygon(&mut self, points: &[(isize, isize)], colour: u32) {
+        let Some((left, top, right, bottom)) = bounding_box(points) else {
+            return;
+        };
+        let top = top.max(self.clip.y as isize);
+        let bottom = bottom.min(self.clip.bottom() as isize - 1);
+        let mut crossings: Vec<i64> = Vec::new();
+        for y in top..=bottom {
+            // where the edges cross the middle of the row, in 1/65536 pixels
+            crossings.clear();
+            for (index, &(x0, y0)) in points.iter().enumerate() {
+                let (x1, y1) = points[(index + 1) % points.len()];
+                if (y0 <= y) == (y1 <= y) {
+                    continue;
+                }
+                let (x0, y0, x1, y1) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64);
+                let along = ((2 * y as i64 + 1 - 2 * y0) << 16) * (x1 - x0) / (2 * (y1 - y0));
+                crossings.push((x0 << 16) + along);
+            }
+            crossings.sort_unstable();
+            for pair in crossings.chunks_exact(2) {
+                // the first and last pixel whose middle is inside
+                let from = (pair[0] + 0x7fff) >> 16;
+                let to = (pair[1] + 0x7fff) >> 16;
+                self.span(from as isize, to as isize, y, colour);
+            }
+        }
+        self.damage(left, top, right, bottom);
+    }
+
+    /// The outline of a rectangle with corners rounded to `radius`
+    pub fn rounded_rect(&mut self, x: isize, y: isize, width: usize, height: usize, radius: usize, colour: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let corners = Corners::new(width, height, radius);
+        let (width, height) = (width as isize, height as isize);
+        for row in 0..height {
+            let inset = corners.inset(row);
+            if row == 0 || row == height - 1 {
+                self.span(x + inset, x + width - inset, y + row, colour);
+                continue;
+            }
+            // out to where the row nearer the edge starts, like `ellipse`
+            let outer = match row < height / 2 {
+                true => corners.inset(row - 1),
+                false => corners.inset(row + 1),
+            };
+            let reach = (outer - 1).max(inset);
+            if reach >= width - 1 - reach {
+                self.span(x + inset, x + width - inset, y + row, colour);
+            } else {
+                self.span(x + inset, x + reach + 1, y + row, colour);
+                self.span(x + width - 1 - reach, x + width - inset, y + row, colour);
+            }
+        }
+        self.damage(x, y, x + width - 1, y + height - 1);
+    }
+
+    pub fn fill_rounded_rect(&mut self, x: isize, y: isize, width: usize, height: usize, radius: usize, colour: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let corners = Corners::new(width, height, radius);
+        let (width, height) = (width as isize, height as isize);
+        for row in 0..height {
+            let inset = corners.inset(row);
+            self.span(x + inset, x + width - inset, y + row, colour);
+        }
+        self.damage(x, y, x + width - 1, y + height - 1);
+    }
+
+    /// Fill a rectangle fading from `from` to `to`, alpha included
+    pub fn gradient(&mut self, x: isize, y: isize, width: usize, height: usize, from: u32, to: u32, direction: Direction) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        match direction {
+            Direction::Vertical => {
+                for row in 0..height {
+                    let colour = mix(from, to, row, height);
+                    self.span(x, x + width as isize, y + row as isize, colour);
+                }
+            }
+            Direction::Horizontal => {
+                let colours: Vec<u32> = (0..width).map(|column| mix(from, to, column, width)).collect();
+                for row in 0..height as isize {
+                    for (column, &colour) in colours.iter().enumerate() {
+                        self.plot(x + column as isize, y + row, colour);
+                    }
+                }
+            }
+        }
+        self.damage(x, y, x + width as isize - 1, y + height as isize - 1);
+    }
+
+    /// Draw `image` with its top left corner at `(x, y)`
+    pub fn blit(&mut self, image: &Surface, x: isize, y: isize) {
+        self.blit_scaled(image, x, y, image.width(), image.height());
+    }
+
+    /// Draw `image` stretched to `width` by `height`, picking the nearest
+    /// pixel and blending it by its alpha
+    pub fn blit_scaled(&mut self, image: &Surface, x: isize, y: isize, width: usize, height: usize) {
+        if width == 0 || height == 0 || image.width() == 0 || image.height() == 0 {
+            return;
+        }
+        let clip = self.clip;
+        let left = x.max(clip.x as isize);
+        let top = y.max(clip.y as isize);
+        let right = (x + width as isize).min(clip.right() as isize);
+        let bottom = (y + height as isize).min(clip.bottom() as isize);
+        for row in top..bottom {
+            let source_y = (row - y) as usize * image.height() / height;
+            for column in left..right {
+                let source_x = (column - x) as usize * image.width() / width;
+                let colour = image.pixel(source_x, source_y);
+                if colour & 0xff != 0 {
+                    self.canvas.blend(column as usize, row as usize, colour);
+                }
+            }
+        }
+        self.damage(x, y, x + width as isize - 1, y + height as isize - 1);
+    }
+}
+
+/// How far the edge of an ellipse with radii `rx` and `ry` is from its
+/// middle column, `dy` rows from its middle row. Pixels count as inside
+/// when their middle is, with the radii taken half a pixel wider so the
+/// ends are not single pixels.
+fn half_width(rx: isize, ry: isize, dy: isize) -> isize {
+    let (width, height) = ((2 * rx + 1) as u128, (2 * ry + 1) as u128);
+    let dy = 2 * dy as u128;
+    if dy > height {
+        return -1;
+    }
+    // at most `width` squared, which fits with radii up to `MAX_RADIUS`
+    let squared = width * width * (height * height - dy * dy) / (height * height);
+    (isqrt(squared as u64) / 2) as isize
+}
+
+fn isqrt(value: u64) -> u64 {
+    if value < 2 {
+        return value;
+    }
+    // Newton's method from above
+    let mut root = value;
+    let mut next = (root + value / root) / 2;
+    while next < root {
+        root = next;
+        next = (root + value / root) / 2;
+    }
+    root
+}
+
+/// Left, top, right and bottom of `points`
+fn bounding_box(points: &[(isize, isize)]) -> Option<(isize, isize, isize, isize)> {
+    let (&(x, y), rest) = points.split_first()?;
+    Some(rest.iter().fold((x, y, x, y), |(left, top, right, bottom), &(x, y)| {
+        (left.min(x), top.min(y), right.max(x), bottom.max(y))
+    }))
+}
+
+/// Rounded corners of a rectangle
+struct Corners {
+    radius: isize,
+    height: isize,
+}
+
+impl Corners {
+    fn new(width: usize, height: usize, radius: usize) -> Self {
+        let radius = radius.min((width - 1) / 2).min((height - 1) / 2).min(MAX_RADIUS) as isize;
+        Corners { radius, height: height as isize }
+    }
+
+    /// Pixels row `row` starts in from each side
+    fn inset(&self, row: isize) -> isize {
+        let radius = self.radius;
+        let dy = match row {
+            _ if row < radius => radius - row,
+            _ if row >= self.height - radius => row - (self.height - 1 - radius),
+            _ => return 0,
+        };
+        radius - half_width(radius, radius, dy)
+    }
+}
+
+/// The colour `step` of `steps` along the way from `from` to `to`
+fn mix(from: u32, to: u32, step: usize, steps: usize) -> u32 {
+    let (from, to) = (hex_to_rgba(from), hex_to_rgba(to));
+    let last = steps.saturating_sub(1).max(1) as i32;
+    let step = step as i32;
+    let channel = |from: u8, to: u8| (from as i32 + (to as i32 - from as i32) * step / last) as u8;
+    rgba_to_hex(channel(from.0, to.0), channel(from.1, to.1), channel(from.2, to.2), channel(from.3, to.3))
+}
+
@@ -0,0 +1,85 @@
+//! An image kept in memory, to draw on off screen or blit onto another
+//! canvas.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{Canvas, Painter};
+use crate::io::vga::{blend_colour, hex_to_rgba, rgba_to_hex};
+
+/// `width` by `height` pixels of `0xRRGGBBAA`, row after row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Surface {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+}
+
+impl Surface {
+    /// A fully transparent surface
+    pub fn new(width: usize, height: usize) -> Self {
+        Surface { width, height, pixels: vec![0; width * height] }
+    }
+
+    /// A surface from four bytes per pixel in red, green, blue, alpha order,
+    /// or `None` if `data` is not that long
+    pub fn from_rgba(width: usize, height: usize, data: &[u8]) -> Option<Self> {
+        if data.len() != width.checked_mul(height)?.checked_mul(4)? {
+            return None;
+        }
+        let pixels = data.chunks_exact(4).map(|pixel| rgba_to_hex(pixel[0], pixel[1], pixel[2], pixel[3])).collect();
+        Some(Surface { width, height, pixels })
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    /// Set every pixel to `colour`, without blending
+    pub fn clear(&mut self, colour: u32) {
+        self.pixels.fill(colour);
+    }
+
+    pub fn painter(&mut self) -> Painter<&mut Self> {
+        Painter::new(self)
+    }
+}
+
+impl Canvas for Surface {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn blend(&mut self, x: usize, y: usize, colour: u32) {
+        let pixel = &mut self.pixels[y * self.width + x];
+        let alpha = colour & 0xff;
+        if alpha == 0xff || *pixel & 0xff == 0 {
+            *pixel = colour;
+        } else if alpha != 0 {
+            let existing = hex_to_rgba(*pixel);
+            let (red, green, blue, _) = blend_colour(hex_to_rgba(colour), existing);
+            // the result covers whatever either of them covered
+            let alpha = alpha + (existing.3 as u32) * (0xff - alpha) / 0xff;
+            *pixel = rgba_to_hex(red, green, blue, alpha as u8);
+        }
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, width: usize, colour: u32) {
+        if colour & 0xff == 0xff {
+            let start = y * self.width + x;
+            self.pixels[start..start + width].fill(colour);
+        } else {
+            for x in x..x + width {
+                self.blend(x, y, colour);
+            }
+        }
+    }
+}
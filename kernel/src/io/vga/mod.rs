@@ -1,7 +1,10 @@
 pub mod framebuffer;
 pub mod font;
+pub mod graphics;
 
 use bootloader_api::info::{FrameBufferInfo, PixelFormat, FrameBuffer, BootInfo};
+use font::Font;
+use graphics::Painter;
 
 
 /// Initialize the screen
@@ -11,9 +14,10 @@ pub fn init(boot_info: &'static BootInfo) {
     let framebuffer = boot_info_mut.framebuffer.as_mut().unwrap();
     let info = framebuffer.info().clone();
         
-    framebuffer::VGA.lock().init(framebuffer.buffer_mut(), info);
-    framebuffer::VGA.lock().clear(0x00_00_00_00);
-    framebuffer::VGA.lock().flip();
+    let mut vga = framebuffer::VGA.lock();
+    vga.init(framebuffer.buffer_mut(), info);
+    vga.clear(0x00_00_00_00);
+    vga.flip();
 }
 
 /// Clear the screen
@@ -23,12 +27,16 @@ pub fn clear(colour: u32) {
 
 /// Draw pixel
 pub fn pixel(x: usize, y: usize, colour: u32) {
-    framebuffer::VGA.lock().pixel(x, y, colour);
+    let mut vga = framebuffer::VGA.lock();
+    vga.pixel(x, y, colour);
+    vga.damage(x, y, 1, 1);
 }
 
 /// Draw pixel without alpha
 pub fn pixel_fast(x: usize, y: usize, colour: u32) {
-    framebuffer::VGA.lock().pixel_fast(x, y, colour);
+    let mut vga = framebuffer::VGA.lock();
+    vga.pixel_fast(x, y, colour);
+    vga.damage(x, y, 1, 1);
 }
 
 /// Shift the framebuffer by <amount> on the y axis
@@ -36,41 +44,86 @@ pub fn shift_y(amount: usize) {
     framebuffer::VGA.lock().shift_y(amount);
 }
 
-/// Flip the double buffer
+/// Move the pixel rows `top..bottom` up by `distance`, or down for a
+/// negative one, leaving the uncovered rows to be drawn over
+pub fn move_rows(top: usize, bottom: usize, distance: isize) {
+    framebuffer::VGA.lock().move_rows(top, bottom, distance);
+}
+
+/// Flip the double buffer, only what was drawn since the last flip is copied
 pub fn flip() {
     framebuffer::VGA.lock().flip();
 }
 
 pub fn rect(x: usize, y: usize, w: usize, h: usize, colour: u32) {
-    for j in 0..w {
-        for k in 0..h {
-            pixel(x+j, y+k, colour);
-        }
-    }
+    framebuffer::VGA.lock().fill_rect(x, y, w, h, colour);
 }
 
+/// Draw on the screen with a `Painter`, holding the lock until `draw`
+/// returns. What is drawn shows up on the next `flip`.
+pub fn paint<R>(draw: impl FnOnce(&mut Painter<&mut framebuffer::Vga>) -> R) -> R {
+    let mut vga = framebuffer::VGA.lock();
+    draw(&mut Painter::new(&mut *vga))
+}
+
+/// Draw `c` anti-aliased in the default noto raster
 pub fn char(x: usize, y: usize, fg_colour: u32, bg_colour: u32, c: char) {
-    
+    glyph(x, y, &font::noto::Noto::default(), c, fg_colour, bg_colour);
 }
 
+/// Draw the glyph for `c` from `font` in a cell at `x`, `y`, anti-aliased
+/// if the font has the coverage for it
+pub fn glyph(x: usize, y: usize, font: &dyn Font, c: char, fg_colour: u32, bg_colour: u32) {
+    let config = font.metrics();
+    match font.coverage(c) {
+        Some(coverage) => antialiased(x, y, config.width, config.height, &coverage, fg_colour, bg_colour),
+        None => bitmap(x, y, 1, config.width, config.height, &font::lookup(font, c), fg_colour, bg_colour),
+    }
+}
+
+/// Draw the glyph for `c` in the built in font, each pixel scaled up to
+/// `size` by `size`
 pub fn char_bitmap(x: usize, y: usize, size: usize, fg_colour: u32, bg_colour: u32, c: char) {
-    let mut x_offset: usize = 0;
-    let mut y_offset: usize = 0;
-    for j in &font::cozette::DATA[c as usize] {
-        y_offset += 1;
-        for bit in 0..8 {
-            rect(x+(x_offset*size), y+(y_offset*size), size, size, bg_colour);
-            match *j & 1 << (bit*-1)+8 {
-                0 => {
-                },
-                _ => {
-                    rect(x+(x_offset*size), y+(y_offset*size), size, size, fg_colour);
-                },
+    let config = font::cozette::CONFIG;
+    let glyph = font::lookup(&font::Cozette, c);
+    bitmap(x, y, size, config.width, config.height, &glyph, fg_colour, bg_colour);
+}
+
+/// Draw a `width` by `height` 1-bit bitmap with rows padded to whole bytes,
+/// each pixel scaled up to `size` by `size`
+pub fn bitmap(x: usize, y: usize, size: usize, width: usize, height: usize, data: &[u8], fg_colour: u32, bg_colour: u32) {
+    let stride = (width + 7) / 8;
+    let mut vga = framebuffer::VGA.lock();
+    for (y_offset, row) in data.chunks(stride).take(height).enumerate() {
+        for x_offset in 0..width {
+            let colour = match row[x_offset / 8] & 0x80 >> (x_offset % 8) {
+                0 => bg_colour,
+                _ => fg_colour,
+            };
+            for j in 0..size {
+                for k in 0..size {
+                    vga.pixel(x + x_offset * size + j, y + y_offset * size + k, colour);
+                }
             }
-            x_offset += 1;
         }
-        x_offset = 0;
     }
+    vga.damage(x, y, width * size, height * size);
+}
+
+/// Draw a `width` by `height` coverage map with a byte per pixel, blending
+/// the foreground into the background by how much of the pixel is covered.
+/// The background is opaque, so whatever was drawn there before is replaced.
+pub fn antialiased(x: usize, y: usize, width: usize, height: usize, coverage: &[u8], fg_colour: u32, bg_colour: u32) {
+    let (fg, bg) = (hex_to_rgba(fg_colour), hex_to_rgba(bg_colour));
+    let mut vga = framebuffer::VGA.lock();
+    for (y_offset, row) in coverage.chunks(width).take(height).enumerate() {
+        for (x_offset, &covered) in row.iter().enumerate() {
+            let alpha = (fg.3 as u16 * covered as u16 / 255) as u8;
+            let (red, green, blue, _) = blend_colour((fg.0, fg.1, fg.2, alpha), bg);
+            vga.pixel_fast(x + x_offset, y + y_offset, rgba_to_hex(red, green, blue, 255));
+        }
+    }
+    vga.damage(x, y, width, height);
 }
 
 // Get functions
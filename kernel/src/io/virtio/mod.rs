@@ -0,0 +1,84 @@
+pub mod pci;
+pub mod queue;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// The device follows the virtio 1.0 spec rather than the legacy interface
+pub const F_VERSION_1: u64 = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device did not accept our feature selection
+    FeaturesRejected,
+    QueueUnavailable,
+    OutOfMemory,
+    /// The transport's configuration is incomplete, or the device handed
+    /// back a descriptor chain it was never given
+    BadDevice,
+    /// The device did not finish a request in time
+    Timeout,
+}
+
+/// How the driver reaches a device, the same for every device type
+pub trait Transport: Send {
+    fn device_features(&mut self) -> u64;
+    fn set_driver_features(&mut self, features: u64);
+    fn status(&mut self) -> u8;
+    fn set_status(&mut self, status: u8);
+
+    /// Whether this is a virtio 1.0 device
+    fn is_modern(&self) -> bool;
+
+    /// Largest queue the device supports, 0 if `queue` does not exist
+    fn max_queue_size(&mut self, queue: u16) -> u16;
+
+    /// Tell the device where the rings of `queue` live and enable it
+    fn setup_queue(&mut self, queue: u16, size: u16, desc: u64, avail: u64, used: u64) -> Result<(), VirtioError>;
+
+    /// Tell the device there are new buffers in `queue`
+    fn notify(&mut self, queue: u16);
+
+    fn read_config_u8(&mut self, offset: usize) -> u8;
+    fn read_config_u32(&mut self, offset: usize) -> u32;
+
+    fn read_config_u64(&mut self, offset: usize) -> u64 {
+        self.read_config_u32(offset) as u64 | (self.read_config_u32(offset + 4) as u64) << 32
+    }
+}
+
+/// Reset the device and negotiate features, returning the accepted set.
+/// Queues still have to be set up before calling `finish_init`.
+pub fn begin_init(transport: &mut dyn Transport, supported: u64) -> Result<u64, VirtioError> {
+    transport.set_status(0);
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let mut supported = supported;
+    if transport.is_modern() {
+        supported |= F_VERSION_1;
+    }
+    let features = transport.device_features() & supported;
+    transport.set_driver_features(features);
+
+    // legacy devices have no FEATURES_OK handshake
+    if transport.is_modern() {
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            transport.set_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+    }
+    Ok(features)
+}
+
+pub fn finish_init(transport: &mut dyn Transport) {
+    let status = transport.status();
+    transport.set_status(status | STATUS_DRIVER_OK);
+}
@@ -0,0 +1,244 @@
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+use super::{Transport, VirtioError};
+use crate::io::pci::{capability::CAP_VENDOR, Bar, PciDevice};
+
+// vendor capability types
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// legacy I/O registers
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+/// Device specific configuration, as long as MSI-X is off
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+/// A register block in one of the device's memory BARs
+#[derive(Clone, Copy)]
+struct Region {
+    base: VirtAddr,
+}
+
+impl Region {
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read_volatile((self.base + offset as u64).as_ptr()) }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { write_volatile((self.base + offset as u64).as_mut_ptr(), value) }
+    }
+}
+
+/// Map the structure described by a virtio vendor capability
+fn map_capability(device: &PciDevice, offset: u16) -> Option<Region> {
+    let address = device.address;
+    let bar = address.read_u8(offset + 4);
+    let region_offset = address.read_u32(offset + 8) as u64;
+    let length = address.read_u32(offset + 12) as u64;
+
+    let base = device.bar(bar as usize)?.memory_address()?;
+    Some(Region { base: crate::memory::map_mmio(base + region_offset, length.max(1)) })
+}
+
+/// The virtio 1.0 transport, configured through vendor specific capabilities
+pub struct ModernTransport {
+    common: Region,
+    notify: Region,
+    notify_multiplier: u32,
+    device: Region,
+}
+
+impl ModernTransport {
+    pub fn new(device: &PciDevice) -> Option<ModernTransport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut config = None;
+
+        for cap in device.capabilities.iter().filter(|cap| cap.id == CAP_VENDOR) {
+            match device.address.read_u8(cap.offset + 3) {
+                CAP_COMMON_CFG if common.is_none() => common = map_capability(device, cap.offset),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    let multiplier = device.address.read_u32(cap.offset + 16);
+                    notify = map_capability(device, cap.offset).map(|region| (region, multiplier));
+                }
+                CAP_DEVICE_CFG if config.is_none() => config = map_capability(device, cap.offset),
+                _ => {}
+            }
+        }
+
+        let (notify, notify_multiplier) = notify?;
+        Some(ModernTransport {
+            common: common?,
+            notify,
+            notify_multiplier,
+            device: config?,
+        })
+    }
+}
+
+impl Transport for ModernTransport {
+    fn device_features(&mut self) -> u64 {
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let low = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+        let high = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+        low | high << 32
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE, features as u32);
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    fn status(&mut self) -> u8 {
+        self.common.read(COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.common.write(COMMON_DEVICE_STATUS, status)
+    }
+
+    fn is_modern(&self) -> bool {
+        true
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.common.write(COMMON_QUEUE_SELECT, queue);
+        self.common.read(COMMON_QUEUE_SIZE)
+    }
+
+    fn setup_queue(&mut self, queue: u16, size: u16, desc: u64, avail: u64, used: u64) -> Result<(), VirtioError> {
+        self.common.write(COMMON_QUEUE_SELECT, queue);
+        self.common.write(COMMON_QUEUE_SIZE, size);
+        self.common.write(COMMON_QUEUE_DESC, desc);
+        self.common.write(COMMON_QUEUE_DRIVER, avail);
+        self.common.write(COMMON_QUEUE_DEVICE, used);
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.common.write(COMMON_QUEUE_SELECT, queue);
+        let offset = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as usize;
+        self.notify.write(offset * self.notify_multiplier as usize, queue);
+    }
+
+    fn read_config_u8(&mut self, offset: usize) -> u8 {
+        self.device.read(offset)
+    }
+
+    fn read_config_u32(&mut self, offset: usize) -> u32 {
+        self.device.read(offset)
+    }
+}
+
+/// The pre 1.0 transport: registers in I/O space behind BAR 0
+pub struct LegacyTransport {
+    base: u16,
+}
+
+impl LegacyTransport {
+    pub fn new(device: &PciDevice) -> Option<LegacyTransport> {
+        match device.bar(0)? {
+            Bar::Io { port, .. } => Some(LegacyTransport { base: port }),
+            Bar::Memory { .. } => None,
+        }
+    }
+
+    fn port<T>(&self, offset: u16) -> Port<T> {
+        Port::new(self.base + offset)
+    }
+}
+
+impl Transport for LegacyTransport {
+    fn device_features(&mut self) -> u64 {
+        unsafe { self.port::<u32>(LEGACY_DEVICE_FEATURES).read() as u64 }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        unsafe { self.port::<u32>(LEGACY_DRIVER_FEATURES).write(features as u32) }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.port::<u8>(LEGACY_DEVICE_STATUS).read() }
+    }
+
+    fn set_status(&mut self, status: u8) {
+        unsafe { self.port::<u8>(LEGACY_DEVICE_STATUS).write(status) }
+    }
+
+    fn is_modern(&self) -> bool {
+        false
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        unsafe {
+            self.port::<u16>(LEGACY_QUEUE_SELECT).write(queue);
+            self.port::<u16>(LEGACY_QUEUE_SIZE).read()
+        }
+    }
+
+    fn setup_queue(&mut self, queue: u16, size: u16, desc: u64, _avail: u64, _used: u64) -> Result<(), VirtioError> {
+        // the size is fixed and the rings follow the descriptors at fixed offsets
+        if size != self.max_queue_size(queue) || desc % 4096 != 0 {
+            return Err(VirtioError::BadDevice);
+        }
+        unsafe { self.port::<u32>(LEGACY_QUEUE_ADDRESS).write((desc / 4096) as u32) };
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        unsafe { self.port::<u16>(LEGACY_QUEUE_NOTIFY).write(queue) }
+    }
+
+    fn read_config_u8(&mut self, offset: usize) -> u8 {
+        unsafe { self.port::<u8>(LEGACY_DEVICE_CONFIG + offset as u16).read() }
+    }
+
+    fn read_config_u32(&mut self, offset: usize) -> u32 {
+        unsafe { self.port::<u32>(LEGACY_DEVICE_CONFIG + offset as u16).read() }
+    }
+}
+
+/// Pick the modern transport if the device has one, legacy otherwise
+pub fn transport(device: &PciDevice) -> Option<Box<dyn Transport>> {
+    device.enable_bus_master();
+    if let Some(modern) = ModernTransport::new(device) {
+        return Some(Box::new(modern));
+    }
+    LegacyTransport::new(device).map(|legacy| Box::new(legacy) as Box<dyn Transport>)
+}
+
+/// The virtio device type, e.g. 2 for block devices
+pub fn device_type(device: &PciDevice) -> u16 {
+    match device.device_id {
+        // transitional devices keep the type in the subsystem id
+        0x1000..=0x103F => device.address.read_u16(0x2E),
+        id => id - 0x1040,
+    }
+}
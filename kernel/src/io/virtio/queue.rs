@@ -0,0 +1,212 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+
+use super::{Transport, VirtioError};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Legacy devices want the used ring on its own page
+const QUEUE_ALIGN: usize = 4096;
+
+/// Modern devices let us pick the size, keep queues small
+const MAX_QUEUE_SIZE: u16 = 256;
+
+/// Checks of the used ring before `submit_and_wait` gives up on the device
+const POLL_LIMIT: usize = 10_000_000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer handed to the device
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// The device writes into this buffer instead of reading it
+    pub device_writable: bool,
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// A split virtqueue: descriptor table, available ring and used ring in one
+/// physically contiguous allocation laid out the way legacy devices need it
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    desc: *mut Descriptor,
+    /// flags, idx, ring[size]
+    avail: *mut u16,
+    /// flags, idx, ring[size] of (id: u32, len: u32)
+    used: *mut u8,
+    free_head: u16,
+    free_count: u16,
+    next_avail: u16,
+    last_used: u16,
+}
+
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    pub fn new(transport: &mut dyn Transport, index: u16) -> Result<VirtQueue, VirtioError> {
+        let max = transport.max_queue_size(index);
+        if max == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let size = if transport.is_modern() { max.min(MAX_QUEUE_SIZE) } else { max };
+
+        let desc_size = 16 * size as usize;
+        let avail_size = 6 + 2 * size as usize;
+        let used_offset = align_up(desc_size + avail_size, QUEUE_ALIGN);
+        let used_size = 6 + 8 * size as usize;
+        let pages = align_up(used_offset + used_size, 4096) / 4096;
+
+        let frame = crate::memory::allocate_dma(pages).ok_or(VirtioError::OutOfMemory)?;
+        let phys = frame.start_address().as_u64();
+        let virt = crate::memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+
+        transport.setup_queue(
+            index,
+            size,
+            phys,
+            phys + desc_size as u64,
+            phys + used_offset as u64,
+        )?;
+
+        let desc = virt as *mut Descriptor;
+        // chain every descriptor into the free list
+        for i in 0..size {
+            unsafe { (*desc.add(i as usize)).next = i + 1 };
+        }
+
+        Ok(VirtQueue {
+            index,
+            size,
+            desc,
+            avail: unsafe { virt.add(desc_size) } as *mut u16,
+            used: unsafe { virt.add(used_offset) },
+            free_head: 0,
+            free_count: size,
+            next_avail: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Put a chain of buffers on the available ring, returning its head
+    /// descriptor, or `None` if the queue is full
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = unsafe { &mut *self.desc.add(current as usize) };
+            let next = desc.next;
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            *desc = Descriptor {
+                addr: buffer.addr.as_u64(),
+                len: buffer.len,
+                flags,
+                next,
+            };
+            if i + 1 < buffers.len() {
+                current = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let slot = self.avail.add(2 + (self.next_avail % self.size) as usize);
+            write_volatile(slot, head);
+            // the descriptors have to be visible before the index moves
+            fence(Ordering::SeqCst);
+            self.next_avail = self.next_avail.wrapping_add(1);
+            write_volatile(self.avail.add(1), self.next_avail);
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Take the next chain the device is done with: its head and the number
+    /// of bytes the device wrote. The head comes from the device, one that
+    /// is not the start of a chain we handed out is `BadDevice`.
+    pub fn pop_used(&mut self) -> Result<Option<(u16, u32)>, VirtioError> {
+        let used_idx = unsafe { read_volatile((self.used as *const u16).add(1)) };
+        if used_idx == self.last_used {
+            return Ok(None);
+        }
+        fence(Ordering::SeqCst);
+
+        let element = unsafe { self.used.add(4 + 8 * (self.last_used % self.size) as usize) } as *const u32;
+        let (id, len) = unsafe { (read_volatile(element), read_volatile(element.add(1))) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        if id >= self.size as u32 {
+            return Err(VirtioError::BadDevice);
+        }
+        self.free_chain(id as u16)?;
+        Ok(Some((id as u16, len)))
+    }
+
+    /// Return a finished chain to the free list. A chain is never longer
+    /// than the queue, a longer or out of range one is left alone.
+    fn free_chain(&mut self, head: u16) -> Result<(), VirtioError> {
+        let mut last = head;
+        let mut count = 1;
+        loop {
+            let desc = unsafe { &*self.desc.add(last as usize) };
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            if desc.next >= self.size || count >= self.size {
+                return Err(VirtioError::BadDevice);
+            }
+            last = desc.next;
+            count += 1;
+        }
+
+        unsafe { (*self.desc.add(last as usize)).next = self.free_head };
+        self.free_head = head;
+        self.free_count += count;
+        Ok(())
+    }
+
+    /// Add `buffers`, notify the device and spin until it hands them back.
+    /// Chains of earlier requests that timed out are freed when they finish.
+    pub fn submit_and_wait(&mut self, transport: &mut dyn Transport, buffers: &[Buffer]) -> Result<u32, VirtioError> {
+        let head = self.add(buffers).ok_or(VirtioError::QueueUnavailable)?;
+        transport.notify(self.index);
+
+        for _ in 0..POLL_LIMIT {
+            match self.pop_used()? {
+                Some((id, len)) if id == head => return Ok(len),
+                Some((id, _)) => log::warn!("virtio queue {}: late completion of chain {}", self.index, id),
+                None => core::hint::spin_loop(),
+            }
+        }
+        Err(VirtioError::Timeout)
+    }
+}
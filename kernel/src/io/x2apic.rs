@@ -2,15 +2,25 @@ use acpi::platform::interrupt::Apic;
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
 use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
-use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder, TimerDivide};
+use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder, TimerDivide, TimerMode};
+use x86_64::registers::model_specific::Msr;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
-use x86_64::VirtAddr;
 
 use crate::cpu::interrupts::InterruptIndex;
+use crate::cpu::percpu::{self, Cpu};
 use crate::{hlt_loop, println};
 
-pub static LAPIC: OnceCell<Mutex<LocalApic>> = OnceCell::uninit();
 pub static IOAPIC: OnceCell<Mutex<IoApic>> = OnceCell::uninit();
+/// Where the LAPIC registers are mapped while it runs in xAPIC mode
+static XAPIC_BASE: OnceCell<u64> = OnceCell::uninit();
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const X2APIC_ID: u32 = 0x802;
+const X2APIC_TIMER_CURRENT: u32 = 0x839;
+const XAPIC_ID: u64 = 0x20;
+const XAPIC_TIMER_CURRENT: u64 = 0x390;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -26,25 +36,29 @@ unsafe fn disable_pic() {
 
 pub fn init(apic: &Apic) {
     unsafe { disable_pic() }
-    init_lapic(apic);
+    let apic_virt_addr = crate::memory::map_mmio(apic.local_apic_address, 4096).as_u64();
+    XAPIC_BASE.init_once(|| apic_virt_addr);
+    log::trace!("mapped phys addr to virt addr");
+
+    let cpu = Cpu::register(current_id()).expect("no room for the boot CPU");
+    cpu.set_lapic(init_lapic());
     unsafe {
         init_ioapic(apic);
     }
 }
 
-pub fn init_lapic(apic: &Apic) {
-    let apic_phys_addr = apic.local_apic_address;
-    let apic_virt_addr = crate::memory::PHYS_MEM_OFFSET.get().unwrap().as_u64() + apic_phys_addr;
-    crate::map_physical_to_virtual!(apic_phys_addr, apic_virt_addr);
-
-    log::trace!("mapped phys addr to virt addr");
+/// Build and enable the local APIC of the CPU this runs on
+pub fn init_lapic() -> LocalApic {
+    let apic_virt_addr = *XAPIC_BASE.try_get().unwrap();
 
     let lapic = LocalApicBuilder::new()
         .spurious_vector(InterruptIndex::ApicSpurious as usize)
         .timer_vector(InterruptIndex::Timer as usize)
         .error_vector(InterruptIndex::ApicError as usize)
-        .timer_divide(TimerDivide::Div2)
-        .timer_initial(100_000_000)
+        // stopped until `time::init` has calibrated it
+        .timer_mode(TimerMode::OneShot)
+        .timer_divide(TimerDivide::Div16)
+        .timer_initial(0)
         .set_xapic_base(apic_virt_addr)
         .build();
 
@@ -55,18 +69,57 @@ pub fn init_lapic(apic: &Apic) {
             lapic.enable();
         }
 
-        LAPIC.init_once(|| Mutex::new(lapic));
+        lapic
     } else {
         log::error!("lapic failed to build");
         hlt_loop();
     }
 }
 
+fn is_x2apic() -> bool {
+    unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_X2APIC != 0 }
+}
+
+/// Local APIC ID of the CPU this runs on, in whichever mode its LAPIC is
+pub fn current_id() -> u32 {
+    unsafe {
+        if is_x2apic() {
+            Msr::new(X2APIC_ID).read() as u32
+        } else {
+            let base = *XAPIC_BASE.try_get().unwrap();
+            core::ptr::read_volatile((base + XAPIC_ID) as *const u32) >> 24
+        }
+    }
+}
+
+/// Signal the end of an interrupt to the LAPIC of the current CPU
+pub fn end_of_interrupt() {
+    unsafe { percpu::current().lapic().lock().end_of_interrupt() }
+}
+
+/// Send interrupt `vector` to the CPU with LAPIC ID `apic_id`
+pub fn send_ipi(apic_id: u32, vector: InterruptIndex) {
+    without_interrupts(|| unsafe {
+        percpu::current().lapic().lock().send_ipi(vector as u8, apic_id);
+    });
+}
+
+/// The current count of the LAPIC timer. The `x2apic` crate has no getter,
+/// so the register is read in whichever mode the LAPIC ended up in.
+pub fn timer_current() -> u32 {
+    unsafe {
+        if is_x2apic() {
+            Msr::new(X2APIC_TIMER_CURRENT).read() as u32
+        } else {
+            let base = *XAPIC_BASE.try_get().unwrap();
+            core::ptr::read_volatile((base + XAPIC_TIMER_CURRENT) as *const u32)
+        }
+    }
+}
+
 unsafe fn init_ioapic(apic: &Apic) {
     let physical_address = apic.io_apics.get(0).unwrap().address as u64;
-    let phys_mem_offset = crate::memory::PHYS_MEM_OFFSET.try_get().unwrap();
-    let virtual_address = phys_mem_offset.as_u64() + physical_address;
-    crate::map_physical_to_virtual!(physical_address, virtual_address);
+    let virtual_address = crate::memory::map_mmio(physical_address, 4096).as_u64();
 
     let mut ioapic = IoApic::new(virtual_address);
     ioapic.init(crate::cpu::interrupts::IOAPIC_INTERRUPT_INDEX_OFFSET);
@@ -77,11 +130,11 @@ unsafe fn init_ioapic(apic: &Apic) {
 }
 
 unsafe fn ioapic_add_entry(irq: IrqVector, vector: InterruptIndex) {
-    let lapic = LAPIC.try_get().unwrap().lock();
+    // device interrupts all go to the boot CPU
     let mut io_apic = IOAPIC.try_get().unwrap().lock();
     let mut entry = RedirectionTableEntry::default();
     entry.set_mode(IrqMode::Fixed);
-    entry.set_dest(lapic.id() as u8);
+    entry.set_dest(current_id() as u8);
     entry.set_vector(vector as u8);
     entry.set_flags(IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE | IrqFlags::MASKED);
     io_apic.set_table_entry(irq as u8, entry);
@@ -38,7 +38,12 @@ impl log::Log for LockedLogger {
     }
 
     fn log(&self, record: &log::Record) {
-        let output = format!("{:5} [{}:{}] {}", record.level(), record.file().unwrap(), record.line().unwrap(), record.args());
+        let message = format!("{:5} [{}:{}] {}", record.level(), record.file().unwrap(), record.line().unwrap(), record.args());
+        // the wall clock only exists once the RTC has been read
+        let output = match crate::time::wall_clock() {
+            Some(now) => format!("{}.{:03} {}", now, now.nanosecond / 1_000_000, message),
+            None => message,
+        };
         if self.framebuffer {
             println!("{}", output);
         }
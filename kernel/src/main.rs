@@ -15,8 +15,13 @@ mod allocator;
 mod memory;
 mod task;
 mod cpu;
+mod fs;
 mod api;
 mod programs;
+mod process;
+mod syscall;
+mod time;
+mod sync;
 
 mod size;
 use crate::size::*;
@@ -67,6 +72,13 @@ fn init(boot_info: &'static BootInfo) {
     vga::init(boot_info);
     console::init(console::palette::Flat);
     init_logger();
+    time::init();
+    process::init();
+    io::pci::init();
+    io::block::init();
+    fs::init();
+    task::scheduler::init();
+    cpu::smp::init();
 }
 
 fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
@@ -84,29 +96,33 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     });*/
 
     /*
-    println!("\x1b2;1mBlue        \x1b1;1m\x1b2;0mBlue\x1b0m");
-    println!("\x1b2;2mGreen       \x1b1;2m\x1b2;0mGreen\x1b0m");
-    println!("\x1b2;3mCyan        \x1b1;3m\x1b2;0mCyan\x1b0m");
-    println!("\x1b2;4mRed         \x1b1;4m\x1b2;0mRed\x1b0m");
-    println!("\x1b2;5mMagenta     \x1b1;5m\x1b2;0mMagenta\x1b0m");
-    println!("\x1b2;6mBrown       \x1b1;6m\x1b2;0mBrown\x1b0m");
-    println!("\x1b2;7mLight Gray  \x1b1;7m\x1b2;0mLight Gray\x1b0m");
-    println!("\x1b2;8mDark Gray   \x1b1;8m\x1b2;0mDark Gray\x1b0m");
-    println!("\x1b2;9mLight Blue  \x1b1;9m\x1b2;0mLight Blue\x1b0m");
-    println!("\x1b2;amLight Green \x1b1;am\x1b2;0mLight Green\x1b0m");
-    println!("\x1b2;bmLight Cyan  \x1b1;bm\x1b2;0mLight Cyan\x1b0m");
-    println!("\x1b2;cmLight Red   \x1b1;cm\x1b2;0mLight Red\x1b0m");
-    println!("\x1b2;dmPink        \x1b1;dm\x1b2;0mPink\x1b0m");
-    println!("\x1b2;emYellow      \x1b1;em\x1b2;0mYellow\x1b0m");
-    println!("\x1b2;fmWhite       \x1b1;fm\x1b2;0mWhite\x1b0m");
+    println!("\x1b[34mBlue        \x1b[44;30mBlue\x1b[0m");
+    println!("\x1b[32mGreen       \x1b[42;30mGreen\x1b[0m");
+    println!("\x1b[36mCyan        \x1b[46;30mCyan\x1b[0m");
+    println!("\x1b[31mRed         \x1b[41;30mRed\x1b[0m");
+    println!("\x1b[35mMagenta     \x1b[45;30mMagenta\x1b[0m");
+    println!("\x1b[33mBrown       \x1b[43;30mBrown\x1b[0m");
+    println!("\x1b[37mLight Gray  \x1b[47;30mLight Gray\x1b[0m");
+    println!("\x1b[90mDark Gray   \x1b[100;30mDark Gray\x1b[0m");
+    println!("\x1b[94mLight Blue  \x1b[104;30mLight Blue\x1b[0m");
+    println!("\x1b[92mLight Green \x1b[102;30mLight Green\x1b[0m");
+    println!("\x1b[96mLight Cyan  \x1b[106;30mLight Cyan\x1b[0m");
+    println!("\x1b[91mLight Red   \x1b[101;30mLight Red\x1b[0m");
+    println!("\x1b[95mPink        \x1b[105;30mPink\x1b[0m");
+    println!("\x1b[93mYellow      \x1b[103;30mYellow\x1b[0m");
+    println!("\x1b[97mWhite       \x1b[107;30mWhite\x1b[0m");
     */
 
     //vga::char_bitmap(0, 0, 2, 0xFF_FF_FF_FF, 0x00_00_00_FF, 'A');
     //vga::rect(0, 0, 100, 100, 0x27_AE_60_80);
     //shell_executor.spawn(task::Task::new(keyboard::print_keypresses()));
-    let mut shell_executor = task::executor::Executor::new();
-    shell_executor.spawn(task::Task::new(programs::shell::main()));
-    shell_executor.run();
+    let shell_thread = task::thread::spawn("shell", || {
+        let mut shell_executor = task::executor::Executor::new();
+        task::executor::set_global(shell_executor.spawner());
+        task::executor::try_spawn(programs::shell::main()).expect("failed to spawn the shell");
+        shell_executor.run();
+    });
+    shell_thread.join();
 
     //let mut shell_executor = task::executor::Executor::new();
     //shell_executor.spawn(task::Task::new(keyboard::print_keypresses()));
@@ -0,0 +1,186 @@
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Frame counts of the physical memory manager
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Usable frames in the memory map
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Physical memory manager with one bit per frame, set while the frame is in
+/// use. Frames the memory map does not call usable are permanently set.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    total: usize,
+    used: usize,
+    /// Word to start the next search at
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Build the bitmap from the memory map. The bitmap itself is placed in
+    /// the first usable region that can hold it.
+    ///
+    /// # Safety
+    ///
+    /// Every frame marked as `Usable` in `memory_map` must really be unused
+    /// and all physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || memory_map.iter().filter(|region| region.kind == MemoryRegionKind::Usable);
+
+        let end = usable().map(|region| region.end).max().unwrap_or(0);
+        let frames = (end / FRAME_SIZE) as usize;
+        let words = frames.div_ceil(64);
+        let bitmap_bytes = (words * 8) as u64;
+
+        let bitmap_start = usable()
+            .map(|region| align_up(region.start))
+            .zip(usable().map(|region| region.end))
+            .find(|(start, end)| start + bitmap_bytes <= *end)
+            .map(|(start, _)| start)
+            .expect("no usable region can hold the frame bitmap");
+
+        let bitmap = core::slice::from_raw_parts_mut(
+            (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>(),
+            words,
+        );
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator { bitmap, total: 0, used: 0, next: 0 };
+        for region in usable() {
+            let first = align_up(region.start) / FRAME_SIZE;
+            let last = region.end / FRAME_SIZE;
+            for frame in first..last {
+                allocator.clear(frame as usize);
+                allocator.total += 1;
+            }
+        }
+
+        // frame 0 stays reserved so a null physical address is never handed out
+        if !allocator.is_used(0) {
+            allocator.set(0);
+            allocator.used += 1;
+        }
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE) as usize;
+        for frame in bitmap_first..bitmap_first + bitmap_frames {
+            allocator.set(frame);
+            allocator.used += 1;
+        }
+
+        log::info!(
+            "physical memory: {} KiB usable, frame bitmap at {:#x}",
+            allocator.total * FRAME_SIZE as usize / 1024,
+            bitmap_start,
+        );
+        allocator
+    }
+
+    fn frame_count(&self) -> usize {
+        self.bitmap.len() * 64
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    /// Allocate `count` physically contiguous frames, the first of which is
+    /// aligned to `align` frames
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_below(count, align, PhysAddr::new(self.frame_count() as u64 * FRAME_SIZE))
+    }
+
+    /// Like `allocate_contiguous`, with every frame below `limit`. Used for
+    /// memory that has to be reachable from real mode or by 32-bit DMA.
+    pub fn allocate_contiguous_below(&mut self, count: usize, align: usize, limit: PhysAddr) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+        let align = align.max(1);
+        let end = self.frame_count().min((limit.as_u64() / FRAME_SIZE) as usize);
+
+        let mut start = 0;
+        while start + count <= end {
+            match (start..start + count).rev().find(|frame| self.is_used(*frame)) {
+                // skip past the used frame, keeping the alignment
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for frame in start..start + count {
+                        self.set(frame);
+                    }
+                    self.used += count;
+                    return Some(Self::frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Free `count` frames starting at `start`, from `allocate_contiguous`
+    pub fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+        for frame in first..first + count {
+            assert!(self.is_used(frame), "double free of frame {:#x}", frame as u64 * FRAME_SIZE);
+            self.clear(frame);
+        }
+        self.used -= count;
+        self.next = self.next.min(first / 64);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            used: self.used,
+            free: self.total - self.used,
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        for offset in 0..words {
+            let word = (self.next + offset) % words;
+            let bits = self.bitmap[word];
+            if bits == u64::MAX {
+                continue;
+            }
+            let frame = word * 64 + bits.trailing_ones() as usize;
+            self.set(frame);
+            self.used += 1;
+            self.next = word;
+            return Some(Self::frame_at(frame));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1);
+    }
+}
+
+unsafe impl Send for BitmapFrameAllocator {}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
@@ -1,3 +1,6 @@
+pub mod frame;
+pub mod vma;
+
 use bootloader_api::BootInfo;
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
@@ -6,9 +9,20 @@ use x86_64::{
     VirtAddr,
 };
 
+use self::frame::{BitmapFrameAllocator, FrameStats};
+use self::vma::{Backing, Protection, Vma, VmaTree};
+
 pub static PHYS_MEM_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
-pub static FRAME_ALLOCATOR: OnceCell<Mutex<BootInfoFrameAllocator>> = OnceCell::uninit();
+pub static FRAME_ALLOCATOR: OnceCell<Mutex<BitmapFrameAllocator>> = OnceCell::uninit();
 pub static MAPPER: OnceCell<Mutex<OffsetPageTable>> = OnceCell::uninit();
+/// Regions of the kernel half that were mapped at run time
+pub static KERNEL_VMAS: Mutex<VmaTree> = Mutex::new(VmaTree::new());
+/// Low memory set aside for starting the other CPUs, which begin in real
+/// mode. Reserved first thing, before other allocations use it up.
+pub static AP_TRAMPOLINE: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// Frames in `AP_TRAMPOLINE`
+pub const AP_TRAMPOLINE_FRAMES: usize = 2;
 
 /// Returns a mutable reference to the active level 4 table.
 ///
@@ -106,7 +120,11 @@ pub fn init(boot_info: &'static BootInfo) {
     unsafe {
         let page_table = active_level_4_table(phys_mem_offset);
         let mapper = OffsetPageTable::new(page_table, phys_mem_offset);
-        let frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_regions);
+        let mut frame_allocator = BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset);
+        // a startup IPI can only point below 1 MiB
+        if let Some(frame) = frame_allocator.allocate_contiguous_below(AP_TRAMPOLINE_FRAMES, 1, PhysAddr::new(0x10_0000)) {
+            AP_TRAMPOLINE.init_once(|| frame);
+        }
         MAPPER.init_once(|| Mutex::new(mapper));
         FRAME_ALLOCATOR.init_once(|| Mutex::new(frame_allocator));
         PHYS_MEM_OFFSET.init_once(|| phys_mem_offset);
@@ -141,79 +159,87 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
-use bootloader_api::info::MemoryRegions;
-use bootloader_api::info::MemoryRegionKind;
-
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
-}
+/// Device registers are mapped from here, one of the ranges
+/// `address_space::init_kernel_half` prepares. The physical memory mapping
+/// cannot be used, it is cached and built from huge pages.
+pub const MMIO_START: u64 = 0x_4470_0000_0000;
+pub const MMIO_END: u64 = 0x_4480_0000_0000;
+
+/// Map device registers at `phys_addr` with caching disabled and return
+/// their virtual address. Each range is recorded as a region of the kernel
+/// address space, registers that were mapped before are mapped only once.
+pub fn map_mmio(phys_addr: u64, size: u64) -> VirtAddr {
+    let phys_start = PhysAddr::new(phys_addr).align_down(4096u64);
+    let phys_end = PhysAddr::new(phys_addr + size.max(1)).align_up(4096u64);
+
+    let vma = {
+        let mut vmas = KERNEL_VMAS.lock();
+        let mapped = vmas.iter().find_map(|vma| match vma.backing {
+            Backing::Physical { start, uncached: true } if start <= phys_start && phys_end <= start + vma.size() => {
+                Some(vma.start + (phys_addr - start.as_u64()))
+            }
+            _ => None,
+        });
+        if let Some(virt_addr) = mapped {
+            return virt_addr;
+        }
 
-#[macro_export]
-macro_rules! map_physical_to_virtual {
-    ($phys_addr:expr, $virt_addr:expr) => {
-        use x86_64::{PhysAddr, VirtAddr};
-        use x86_64::structures::paging::{Mapper, mapper::MapToError};
-        use x86_64::structures::paging::{Page, Size4KiB, PageTableFlags, PhysFrame};
-        let result = unsafe {
-            $crate::memory::MAPPER.try_get().unwrap().lock().map_to(
-                Page::<Size4KiB>::containing_address(VirtAddr::new($virt_addr)),
-                PhysFrame::containing_address(PhysAddr::new($phys_addr)),
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                &mut *$crate::memory::FRAME_ALLOCATOR.try_get().unwrap().lock(),
-            )
-        };
-        match result {
-            Ok(flush) => flush.flush(),
-            Err(err) => match err {
-                MapToError::FrameAllocationFailed => {
-                    panic!("Failed to allocate frame!");
-                }
-                MapToError::PageAlreadyMapped(frame) => {
-                    log::debug!("Already mapped to frame: {:?}", frame);
-                }
-                MapToError::ParentEntryHugePage => {
-                    log::debug!("Already mapped to huge page!");
-                }
-            },
-        };
+        let size = phys_end - phys_start;
+        let start = vmas.find_free(VirtAddr::new(MMIO_START), size, VirtAddr::new(MMIO_END))
+            .expect("no room left to map MMIO");
+        let vma = Vma::new(
+            start,
+            start + size,
+            Protection::KERNEL_DATA,
+            Backing::Physical { start: phys_start, uncached: true },
+        );
+        vmas.insert(vma).expect("MMIO regions overlap");
+        vma
     };
-}
 
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+    let flags = vma.page_flags();
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+    let pages = Page::<Size4KiB>::range(Page::containing_address(vma.start), Page::containing_address(vma.end));
+    for page in pages {
+        let frame = PhysFrame::containing_address(vma.physical_address(page.start_address()).unwrap());
+        match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => panic!("mapping MMIO at {:#x} failed: {:?}", phys_addr, err),
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    vma.start + (phys_addr - phys_start.as_u64())
+}
+
+/// Where physical memory at `addr` can be reached
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    *PHYS_MEM_OFFSET.try_get().unwrap() + addr.as_u64()
+}
+
+/// Zeroed, physically contiguous memory for devices to DMA into
+pub fn allocate_dma(pages: usize) -> Option<PhysFrame> {
+    let start = FRAME_ALLOCATOR.try_get().unwrap().lock().allocate_contiguous(pages, 1)?;
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(start.start_address()).as_mut_ptr::<u8>(), 0, pages * 4096);
     }
+    Some(start)
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+/// Like `allocate_dma`, for devices that can only reach memory below `limit`
+pub fn allocate_dma_below(pages: usize, limit: PhysAddr) -> Option<PhysFrame> {
+    let start = FRAME_ALLOCATOR.try_get().unwrap().lock().allocate_contiguous_below(pages, 1, limit)?;
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(start.start_address()).as_mut_ptr::<u8>(), 0, pages * 4096);
     }
+    Some(start)
 }
 
-unsafe impl Send for BootInfoFrameAllocator {}
\ No newline at end of file
+/// Give memory from `allocate_dma` back
+pub fn free_dma(start: PhysFrame, pages: usize) {
+    FRAME_ALLOCATOR.try_get().unwrap().lock().deallocate_contiguous(start, pages);
+}
+
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.try_get().unwrap().lock().stats()
+}
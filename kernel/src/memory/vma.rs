@@ -0,0 +1,253 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Who may do what with a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// Reachable from ring 3
+    pub user: bool,
+}
+
+impl Protection {
+    pub const NONE: Protection = Protection { read: false, write: false, execute: false, user: false };
+    pub const KERNEL_DATA: Protection = Protection { read: true, write: true, execute: false, user: false };
+    pub const USER_DATA: Protection = Protection { read: true, write: true, execute: false, user: true };
+
+    /// From the `PROT_*` bits of `mmap` and `mprotect`
+    pub fn from_prot(prot: u64) -> Protection {
+        use crate::api::syscall::{PROT_EXEC, PROT_READ, PROT_WRITE};
+        Protection {
+            read: prot & PROT_READ != 0,
+            write: prot & PROT_WRITE != 0,
+            execute: prot & PROT_EXEC != 0,
+            user: true,
+        }
+    }
+
+    /// Whether the region can be touched at all
+    pub fn is_accessible(&self) -> bool {
+        self.read || self.write || self.execute
+    }
+
+    /// Allows everything either of the two allows
+    pub fn union(self, other: Protection) -> Protection {
+        Protection {
+            read: self.read || other.read,
+            write: self.write || other.write,
+            execute: self.execute || other.execute,
+            user: self.user || other.user,
+        }
+    }
+
+    /// Page table flags for pages of the region. x86 cannot map a page
+    /// without read access, so inaccessible user pages lose `USER_ACCESSIBLE`
+    /// instead and stay present to keep their frame.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.write {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if self.user && self.is_accessible() {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        flags
+    }
+}
+
+/// What backs the pages of a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames owned by the region, allocated on first touch and freed
+    /// on unmap
+    Anonymous,
+    /// Fixed physical memory such as device registers, never freed
+    Physical { start: PhysAddr, uncached: bool },
+}
+
+/// A virtual memory area: a page aligned range with one protection and
+/// backing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub protection: Protection,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn new(start: VirtAddr, end: VirtAddr, protection: Protection, backing: Backing) -> Vma {
+        debug_assert!(start.is_aligned(4096u64) && end.is_aligned(4096u64) && start < end);
+        Vma { start, end, protection, backing }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = self.protection.page_flags();
+        if let Backing::Physical { uncached: true, .. } = self.backing {
+            flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        }
+        flags
+    }
+
+    /// Physical address backing `addr`, for physically backed regions
+    pub fn physical_address(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        match self.backing {
+            Backing::Physical { start, .. } => Some(start + (addr - self.start)),
+            Backing::Anonymous => None,
+        }
+    }
+
+    /// The part of the region in `[start, end)`, keeping the physical
+    /// backing in step with the virtual start
+    fn clip(&self, start: VirtAddr, end: VirtAddr) -> Vma {
+        let start = start.max(self.start);
+        let end = end.min(self.end);
+        let backing = match self.backing {
+            Backing::Physical { start: phys, uncached } => Backing::Physical {
+                start: phys + (start - self.start),
+                uncached,
+            },
+            Backing::Anonymous => Backing::Anonymous,
+        };
+        Vma { start, end, protection: self.protection, backing }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    Overlap,
+    NotMapped,
+    Unaligned,
+}
+
+/// The regions of one address space, keyed by start address. Regions never
+/// overlap.
+#[derive(Debug, Default)]
+pub struct VmaTree {
+    areas: BTreeMap<VirtAddr, Vma>,
+}
+
+impl VmaTree {
+    pub const fn new() -> VmaTree {
+        VmaTree { areas: BTreeMap::new() }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// The region containing `addr`
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas.range(..=addr).next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Regions overlapping `[start, end)`, in address order
+    pub fn overlapping(&self, start: VirtAddr, end: VirtAddr) -> impl Iterator<Item = &Vma> {
+        let first = self.find(start).map(|vma| vma.start).unwrap_or(start);
+        self.areas.range(first..end).map(|(_, vma)| vma).filter(move |vma| vma.end > start)
+    }
+
+    /// Whether `[start, end)` is covered by regions without gaps
+    pub fn covers(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let mut cursor = start;
+        for vma in self.overlapping(start, end) {
+            if vma.start > cursor {
+                return false;
+            }
+            cursor = vma.end;
+        }
+        cursor >= end
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if self.overlapping(vma.start, vma.end).next().is_some() {
+            return Err(VmaError::Overlap);
+        }
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// Insert `vma`, where it overlaps existing regions the overlap gets
+    /// the union of both protections. Used where two users share pages,
+    /// e.g. ELF segments that meet in the middle of a page.
+    pub fn insert_merged(&mut self, vma: Vma) {
+        let mut cursor = vma.start;
+        for old in self.remove_range(vma.start, vma.end) {
+            if old.start > cursor {
+                self.areas.insert(cursor, vma.clip(cursor, old.start));
+            }
+            let mut merged = vma.clip(old.start, old.end);
+            merged.protection = merged.protection.union(old.protection);
+            self.areas.insert(merged.start, merged);
+            cursor = old.end;
+        }
+        if cursor < vma.end {
+            self.areas.insert(cursor, vma.clip(cursor, vma.end));
+        }
+    }
+
+    /// Take `[start, end)` out of the tree, splitting regions that only
+    /// partly overlap. Returns the removed parts.
+    pub fn remove_range(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        let hit: Vec<Vma> = self.overlapping(start, end).copied().collect();
+        let mut removed = Vec::with_capacity(hit.len());
+        for vma in hit {
+            self.areas.remove(&vma.start);
+            if vma.start < start {
+                self.areas.insert(vma.start, vma.clip(vma.start, start));
+            }
+            if vma.end > end {
+                self.areas.insert(end, vma.clip(end, vma.end));
+            }
+            removed.push(vma.clip(start, end));
+        }
+        removed
+    }
+
+    /// Change the protection of `[start, end)`, which has to be fully
+    /// mapped. Returns the changed parts with their new protection.
+    pub fn protect(&mut self, start: VirtAddr, end: VirtAddr, protection: Protection)
+        -> Result<Vec<Vma>, VmaError>
+    {
+        if !self.covers(start, end) {
+            return Err(VmaError::NotMapped);
+        }
+        let mut changed = self.remove_range(start, end);
+        for vma in changed.iter_mut() {
+            vma.protection = protection;
+            self.areas.insert(vma.start, *vma);
+        }
+        Ok(changed)
+    }
+
+    /// Lowest free, page aligned range of `size` bytes in `[from, limit)`
+    /// that leaves an unmapped page on both sides
+    pub fn find_free(&self, from: VirtAddr, size: u64, limit: VirtAddr) -> Option<VirtAddr> {
+        let size = size.next_multiple_of(4096);
+        let mut candidate = from.align_up(4096u64);
+        for vma in self.overlapping(from, limit) {
+            if candidate + size + 4096u64 <= vma.start {
+                return Some(candidate);
+            }
+            candidate = candidate.max(vma.end + 4096u64);
+        }
+        (candidate + size <= limit).then_some(candidate)
+    }
+}
@@ -0,0 +1,433 @@
+use conquer_once::spin::OnceCell;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::memory::vma::{Backing, Protection, Vma, VmaError, VmaTree};
+use crate::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use crate::memory::{FRAME_ALLOCATOR, MAPPER, MMIO_END, MMIO_START, PHYS_MEM_OFFSET};
+use crate::task::stack::{STACKS_END, STACKS_START};
+
+/// Lowest user space address, everything below belongs to the kernel
+pub const USER_START: u64 = 0x0000_6000_0000_0000;
+/// One past the highest user space address (end of the lower half)
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// One past the highest address that is ever mapped for a process. The last
+/// page stays empty so no instruction can run up to the non-canonical hole
+/// and leave `syscall` with a return address `sysretq` cannot take.
+pub const USER_TOP: u64 = USER_END - 0x1000;
+
+/// Where flat program images are loaded
+pub const USER_CODE_START: u64 = USER_START + 0x40_0000;
+/// Anonymous memory handed out by `mmap` starts here
+pub const USER_MMAP_START: u64 = USER_START + 0x1000_0000_0000;
+/// The user stack grows down from here
+pub const USER_STACK_TOP: u64 = USER_TOP;
+pub const USER_STACK_SIZE: u64 = 0x10_0000;
+
+const USER_P4_START: usize = (USER_START >> 39) as usize;
+const USER_P4_END: usize = (USER_END >> 39) as usize;
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    FrameAllocationFailed,
+    NotUserAddress,
+    AlreadyMapped,
+    /// Part of the range has no region
+    NotMapped,
+    Unaligned,
+}
+
+impl From<VmaError> for AddressSpaceError {
+    fn from(err: VmaError) -> Self {
+        match err {
+            VmaError::Overlap => AddressSpaceError::AlreadyMapped,
+            VmaError::NotMapped => AddressSpaceError::NotMapped,
+            VmaError::Unaligned => AddressSpaceError::Unaligned,
+        }
+    }
+}
+
+/// A level 4 page table that shares every kernel mapping with the active
+/// kernel table and keeps the user range private. The regions of the user
+/// range are tracked in a VMA tree, page tables follow it.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    vmas: VmaTree,
+}
+
+impl AddressSpace {
+    /// The page table the kernel booted with
+    pub fn kernel_frame() -> PhysFrame {
+        *KERNEL_FRAME.get_or_init(|| Cr3::read().0)
+    }
+
+    pub fn new_user() -> Result<AddressSpace, AddressSpaceError> {
+        let frame = FRAME_ALLOCATOR.try_get().unwrap().lock()
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+
+        let table = unsafe { &mut *table_ptr(frame) };
+        table.zero();
+
+        let kernel_table = unsafe { &*table_ptr(Self::kernel_frame()) };
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if entry.is_unused() {
+                continue;
+            }
+            if (USER_P4_START..USER_P4_END).contains(&index) {
+                panic!("kernel mapping in user space (level 4 entry {})", index);
+            }
+            table[index] = entry.clone();
+        }
+
+        Ok(AddressSpace { level_4_frame: frame, vmas: VmaTree::new() })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn vmas(&self) -> &VmaTree {
+        &self.vmas
+    }
+
+    /// # Safety
+    ///
+    /// The caller must not create two mappers for the same address space at
+    /// the same time.
+    pub unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        let offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+        OffsetPageTable::new(&mut *table_ptr(self.level_4_frame), offset)
+    }
+
+    /// Back `[start, start + size)` with fresh zeroed frames right away.
+    /// Pages that are already mapped keep their contents and gain the rights
+    /// of `protection`.
+    pub fn map_user(&mut self, start: VirtAddr, size: u64, protection: Protection)
+        -> Result<(), AddressSpaceError>
+    {
+        let (start, end) = user_pages(start, size)?;
+        if start == end {
+            return Ok(());
+        }
+
+        self.vmas.insert_merged(Vma::new(start, end, protection, Backing::Anonymous));
+        for page in Page::<Size4KiB>::range(Page::containing_address(start), Page::containing_address(end)) {
+            self.populate(page)?;
+        }
+        Ok(())
+    }
+
+    /// Add an anonymous region that is backed on first touch, see
+    /// `handle_fault`. With `addr` set the region goes exactly there and
+    /// replaces whatever was mapped, otherwise the first free range above
+    /// `USER_MMAP_START` is used.
+    pub fn mmap(&mut self, addr: Option<VirtAddr>, size: u64, protection: Protection)
+        -> Result<VirtAddr, AddressSpaceError>
+    {
+        if size == 0 {
+            return Err(AddressSpaceError::NotMapped);
+        }
+        let start = match addr {
+            Some(addr) if !addr.is_aligned(4096u64) => return Err(AddressSpaceError::Unaligned),
+            Some(addr) => addr,
+            None => self.vmas
+                .find_free(VirtAddr::new(USER_MMAP_START), size, VirtAddr::new(USER_TOP))
+                .ok_or(AddressSpaceError::FrameAllocationFailed)?,
+        };
+        let (start, end) = user_pages(start, size)?;
+
+        self.munmap(start, end - start)?;
+        self.vmas.insert(Vma::new(start, end, protection, Backing::Anonymous))?;
+        Ok(start)
+    }
+
+    /// Remove the regions in `[start, start + size)` and their mappings.
+    /// Anonymous frames go back to the frame allocator.
+    pub fn munmap(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        if !start.is_aligned(4096u64) {
+            return Err(AddressSpaceError::Unaligned);
+        }
+        let (start, end) = user_pages(start, size)?;
+
+        for vma in self.vmas.remove_range(start, end) {
+            self.unmap_pages(&vma);
+        }
+        Ok(())
+    }
+
+    /// Change the rights of `[start, start + size)`, which has to be
+    /// covered by regions
+    pub fn mprotect(&mut self, start: VirtAddr, size: u64, protection: Protection)
+        -> Result<(), AddressSpaceError>
+    {
+        if !start.is_aligned(4096u64) {
+            return Err(AddressSpaceError::Unaligned);
+        }
+        let (start, end) = user_pages(start, size)?;
+
+        let changed = self.vmas.protect(start, end, protection)?;
+        let mut mapper = unsafe { self.mapper() };
+        for vma in changed {
+            for page in vma_pages(&vma) {
+                // pages that were never touched pick the flags up when they are
+                if let Ok(flush) = unsafe { mapper.update_flags(page, vma.page_flags()) } {
+                    flush.flush();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Back the page containing `addr` if a region allows the access.
+    /// Returns false if there is no region or it forbids the access.
+    pub fn handle_fault(&mut self, addr: VirtAddr, write: bool) -> Result<bool, AddressSpaceError> {
+        let Some(vma) = self.vmas.find(addr) else {
+            return Ok(false);
+        };
+        let protection = vma.protection;
+        if !protection.is_accessible() || (write && !protection.write) {
+            return Ok(false);
+        }
+        self.populate(Page::containing_address(addr))?;
+        Ok(true)
+    }
+
+    /// Make `page` present with the flags of its region, mapping a zeroed
+    /// frame or the region's physical memory if it is not mapped yet
+    fn populate(&mut self, page: Page<Size4KiB>) -> Result<(), AddressSpaceError> {
+        let vma = *self.vmas.find(page.start_address()).ok_or(AddressSpaceError::NotMapped)?;
+        let flags = vma.page_flags();
+
+        let mut mapper = unsafe { self.mapper() };
+        if let TranslateResult::Mapped { .. } = mapper.translate(page.start_address()) {
+            unsafe {
+                mapper.update_flags(page, flags)
+                    .map_err(|_| AddressSpaceError::AlreadyMapped)?
+                    .flush();
+            }
+            return Ok(());
+        }
+
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        let frame = match vma.physical_address(page.start_address()) {
+            Some(phys) => PhysFrame::containing_address(phys),
+            None => {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+                unsafe { core::ptr::write_bytes(frame_ptr(frame), 0, 4096) };
+                frame
+            }
+        };
+        unsafe {
+            mapper.map_to(page, frame, flags, &mut *frame_allocator)
+                .map_err(|_| AddressSpaceError::AlreadyMapped)?
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// Unmap the pages of a region that was taken out of the tree
+    fn unmap_pages(&mut self, vma: &Vma) {
+        let mut mapper = unsafe { self.mapper() };
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        for page in vma_pages(vma) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                if vma.backing == Backing::Anonymous {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        }
+    }
+
+    /// Copy `data` into user memory starting at `start`, backing untouched
+    /// pages on the way. Ignores the protection, it is used to load images.
+    pub fn write_user(&mut self, start: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        if !is_user_range(start, data.len() as u64) {
+            return Err(AddressSpaceError::NotUserAddress);
+        }
+
+        let mut written = 0;
+        while written < data.len() {
+            let addr = start + written;
+            let phys = match unsafe { self.mapper() }.translate_addr(addr) {
+                Some(phys) => phys,
+                None => {
+                    self.populate(Page::containing_address(addr))?;
+                    unsafe { self.mapper() }
+                        .translate_addr(addr)
+                        .ok_or(AddressSpaceError::NotUserAddress)?
+                }
+            };
+            let chunk = core::cmp::min(data.len() - written, 4096 - (addr.as_u64() as usize & 0xFFF));
+            let offset = PHYS_MEM_OFFSET.try_get().unwrap().as_u64();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    (offset + phys.as_u64()) as *mut u8,
+                    chunk,
+                );
+            }
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    /// Check that `[start, start + size)` is covered by regions that allow
+    /// user reads (and writes if `write` is set). Untouched pages count, the
+    /// fault handler backs them when the kernel gets to them.
+    pub fn is_accessible(&self, start: VirtAddr, size: u64, write: bool) -> bool {
+        if !is_user_range(start, size) {
+            return false;
+        }
+        if size == 0 {
+            return true;
+        }
+
+        let (start, end) = (start.align_down(4096u64), (start + size).align_up(4096u64));
+        self.vmas.covers(start, end)
+            && self.vmas.overlapping(start, end).all(|vma| {
+                let protection = vma.protection;
+                protection.user && protection.read && (!write || protection.write)
+            })
+    }
+
+    /// Load this address space into CR3
+    pub fn activate(&self) {
+        switch_to(self.level_4_frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Free every user page, the page tables of the user range and the level
+    /// 4 table. Kernel tables are shared and stay.
+    fn drop(&mut self) {
+        assert!(Cr3::read().0 != self.level_4_frame, "dropping the active address space");
+
+        // physical memory is not ours to free, take it out before the sweep
+        let physical: alloc::vec::Vec<Vma> = self.vmas.iter()
+            .filter(|vma| vma.backing != Backing::Anonymous)
+            .copied()
+            .collect();
+        for vma in physical {
+            self.unmap_pages(&vma);
+        }
+
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        let level_4 = unsafe { &mut *table_ptr(self.level_4_frame) };
+        for entry in level_4.iter_mut().take(USER_P4_END).skip(USER_P4_START) {
+            if let Ok(frame) = entry.frame() {
+                unsafe { free_table(frame, 3, &mut *frame_allocator) };
+            }
+            entry.set_unused();
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// Free a page table at `level` (3 to 1), everything it maps and the table
+/// itself
+unsafe fn free_table(table_frame: PhysFrame, level: u8, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let table = &*table_ptr(table_frame);
+    for entry in table.iter() {
+        if let Ok(frame) = entry.frame() {
+            if level > 1 {
+                free_table(frame, level - 1, frame_allocator);
+            } else {
+                frame_allocator.deallocate_frame(frame);
+            }
+        }
+    }
+    frame_allocator.deallocate_frame(table_frame);
+}
+
+static KERNEL_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// Kernel ranges that are mapped after boot. Everything else the kernel
+/// uses, its image and the physical memory mapping, is in place before the
+/// first address space is made.
+const KERNEL_GROWTH: [(u64, u64); 3] = [
+    (HEAP_START as u64, (HEAP_START + HEAP_MAX_SIZE) as u64),
+    (STACKS_START, STACKS_END),
+    (MMIO_START, MMIO_END),
+];
+
+/// Give the level 4 entries of the kernel ranges mapped after boot a level 3
+/// table. Each address space copies the kernel entries once, so a kernel
+/// mapping is only seen by all of them if its entry existed before the first
+/// one was made, and nothing may be mapped outside `KERNEL_GROWTH` later.
+pub fn init_kernel_half() {
+    let table = unsafe { &mut *table_ptr(AddressSpace::kernel_frame()) };
+    let _mapper = MAPPER.try_get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+    let mut added = 0;
+    for (start, end) in KERNEL_GROWTH {
+        for index in (start >> 39) as usize..=((end - 1) >> 39) as usize {
+            let entry = &mut table[index];
+            if !entry.is_unused() {
+                continue;
+            }
+            let frame = frame_allocator.allocate_frame().expect("no memory for the kernel page tables");
+            unsafe { (*table_ptr(frame)).zero() };
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            added += 1;
+        }
+    }
+    log::debug!("allocated {} kernel level 3 tables", added);
+}
+
+/// Page aligned bounds of `[start, start + size)`, which has to be user memory
+fn user_pages(start: VirtAddr, size: u64) -> Result<(VirtAddr, VirtAddr), AddressSpaceError> {
+    if !is_user_range(start, size) {
+        return Err(AddressSpaceError::NotUserAddress);
+    }
+    let end = (start + size).align_up(4096u64);
+    if end.as_u64() > USER_TOP {
+        return Err(AddressSpaceError::NotUserAddress);
+    }
+    Ok((start.align_down(4096u64), end))
+}
+
+fn vma_pages(vma: &Vma) -> PageRange<Size4KiB> {
+    Page::range(Page::containing_address(vma.start), Page::containing_address(vma.end))
+}
+
+/// Whether `addr` is in the unmapped page below the user stack
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    let bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    (bottom - 0x1000..bottom).contains(&addr.as_u64())
+}
+
+/// Whether `[start, start + size)` lies where process memory can be mapped
+pub fn is_user_range(start: VirtAddr, size: u64) -> bool {
+    let start = start.as_u64();
+    match start.checked_add(size) {
+        Some(end) => start >= USER_START && end <= USER_TOP,
+        None => false,
+    }
+}
+
+/// Switch to the given level 4 table unless it is already active
+pub fn switch_to(level_4_frame: PhysFrame) {
+    let (current, _) = Cr3::read();
+    if current != level_4_frame {
+        unsafe { Cr3::write(level_4_frame, Cr3Flags::empty()) };
+    }
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    let offset = PHYS_MEM_OFFSET.try_get().unwrap();
+    (*offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    frame_ptr(frame) as *mut PageTable
+}
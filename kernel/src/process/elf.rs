@@ -0,0 +1,277 @@
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use super::address_space::{self, AddressSpace, AddressSpaceError, USER_CODE_START};
+use crate::memory::vma::Protection;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+// auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotX86_64,
+    NotExecutable,
+    /// Dynamically linked programs need an interpreter we don't have
+    Interpreter,
+    /// A fixed position program linked below user space, such as at the
+    /// usual 0x400000. Link it with `user.ld` or as a static PIE.
+    OutsideUserSpace,
+    BadSegment,
+    Map(AddressSpaceError),
+}
+
+impl From<AddressSpaceError> for ElfError {
+    fn from(err: AddressSpaceError) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    fn protection(&self) -> Protection {
+        Protection {
+            read: true,
+            write: self.flags & PF_W != 0,
+            execute: self.flags & PF_X != 0,
+            user: true,
+        }
+    }
+}
+
+/// The parts of an ELF64 file the loader cares about
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub kind: u16,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    /// `e_phentsize`, at least `PROGRAM_HEADER_SIZE`
+    pub program_header_size: usize,
+    pub program_headers: Vec<ProgramHeader>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(ElfError::NotElf64);
+        }
+
+        let kind = read_u16(data, 16);
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::NotX86_64);
+        }
+
+        let entry = read_u64(data, 24);
+        let program_header_offset = read_u64(data, 32);
+        let entry_size = read_u16(data, 54) as usize;
+        let entry_count = read_u16(data, 56) as usize;
+
+        if entry_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadSegment);
+        }
+        let table_end = (program_header_offset as usize)
+            .checked_add(entry_size * entry_count)
+            .ok_or(ElfError::TooShort)?;
+        if table_end > data.len() {
+            return Err(ElfError::TooShort);
+        }
+
+        let program_headers = (0..entry_count)
+            .map(|i| {
+                let base = program_header_offset as usize + i * entry_size;
+                ProgramHeader {
+                    kind: read_u32(data, base),
+                    flags: read_u32(data, base + 4),
+                    offset: read_u64(data, base + 8),
+                    vaddr: read_u64(data, base + 16),
+                    file_size: read_u64(data, base + 32),
+                    mem_size: read_u64(data, base + 40),
+                }
+            })
+            .collect();
+
+        Ok(Elf {
+            data,
+            kind,
+            entry,
+            program_header_offset,
+            program_header_size: entry_size,
+            program_headers,
+        })
+    }
+
+    fn load_segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers.iter().filter(|ph| ph.kind == PT_LOAD)
+    }
+}
+
+/// Where an image ended up after loading
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    pub program_headers: VirtAddr,
+    pub program_header_size: usize,
+    pub program_header_count: usize,
+}
+
+/// Map every PT_LOAD segment of `elf` into `space`. User space starts at
+/// `USER_START`, far above where static executables are normally linked,
+/// so fixed position programs have to be linked with `user.ld`. Position
+/// independent ones are moved to `USER_CODE_START`.
+pub fn load(space: &mut AddressSpace, elf: &Elf) -> Result<LoadedImage, ElfError> {
+    if elf.program_headers.iter().any(|ph| ph.kind == PT_INTERP) {
+        return Err(ElfError::Interpreter);
+    }
+
+    // position independent executables are placed at the start of user code
+    let bias = if elf.kind == ET_DYN { USER_CODE_START } else { 0 };
+
+    for segment in elf.load_segments() {
+        if segment.file_size > segment.mem_size {
+            return Err(ElfError::BadSegment);
+        }
+        let file_end = segment.offset.checked_add(segment.file_size).ok_or(ElfError::BadSegment)?;
+        if file_end as usize > elf.data.len() {
+            return Err(ElfError::TooShort);
+        }
+
+        let start = segment.vaddr.checked_add(bias).ok_or(ElfError::BadSegment)?;
+        if !address_space::is_user_range(VirtAddr::try_new(start).map_err(|_| ElfError::BadSegment)?, segment.mem_size) {
+            return match elf.kind {
+                ET_EXEC => Err(ElfError::OutsideUserSpace),
+                _ => Err(ElfError::Map(AddressSpaceError::NotUserAddress)),
+            };
+        }
+
+        space.map_user(VirtAddr::new(start), segment.mem_size, segment.protection())?;
+        let contents = &elf.data[segment.offset as usize..file_end as usize];
+        space.write_user(VirtAddr::new(start), contents)?;
+    }
+
+    // every segment was checked above, but PT_PHDR and the entry point
+    // are not and may point anywhere
+    let program_headers = match elf.program_headers.iter().find(|ph| ph.kind == PT_PHDR) {
+        Some(phdr) => phdr.vaddr.checked_add(bias).ok_or(ElfError::BadSegment)?,
+        None => elf.load_segments()
+            .find(|ph| ph.offset <= elf.program_header_offset
+                && elf.program_header_offset - ph.offset < ph.file_size)
+            .map(|ph| ph.vaddr.checked_add(bias + (elf.program_header_offset - ph.offset)).ok_or(ElfError::BadSegment))
+            .transpose()?
+            .unwrap_or(0),
+    };
+    let entry = elf.entry.checked_add(bias).ok_or(ElfError::BadSegment)?;
+
+    Ok(LoadedImage {
+        entry: VirtAddr::try_new(entry).map_err(|_| ElfError::BadSegment)?,
+        program_headers: VirtAddr::new_truncate(program_headers),
+        program_header_size: elf.program_header_size,
+        program_header_count: elf.program_headers.len(),
+    })
+}
+
+/// Build the System V initial stack (argc, argv, envp, auxv and the strings
+/// they point to) below `stack_top` and return the new stack pointer.
+pub fn setup_stack(
+    space: &mut AddressSpace,
+    stack_top: VirtAddr,
+    image: &LoadedImage,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    // strings go right below the top of the stack
+    let mut strings: Vec<u8> = Vec::new();
+    let mut string_offsets = Vec::new();
+    for s in argv.iter().chain(envp.iter()) {
+        string_offsets.push(strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = (stack_top.as_u64() - strings.len() as u64) & !0xF;
+
+    let auxv = [
+        (AT_PHDR, image.program_headers.as_u64()),
+        (AT_PHENT, image.program_header_size as u64),
+        (AT_PHNUM, image.program_header_count as u64),
+        (AT_PAGESZ, 4096),
+        (AT_ENTRY, image.entry.as_u64()),
+        (AT_NULL, 0),
+    ];
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    for offset in &string_offsets[..argv.len()] {
+        words.push(strings_start + offset);
+    }
+    words.push(0);
+    for offset in &string_offsets[argv.len()..] {
+        words.push(strings_start + offset);
+    }
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+
+    // rsp has to be 16 byte aligned when the entry point is reached
+    let mut rsp = strings_start - (words.len() as u64 * 8);
+    rsp &= !0xF;
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.write_user(VirtAddr::new(strings_start), &strings)?;
+    space.write_user(VirtAddr::new(rsp), &bytes)?;
+
+    Ok(VirtAddr::new(rsp))
+}
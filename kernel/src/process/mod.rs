@@ -0,0 +1,199 @@
+pub mod address_space;
+pub mod elf;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use address_space::{
+    AddressSpace, AddressSpaceError, USER_CODE_START, USER_STACK_SIZE, USER_STACK_TOP,
+};
+use crate::fs::File;
+use crate::memory::vma::Protection;
+use crate::task::thread::{self, JoinHandle};
+
+pub static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    address_space: AddressSpace,
+    files: BTreeMap<u64, Box<dyn File>>,
+    next_fd: u64,
+}
+
+impl Process {
+    pub fn new(name: &str) -> Result<Process, AddressSpaceError> {
+        Ok(Process {
+            pid: Pid::new(),
+            name: String::from(name),
+            address_space: AddressSpace::new_user()?,
+            files: BTreeMap::new(),
+            next_fd: 3, // 0, 1 and 2 are the console
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    pub fn add_file(&mut self, file: Box<dyn File>) -> u64 {
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        fd
+    }
+
+    /// Take an open file out of the table, e.g. to use it without holding
+    /// the process lock. Put it back with `return_file`.
+    pub fn take_file(&mut self, fd: u64) -> Option<Box<dyn File>> {
+        self.files.remove(&fd)
+    }
+
+    pub fn return_file(&mut self, fd: u64, file: Box<dyn File>) {
+        self.files.insert(fd, file);
+    }
+}
+
+pub fn init() {
+    // remember the boot page table before any process can switch away from it
+    let kernel = AddressSpace::kernel_frame();
+    log::debug!("kernel page table at {:?}", kernel.start_address());
+    address_space::init_kernel_half();
+}
+
+/// Load a flat binary at `USER_CODE_START` and run it in ring 3
+pub fn spawn_flat(name: &str, image: &[u8]) -> Result<JoinHandle, AddressSpaceError> {
+    let mut process = Process::new(name)?;
+
+    let code_start = VirtAddr::new(USER_CODE_START);
+    let space = process.address_space_mut();
+    space.map_user(
+        code_start,
+        image.len() as u64,
+        Protection { execute: true, ..Protection::USER_DATA },
+    )?;
+    space.write_user(code_start, image)?;
+    space.mmap(
+        Some(VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE)),
+        USER_STACK_SIZE,
+        Protection::USER_DATA,
+    )?;
+
+    Ok(start(process, code_start, VirtAddr::new(USER_STACK_TOP)))
+}
+
+/// Load an ELF64 executable and run it in ring 3 with the given arguments
+pub fn spawn_elf(name: &str, data: &[u8], argv: &[&str], envp: &[&str])
+    -> Result<JoinHandle, elf::ElfError>
+{
+    let elf = elf::Elf::parse(data)?;
+    let mut process = Process::new(name)?;
+
+    let space = process.address_space_mut();
+    let image = elf::load(space, &elf)?;
+    space.mmap(
+        Some(VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE)),
+        USER_STACK_SIZE,
+        Protection::USER_DATA,
+    )?;
+    let stack_pointer = elf::setup_stack(space, VirtAddr::new(USER_STACK_TOP), &image, argv, envp)?;
+
+    Ok(start(process, image.entry, stack_pointer))
+}
+
+/// Register the process and start its main thread at `entry`
+pub fn start(process: Process, entry: VirtAddr, stack_top: VirtAddr) -> JoinHandle {
+    let pid = process.pid;
+    let level_4_frame = process.address_space.level_4_frame();
+    let name = process.name.clone();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        PROCESSES.lock().insert(pid, process);
+    });
+
+    thread::spawn_process(&name, pid, level_4_frame, move || unsafe {
+        enter_user(entry, stack_top)
+    })
+}
+
+/// Run `f` with the process the current thread belongs to
+pub fn with_current<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut Process) -> R,
+{
+    let pid = thread::current_pid()?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        PROCESSES.lock().get_mut(&pid).map(f)
+    })
+}
+
+/// Tear down the current process and end its thread
+pub fn exit_current(code: u64) -> ! {
+    if let Some(pid) = thread::current_pid() {
+        log::debug!("process {} exited with code {}", pid.as_u64(), code);
+        thread::detach_current();
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            PROCESSES.lock().remove(&pid);
+        });
+    }
+    thread::exit();
+}
+
+/// Drop to ring 3 and start executing at `entry` with the stack at `stack_top`.
+///
+/// # Safety
+///
+/// The active address space must map `entry` and `stack_top` as user pages.
+pub unsafe fn enter_user(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = crate::cpu::gdt::selectors();
+    let data = selectors.user_data_selector.0 as u64;
+    let code = selectors.user_code_selector.0 as u64;
+
+    asm!(
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {data}",
+        "push {stack}",
+        "push {rflags}",
+        "push {code}",
+        "push {entry}",
+        "iretq",
+        data = in(reg) data,
+        stack = in(reg) stack_top.as_u64(),
+        rflags = in(reg) 0x202u64,
+        code = in(reg) code,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
@@ -1,2 +1,3 @@
 pub mod helloworld;
-pub mod shell;
\ No newline at end of file
+pub mod shell;
+pub mod usertest;
\ No newline at end of file
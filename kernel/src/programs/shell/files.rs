@@ -0,0 +1,173 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::api::console;
+use crate::fs::{self, path, NodeKind};
+use crate::io::vga::font;
+use crate::task::{executor, thread};
+use crate::{print, println, process};
+
+static CWD: Mutex<String> = Mutex::new(String::new());
+
+pub fn cwd() -> String {
+    let cwd = CWD.lock();
+    if cwd.is_empty() {
+        String::from("/")
+    } else {
+        cwd.clone()
+    }
+}
+
+fn absolute(path: &str) -> String {
+    path::normalize(&cwd(), path)
+}
+
+pub fn ls(args: &[&str]) {
+    let target = absolute(args.first().copied().unwrap_or("."));
+    match fs::read_dir(&target) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry.metadata.kind {
+                    NodeKind::Directory => println!("{}/", entry.name),
+                    NodeKind::File => println!("{:<24} {}", entry.name, entry.metadata.size),
+                }
+            }
+        }
+        Err(err) => println!("ls: {}: {:?}", target, err),
+    }
+}
+
+pub fn cd(args: &[&str]) {
+    let target = absolute(args.first().copied().unwrap_or("/"));
+    match fs::lookup(&target).and_then(|node| node.as_directory()) {
+        Ok(_) => *CWD.lock() = target,
+        Err(err) => println!("cd: {}: {:?}", target, err),
+    }
+}
+
+pub fn cat(args: &[&str]) {
+    let Some(name) = args.first() else {
+        println!("usage: cat <path>");
+        return;
+    };
+    let target = absolute(name);
+    match fs::read_to_end(&target) {
+        Ok(data) => print!("{}", String::from_utf8_lossy(&data)),
+        Err(err) => println!("cat: {}: {:?}", target, err),
+    }
+}
+
+pub fn mkdir(args: &[&str]) {
+    let Some(name) = args.first() else {
+        println!("usage: mkdir <path>");
+        return;
+    };
+    let target = absolute(name);
+    if let Err(err) = fs::create_dir(&target) {
+        println!("mkdir: {}: {:?}", target, err);
+    }
+}
+
+pub fn rm(args: &[&str]) {
+    let Some(name) = args.first() else {
+        println!("usage: rm <path>");
+        return;
+    };
+    let target = absolute(name);
+    if let Err(err) = fs::remove(&target) {
+        println!("rm: {}: {:?}", target, err);
+    }
+}
+
+/// Replace the contents of a file with the rest of the arguments
+pub fn write(args: &[&str]) {
+    let Some((name, words)) = args.split_first() else {
+        println!("usage: write <path> <text>");
+        return;
+    };
+    let target = absolute(name);
+    let mut text = words.join(" ");
+    text.push('\n');
+
+    let result = fs::create(&target).and_then(|mut file| {
+        file.truncate()?;
+        file.write(text.as_bytes())?;
+        file.flush()
+    });
+    if let Err(err) = result {
+        println!("write: {}: {:?}", target, err);
+    }
+}
+
+/// Switch the console to a PSF or BDF font file, or a built in font by name
+pub fn setfont(args: &[&str]) {
+    let Some(name) = args.first() else {
+        println!("usage: setfont <path|name>");
+        let names: Vec<&str> = font::builtin_names().collect();
+        println!("built in: {}", names.join(" "));
+        return;
+    };
+    let (target, font) = match font::builtin(name) {
+        Some(font) => (String::from(*name), Ok(font)),
+        None => {
+            let target = absolute(name);
+            let font = font::load(&target);
+            (target, font)
+        }
+    };
+    if let Err(err) = font.and_then(console::set_font) {
+        println!("setfont: {}: {:?}", target, err);
+    }
+}
+
+/// Load an ELF executable from the filesystem and run it in ring 3. The
+/// shell waits for it unless the last argument is `&`.
+pub async fn exec(args: &[&str]) {
+    let (args, background) = match args.split_last() {
+        Some((&"&", rest)) => (rest, true),
+        _ => (args, false),
+    };
+    let Some(name) = args.first() else {
+        println!("usage: exec <path> [args] [&]");
+        return;
+    };
+    let target = absolute(name);
+    let data = match fs::read_to_end(&target) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("exec: {}: {:?}", target, err);
+            return;
+        }
+    };
+
+    let argv: Vec<&str> = core::iter::once(target.as_str()).chain(args[1..].iter().copied()).collect();
+    let cwd = cwd();
+    let pwd = alloc::format!("PWD={}", cwd);
+    let thread = match process::spawn_elf(&target, &data, &argv, &[&pwd]) {
+        Ok(thread) => thread,
+        Err(err) => {
+            println!("exec: {}: {:?}", target, err);
+            return;
+        }
+    };
+
+    let waiter = match executor::spawn(wait_for_exit(thread)).await {
+        Ok(waiter) => waiter,
+        Err(err) => {
+            println!("exec: {}: {:?}", target, err);
+            return;
+        }
+    };
+    if background {
+        println!("started {} as task {}", target, waiter.id().as_u64());
+    } else {
+        let _ = waiter.await;
+    }
+}
+
+/// Wait until the thread of a process has exited
+async fn wait_for_exit(thread: thread::JoinHandle) {
+    thread.exited().await;
+    println!("thread {} exited", thread.id().as_u64());
+}
@@ -1,10 +1,19 @@
-use crate::{println, print, api::console, io::keyboard::ScancodeStream};
+use crate::{println, print, api::console, io::keyboard::{console_key, ScancodeStream}};
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode};
 use futures_util::{stream::Stream, StreamExt};
 
 use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::task::timer::{timeout, Elapsed};
+use crate::time::Duration;
+
+mod files;
+mod system;
 
 static mut SCANCODES: ScancodeStream = ScancodeStream {};
+
+const CURSOR_BLINK: Duration = Duration::from_millis(500);
 //let mut scancodes = ScancodeStream::new();
      //let mut keyboard: Keyboard<layouts::Us104Key, ScancodeSet1> =
 //         Keyboard::new(HandleControl::Ignore);
@@ -31,9 +40,28 @@ pub async fn input() -> String {
     let mut keyboard: Keyboard<layouts::Us104Key, ScancodeSet1> = Keyboard::new(HandleControl::Ignore);
     let mut out: String = String::from("");
 
+    let mut cursor_visible = false;
+
     unsafe {
-        while let Some(scancode) = SCANCODES.next().await {
+        loop {
+            let scancode = match timeout(CURSOR_BLINK, SCANCODES.next()).await {
+                Ok(Some(scancode)) => scancode,
+                Ok(None) => break,
+                Err(Elapsed) => {
+                    cursor_visible = !cursor_visible;
+                    console::show_cursor(cursor_visible);
+                    continue;
+                }
+            };
+            if cursor_visible {
+                console::show_cursor(false);
+                cursor_visible = false;
+            }
+
             if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+                if console_key(&key_event, keyboard.get_modifiers()) {
+                    continue;
+                }
                 if let Some(key) = keyboard.process_keyevent(key_event) {
                     match key {
                         DecodedKey::Unicode(character) => match character {
@@ -72,16 +100,34 @@ pub async fn update() {
     print!("\n$ ");
     let command: String = input().await;
 
-    if command == String::from("") {
+    let mut words = command.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let args: Vec<&str> = words.collect();
 
-    } else if command == String::from("help") {
-        println!("commands:\n   clear");
-    } else if command == String::from("clear") {
-        console::clear();
-    } else {
-        println!("\"{}\" not found", command);
+    match name {
+        "help" => println!("commands:\n   clear\n   usertest\n   ls [path]\n   cd [path]\n   pwd\n   cat <path>\n   mkdir <path>\n   rm <path>\n   write <path> <text>\n   exec <path> [args] [&]\n   setfont <path|name>\n   scrollback [lines]\n   lspci [-v]\n   mem\n   cpuinfo\n   uptime\n   timer [periodic|oneshot|deadline] [hz]\n   date\n   sleep <ms> [text]"),
+        "clear" => console::clear(),
+        "usertest" => crate::programs::usertest::main(),
+        "ls" => files::ls(&args),
+        "cd" => files::cd(&args),
+        "pwd" => println!("{}", files::cwd()),
+        "cat" => files::cat(&args),
+        "mkdir" => files::mkdir(&args),
+        "rm" => files::rm(&args),
+        "write" => files::write(&args),
+        "exec" => files::exec(&args).await,
+        "setfont" => files::setfont(&args),
+        "scrollback" => system::scrollback(&args),
+        "lspci" => system::lspci(&args),
+        "mem" => system::mem(),
+        "cpuinfo" => system::cpuinfo(),
+        "uptime" => system::uptime(),
+        "timer" => system::timer(&args),
+        "date" => system::date(),
+        "sleep" => system::sleep(&args).await,
+        _ => println!("\"{}\" not found", command),
     }
-
-    
-    //print!("a");
-}
\ No newline at end of file
+}
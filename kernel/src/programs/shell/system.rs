@@ -0,0 +1,146 @@
+use crate::io::pci::{self, capability, Bar};
+use crate::cpu::{self, percpu};
+use crate::task::{scheduler, timer};
+use crate::time::{Duration, TimerMode};
+use crate::api::console;
+use crate::{allocator, memory, println, time};
+
+pub fn lspci(args: &[&str]) {
+    let verbose = args.contains(&"-v");
+
+    for device in pci::devices() {
+        println!(
+            "{} {:04x}:{:04x} {} [{}]",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            pci::class_name(device.class, device.subclass),
+            device.driver.unwrap_or("-"),
+        );
+        if !verbose {
+            continue;
+        }
+
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory { address, size, prefetchable }) => println!(
+                    "    bar{}: memory at {:#x} ({} KiB{})",
+                    index, address, size / 1024, if *prefetchable { ", prefetchable" } else { "" },
+                ),
+                Some(Bar::Io { port, size }) => println!("    bar{}: io at {:#x} ({} ports)", index, port, size),
+                None => {}
+            }
+        }
+        for cap in &device.capabilities {
+            println!("    capability {:#04x} at {:#x}: {}", cap.id, cap.offset, capability::name(cap.id));
+        }
+    }
+}
+
+pub fn mem() {
+    let frames = memory::frame_stats();
+    println!(
+        "physical: {} KiB total, {} KiB used, {} KiB free",
+        frames.total * 4, frames.used * 4, frames.free * 4,
+    );
+
+    let heap = allocator::stats();
+    println!(
+        "heap: {} KiB mapped, {} KiB used, {} KiB requested, {} KiB overhead",
+        heap.mapped / 1024, heap.used / 1024, heap.requested / 1024, heap.overhead() / 1024,
+    );
+    for slab in heap.slabs.iter().filter(|slab| slab.in_use + slab.free > 0) {
+        println!("    slab {:>4}: {:>6} in use {:>6} free", slab.size, slab.in_use, slab.free);
+    }
+}
+
+pub fn cpuinfo() {
+    if let Some(brand) = cpu::brand_string() {
+        println!("{}", brand);
+    }
+    println!("{} of {} CPUs online", percpu::online_count(), percpu::count());
+    for cpu in percpu::iter() {
+        let (current, waiting) = scheduler::cpu_status(cpu.index())
+            .unwrap_or_else(|| ("-".into(), 0));
+        println!(
+            "cpu {:>2}: apic {:>3} {:<7}{} {:>8} ticks, running {}, {} waiting",
+            cpu.index(),
+            cpu.apic_id(),
+            if cpu.is_online() { "online" } else { "offline" },
+            if cpu.is_bsp() { " (bsp)" } else { "      " },
+            cpu.ticks(),
+            current,
+            waiting,
+        );
+    }
+}
+
+pub fn uptime() {
+    let uptime = time::uptime();
+    let seconds = uptime.as_secs();
+    println!(
+        "up {}:{:02}:{:02}.{:03}",
+        seconds / 3600, seconds / 60 % 60, seconds % 60, uptime.subsec_millis(),
+    );
+    println!(
+        "clock: {}, timer: {:?} at {} Hz, {} ticks",
+        time::clock_source().map(|source| alloc::format!("{}", source)).unwrap_or_else(|| "none".into()),
+        time::timer_mode(),
+        time::tick_frequency(),
+        time::ticks(),
+    );
+}
+
+/// Show the timer mode and tick frequency, or change them
+pub fn timer(args: &[&str]) {
+    if args.is_empty() {
+        println!("timer: {:?} at {} Hz", time::timer_mode(), time::tick_frequency());
+        return;
+    }
+    for arg in args {
+        let result = match *arg {
+            "periodic" => time::set_timer_mode(TimerMode::Periodic),
+            "oneshot" => time::set_timer_mode(TimerMode::OneShot),
+            "deadline" => time::set_timer_mode(TimerMode::TscDeadline),
+            hz => match hz.parse::<u64>() {
+                Ok(hz) => time::set_tick_frequency(hz),
+                Err(_) => {
+                    println!("usage: timer [periodic|oneshot|deadline] [hz]");
+                    return;
+                }
+            },
+        };
+        if let Err(err) = result {
+            println!("timer: {}: {:?}", arg, err);
+            return;
+        }
+    }
+}
+
+/// Show or change how many lines the console keeps for Shift+PgUp
+pub fn scrollback(args: &[&str]) {
+    match args.first().map(|lines| lines.parse::<usize>()) {
+        None => println!("scrollback: {} lines", console::scrollback()),
+        Some(Ok(lines)) => console::set_scrollback(lines),
+        Some(Err(_)) => println!("usage: scrollback [lines]"),
+    }
+}
+
+pub fn date() {
+    match time::wall_clock() {
+        Some(now) => println!("{} {} UTC", now.weekday_name(), now),
+        None => println!("date: the clock is not set"),
+    }
+}
+
+/// Wait without blocking the executor, then print the rest of the line
+pub async fn sleep(args: &[&str]) {
+    let Some(Ok(milliseconds)) = args.first().map(|ms| ms.parse::<u64>()) else {
+        println!("usage: sleep <ms> [text]");
+        return;
+    };
+    timer::sleep(Duration::from_millis(milliseconds)).await;
+    if args.len() > 1 {
+        println!("{}", args[1..].join(" "));
+    }
+}
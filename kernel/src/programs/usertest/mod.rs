@@ -0,0 +1,59 @@
+use core::arch::global_asm;
+use core::ptr::addr_of;
+
+use crate::{println, process};
+
+// Position independent ring 3 code, copied into the process as a flat image.
+global_asm!(
+    r#"
+.pushsection .rodata.usertest, "a"
+.global usertest_start
+usertest_start:
+    // write(STDOUT, message, len)
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + 2f]
+    lea rdx, [rip + 3f]
+    sub rdx, rsi
+    syscall
+    // the same through the interrupt gate
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + 2f]
+    lea rdx, [rip + 3f]
+    sub rdx, rsi
+    int 0x24
+    // exit(0)
+    mov eax, 4
+    xor edi, edi
+    syscall
+4:
+    jmp 4b
+2:
+    .ascii "Hello from ring 3!\n"
+3:
+.global usertest_end
+usertest_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static usertest_start: u8;
+    static usertest_end: u8;
+}
+
+fn image() -> &'static [u8] {
+    unsafe {
+        let start = addr_of!(usertest_start);
+        let end = addr_of!(usertest_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+pub fn main() {
+    match process::spawn_flat("usertest", image()) {
+        Ok(handle) => println!("started usertest as thread {}", handle.id().as_u64()),
+        Err(err) => println!("failed to start usertest: {:?}", err),
+    }
+}
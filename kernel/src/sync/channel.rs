@@ -0,0 +1,163 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use super::wait::{WaitList, WaitUntil};
+use super::IrqSpinlock;
+
+struct State<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver: bool,
+}
+
+struct Channel<T> {
+    state: IrqSpinlock<State<T>>,
+    /// Senders waiting for room
+    senders: IrqSpinlock<WaitList>,
+    /// The receiver waiting for an item
+    receiver: IrqSpinlock<WaitList>,
+}
+
+/// Sends items to the `Receiver` of a channel, clone it for more senders
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// The receiver is gone, the item comes back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender is gone and nothing is left
+    Closed,
+}
+
+/// A channel that holds up to `capacity` items, senders wait while it is
+/// full. Both ends may also be used from interrupt handlers with the `try_`
+/// functions.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a channel needs room for at least one item");
+    let channel = Arc::new(Channel {
+        state: IrqSpinlock::new(State {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receiver: true,
+        }),
+        senders: IrqSpinlock::new(WaitList::new()),
+        receiver: IrqSpinlock::new(WaitList::new()),
+    });
+    (Sender { channel: channel.clone() }, Receiver { channel })
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        {
+            let mut state = self.channel.state.lock();
+            if !state.receiver {
+                return Err(TrySendError::Closed(item));
+            }
+            if state.items.len() >= state.capacity {
+                return Err(TrySendError::Full(item));
+            }
+            state.items.push_back(item);
+        }
+        self.channel.receiver.lock().wake_one();
+        Ok(())
+    }
+
+    /// Send `item`, waiting for room while the channel is full
+    pub async fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let mut item = Some(item);
+        WaitUntil::new(&self.channel.senders, || {
+            match self.try_send(item.take().expect("item already sent")) {
+                Ok(()) => Some(Ok(())),
+                Err(TrySendError::Closed(back)) => Some(Err(SendError(back))),
+                Err(TrySendError::Full(back)) => {
+                    item = Some(back);
+                    None
+                }
+            }
+        })
+        .await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.channel.state.lock().receiver
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.lock().senders += 1;
+        Sender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.channel.state.lock();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.channel.receiver.lock().wake_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let item = {
+            let mut state = self.channel.state.lock();
+            match state.items.pop_front() {
+                Some(item) => item,
+                None if state.senders == 0 => return Err(TryRecvError::Closed),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+        self.channel.senders.lock().wake_one();
+        Ok(item)
+    }
+
+    /// The next item, `None` once every sender is gone and the channel is
+    /// empty
+    pub async fn recv(&mut self) -> Option<T> {
+        let channel = self.channel.clone();
+        WaitUntil::new(&channel.receiver, || match self.try_recv() {
+            Ok(item) => Some(Some(item)),
+            Err(TryRecvError::Closed) => Some(None),
+            Err(TryRecvError::Empty) => None,
+        })
+        .await
+    }
+
+    pub fn len(&self) -> usize {
+        self.channel.state.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.state.lock().receiver = false;
+        self.channel.senders.lock().wake_all();
+    }
+}
@@ -0,0 +1,77 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::mutex::{Mutex, MutexGuard};
+use super::wait::WaitList;
+use super::IrqSpinlock;
+
+/// Lets tasks wait for a condition on data behind a `sync::Mutex`. Check the
+/// condition in a loop, another task may have changed it again before the
+/// waiter got the mutex back.
+pub struct Condvar {
+    waiters: IrqSpinlock<WaitList>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { waiters: IrqSpinlock::new(WaitList::new()) }
+    }
+
+    /// Unlock `guard`, wait for a notification and lock the mutex again
+    pub async fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex: &'a Mutex<T> = MutexGuard::mutex(&guard);
+        // queued before unlocking, so a notify right after it is not missed
+        let id = self.waiters.lock().push(None);
+        drop(guard);
+        Notified { condvar: self, id: Some(id) }.await;
+        mutex.lock().await
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.lock().wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.lock().wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+/// Resolves once the waiter `id` was taken out of the list by a notify
+struct Notified<'a> {
+    condvar: &'a Condvar,
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let Some(id) = self.id else {
+            return Poll::Ready(());
+        };
+        if self.condvar.waiters.lock().update(id, context.waker()) {
+            return Poll::Pending;
+        }
+        self.id = None;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let mut waiters = self.condvar.waiters.lock();
+            // a notify this waiter got but never saw goes to the next one
+            if !waiters.remove(id) {
+                waiters.wake_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Lock order checking for debug builds. Every CPU keeps a stack of the
+//! locks it holds, and every acquisition records "held before" edges
+//! between locks. Taking a lock that is already held, or one that some
+//! other path takes before a lock held now, is reported on the serial port
+//! and panics, instead of hanging the first time both paths race.
+//!
+//! Only acquisitions with interrupts disabled are tracked, with interrupts
+//! enabled the thread may be switched out or moved to another CPU while it
+//! holds the lock. `IrqSpinlock` always qualifies. A tracked lock must be
+//! released before interrupts are enabled again, switching threads while
+//! one is held is reported too: its holder could release it on another
+//! CPU and leave it behind in this CPU's stack.
+
+/// How a lock is being taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acquire {
+    /// Waits until the lock is free. Also used for readers, taking a read
+    /// lock twice hangs once a writer queues up in between.
+    Blocking,
+    /// `try_lock` cannot wait, so it is only recorded as held
+    Try,
+}
+
+#[cfg(debug_assertions)]
+mod imp {
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use spin::Mutex;
+    use x86_64::instructions::interrupts;
+
+    use super::Acquire;
+    use crate::cpu::percpu::{self, MAX_CPUS};
+    use crate::serial_print_unlocked;
+
+    /// Locks one CPU can hold at once before the rest go untracked
+    const MAX_HELD: usize = 16;
+    /// Distinct lock pairs remembered
+    const MAX_EDGES: usize = 512;
+
+    #[derive(Clone, Copy)]
+    struct Held {
+        lock: usize,
+        name: &'static str,
+    }
+
+    struct HeldStack {
+        locks: [Held; MAX_HELD],
+        len: usize,
+    }
+
+    /// Held locks of one CPU, only touched by that CPU with interrupts off
+    struct CpuLocks(UnsafeCell<HeldStack>);
+
+    unsafe impl Sync for CpuLocks {}
+
+    const NONE: Held = Held { lock: 0, name: "" };
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: CpuLocks = CpuLocks(UnsafeCell::new(HeldStack { locks: [NONE; MAX_HELD], len: 0 }));
+    static HELD: [CpuLocks; MAX_CPUS] = [EMPTY; MAX_CPUS];
+
+    /// `(before, after)` pairs of locks seen nested in that order
+    struct Graph {
+        edges: [(usize, usize); MAX_EDGES],
+        len: usize,
+        /// Locks still to visit in `reaches`, each edge is followed once
+        pending: [usize; MAX_EDGES],
+    }
+
+    static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+        edges: [(0, 0); MAX_EDGES],
+        len: 0,
+        pending: [0; MAX_EDGES],
+    });
+    /// Set by the first report, checking stops after it
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+
+    impl Graph {
+        fn contains(&self, edge: (usize, usize)) -> bool {
+            self.edges[..self.len].contains(&edge)
+        }
+
+        fn add(&mut self, edge: (usize, usize)) {
+            if self.len < MAX_EDGES && !self.contains(edge) {
+                self.edges[self.len] = edge;
+                self.len += 1;
+            }
+        }
+
+        /// Whether `to` is known to be taken while `from` is held
+        fn reaches(&mut self, from: usize, to: usize) -> bool {
+            let mut visited = [0u64; MAX_EDGES / 64];
+            let mut depth = 1;
+            self.pending[0] = from;
+            while depth > 0 {
+                depth -= 1;
+                let lock = self.pending[depth];
+                for (index, &(before, after)) in self.edges[..self.len].iter().enumerate() {
+                    if before != lock {
+                        continue;
+                    }
+                    if after == to {
+                        return true;
+                    }
+                    // `from` is pending before any edge is followed, so
+                    // there is room for one lock per edge after it
+                    if visited[index / 64] & (1 << (index % 64)) == 0 && depth < MAX_EDGES {
+                        visited[index / 64] |= 1 << (index % 64);
+                        self.pending[depth] = after;
+                        depth += 1;
+                    }
+                }
+            }
+            false
+        }
+
+        fn forget(&mut self, lock: usize) {
+            let mut index = 0;
+            while index < self.len {
+                let (before, after) = self.edges[index];
+                if before == lock || after == lock {
+                    self.len -= 1;
+                    self.edges[index] = self.edges[self.len];
+                } else {
+                    index += 1;
+                }
+            }
+        }
+    }
+
+    /// Held locks of this CPU. Interrupts have to be off.
+    fn held() -> Option<&'static mut HeldStack> {
+        if REPORTED.load(Ordering::Relaxed) {
+            return None;
+        }
+        let cpu = percpu::current_index();
+        Some(unsafe { &mut *HELD[cpu].0.get() })
+    }
+
+    fn report(args: core::fmt::Arguments) -> ! {
+        REPORTED.store(true, Ordering::Relaxed);
+        serial_print_unlocked!("lockdep: cpu {}: {}\n", percpu::current_index(), args);
+        panic!("lockdep: {}", args);
+    }
+
+    pub fn acquire(lock: usize, name: &'static str, kind: Acquire) {
+        if interrupts::are_enabled() {
+            return;
+        }
+        let Some(held) = held() else {
+            return;
+        };
+
+        if kind == Acquire::Blocking {
+            for other in &held.locks[..held.len] {
+                if other.lock == lock {
+                    report(format_args!("recursive locking of {} ({:#x})", name, lock));
+                }
+                let mut graph = GRAPH.lock();
+                graph.add((other.lock, lock));
+                if graph.reaches(lock, other.lock) {
+                    drop(graph);
+                    report(format_args!(
+                        "lock order inversion: {} ({:#x}) taken while holding {} ({:#x}), elsewhere it is taken first",
+                        name, lock, other.name, other.lock,
+                    ));
+                }
+            }
+        }
+
+        // deeper nesting goes untracked
+        if held.len < MAX_HELD {
+            held.locks[held.len] = Held { lock, name };
+            held.len += 1;
+        }
+    }
+
+    /// Locks taken with interrupts off may be released after they were
+    /// enabled again, so this does not skip those
+    pub fn release(lock: usize) {
+        interrupts::without_interrupts(|| {
+            let Some(held) = held() else {
+                return;
+            };
+            if let Some(index) = held.locks[..held.len].iter().rposition(|other| other.lock == lock) {
+                held.locks.copy_within(index + 1..held.len, index);
+                held.len -= 1;
+            }
+        });
+    }
+
+    pub fn forget(lock: usize) {
+        interrupts::without_interrupts(|| GRAPH.lock().forget(lock));
+    }
+
+    /// Interrupts are off while switching threads
+    pub fn switch_thread() {
+        let Some(held) = held() else {
+            return;
+        };
+        if let Some(other) = held.locks[..held.len].first() {
+            report(format_args!(
+                "switching threads while holding {} ({:#x}), it was taken with interrupts off and kept after they were enabled",
+                other.name, other.lock,
+            ));
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod imp {
+    use super::Acquire;
+
+    #[inline(always)]
+    pub fn acquire(_lock: usize, _name: &'static str, _kind: Acquire) {}
+
+    #[inline(always)]
+    pub fn release(_lock: usize) {}
+
+    #[inline(always)]
+    pub fn forget(_lock: usize) {}
+
+    #[inline(always)]
+    pub fn switch_thread() {}
+}
+
+/// Note that `lock` is about to be taken on this CPU
+pub fn acquire(lock: usize, name: &'static str, kind: Acquire) {
+    imp::acquire(lock, name, kind);
+}
+
+/// Note that `lock` was released on this CPU
+pub fn release(lock: usize) {
+    imp::release(lock);
+}
+
+/// Drop what is known about `lock`, its address may be reused for another
+pub fn forget(lock: usize) {
+    imp::forget(lock);
+}
+
+/// Check that this CPU holds no tracked locks before it switches threads
+pub fn switch_thread() {
+    imp::switch_thread();
+}
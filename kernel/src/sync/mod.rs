@@ -0,0 +1,19 @@
+//! Locks beyond `spin::Mutex`. The spinning ones are for short critical
+//! sections, `IrqSpinlock` for anything an interrupt handler touches. The
+//! async ones park the waiting task with its waker instead of spinning.
+
+pub mod channel;
+pub mod condvar;
+pub mod lockdep;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+mod wait;
+
+pub use self::channel::{channel, Receiver, Sender};
+pub use self::condvar::Condvar;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::{Semaphore, SemaphorePermit};
+pub use self::spinlock::{IrqSpinlock, IrqSpinlockGuard, TicketLock, TicketLockGuard};
@@ -0,0 +1,164 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::lockdep::{self, Acquire};
+
+/// Set while a writer holds the lock
+const WRITER: usize = 1;
+/// Set while a writer waits, new readers hold back so it gets a turn
+const WRITER_WAITING: usize = 1 << 1;
+/// Each reader adds this
+const READER: usize = 1 << 2;
+
+/// A spinning reader-writer lock. Any number of readers or one writer hold
+/// it, and a waiting writer keeps new readers out so it is not starved.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        lockdep::forget(self.id());
+        let this = core::mem::ManuallyDrop::new(self);
+        unsafe { core::ptr::read(this.data.get()) }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Blocking);
+        loop {
+            if let Some(guard) = self.acquire_read() {
+                return guard;
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let guard = self.acquire_read()?;
+        lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Try);
+        Some(guard)
+    }
+
+    fn acquire_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Blocking);
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            // other waiting writers set the bit again on their next look
+            if state & !WRITER_WAITING == 0
+                && self.state.compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+            {
+                return RwLockWriteGuard { lock: self };
+            }
+            if state & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Try);
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Readers holding the lock right now
+    pub fn reader_count(&self) -> usize {
+        self.state.load(Ordering::Relaxed) / READER
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.id());
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.id());
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.id());
+        // keeps the waiting bit of other writers
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
@@ -0,0 +1,64 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::wait::{WaitList, WaitUntil};
+use super::IrqSpinlock;
+
+/// Counts permits for tasks, `acquire` waits while there are none left.
+/// `release` may be called from an interrupt handler.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: IrqSpinlock<WaitList>,
+}
+
+/// A permit taken from a semaphore, it is given back on drop
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: IrqSpinlock::new(WaitList::new()),
+        }
+    }
+
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        WaitUntil::new(&self.waiters, || self.try_acquire()).await
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .ok()?;
+        Some(SemaphorePermit { semaphore: self })
+    }
+
+    /// Add `count` permits and wake as many waiters
+    pub fn release(&self, count: usize) {
+        self.permits.fetch_add(count, Ordering::Release);
+        let mut waiters = self.waiters.lock();
+        for _ in 0..count {
+            if !waiters.wake_one() {
+                break;
+            }
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Keep the permit taken for good
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release(1);
+    }
+}
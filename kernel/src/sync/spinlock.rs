@@ -0,0 +1,238 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use super::lockdep::{self, Acquire};
+
+/// A spinlock that keeps interrupts disabled while it is held, so an
+/// interrupt handler taking the same lock cannot deadlock against the code
+/// it interrupted. Guards restore the interrupt flag they found, so nested
+/// guards have to be dropped in reverse order.
+pub struct IrqSpinlock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for IrqSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinlock<T> {}
+
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinlock<T>,
+    /// Interrupts were enabled before the lock was taken
+    enable: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinlock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        lockdep::forget(self.id());
+        let this = core::mem::ManuallyDrop::new(self);
+        unsafe { core::ptr::read(this.data.get()) }
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Blocking);
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            while self.is_locked() {
+                spin_loop();
+            }
+        }
+        IrqSpinlockGuard { lock: self, enable }
+    }
+
+    /// Take the lock if nobody holds it. Interrupts stay as they were if
+    /// it is not free.
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Try);
+            Some(IrqSpinlockGuard { lock: self, enable })
+        } else {
+            if enable {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// # Safety
+    ///
+    /// Whoever holds the lock must never touch the data again
+    pub unsafe fn force_unlock(&self) {
+        lockdep::release(self.id());
+        self.locked.store(false, Ordering::Release);
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.id());
+    }
+}
+
+impl<T: Default> Default for IrqSpinlock<T> {
+    fn default() -> Self {
+        IrqSpinlock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSpinlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("IrqSpinlock").field("data", &&*guard).finish(),
+            None => f.write_str("IrqSpinlock { <locked> }"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        lockdep::release(self.lock.id());
+        if self.enable {
+            interrupts::enable();
+        }
+    }
+}
+
+/// A fair spinlock, CPUs get the lock in the order they asked for it. It
+/// does not touch the interrupt flag.
+pub struct TicketLock<T: ?Sized> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(value: T) -> Self {
+        TicketLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        lockdep::forget(self.id());
+        let this = core::mem::ManuallyDrop::new(self);
+        unsafe { core::ptr::read(this.data.get()) }
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Blocking);
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+        TicketLockGuard { lock: self }
+    }
+
+    /// Take the lock if nobody holds it or waits for it
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let ticket = self.now_serving.load(Ordering::Acquire);
+        self.next_ticket
+            .compare_exchange(ticket, ticket.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        lockdep::acquire(self.id(), core::any::type_name::<T>(), Acquire::Try);
+        Some(TicketLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Holders and waiters, including the current holder
+    pub fn queue_len(&self) -> usize {
+        self.next_ticket.load(Ordering::Relaxed).wrapping_sub(self.now_serving.load(Ordering::Relaxed))
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.id());
+    }
+}
+
+impl<T: Default> Default for TicketLock<T> {
+    fn default() -> Self {
+        TicketLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.id());
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
@@ -0,0 +1,128 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use super::IrqSpinlock;
+
+/// Tasks waiting for something, woken oldest first. A waiter that is no
+/// longer in the list has been woken.
+pub(super) struct WaitList {
+    next_id: u64,
+    waiters: VecDeque<(u64, Option<Waker>)>,
+}
+
+impl WaitList {
+    pub const fn new() -> Self {
+        WaitList { next_id: 0, waiters: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, waker: Option<Waker>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiters.push_back((id, waker));
+        id
+    }
+
+    /// Store the waker of `id`, false if it was woken already
+    pub fn update(&mut self, id: u64, waker: &Waker) -> bool {
+        match self.waiters.iter_mut().find(|(other, _)| *other == id) {
+            Some((_, slot)) => {
+                if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+                    *slot = Some(waker.clone());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take `id` out, false if it was woken already
+    pub fn remove(&mut self, id: u64) -> bool {
+        match self.waiters.iter().position(|(other, _)| *other == id) {
+            Some(index) => {
+                self.waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake the oldest waiter, false if there is none
+    pub fn wake_one(&mut self) -> bool {
+        match self.waiters.pop_front() {
+            Some((_, waker)) => {
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn wake_all(&mut self) {
+        while self.wake_one() {}
+    }
+}
+
+/// Resolves once `ready` returns something, polling it again every time
+/// the waiter is woken from `list`. Dropped before that, a wake it already
+/// got is handed on to the next waiter.
+pub(super) struct WaitUntil<'a, F> {
+    list: &'a IrqSpinlock<WaitList>,
+    ready: F,
+    id: Option<u64>,
+}
+
+impl<'a, F> WaitUntil<'a, F> {
+    pub fn new(list: &'a IrqSpinlock<WaitList>, ready: F) -> Self {
+        WaitUntil { list, ready, id: None }
+    }
+}
+
+impl<F: FnMut() -> Option<R> + Unpin, R> Future for WaitUntil<'_, F> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<R> {
+        if let Some(result) = (self.ready)() {
+            self.finish();
+            return Poll::Ready(result);
+        }
+
+        {
+            let mut list = self.list.lock();
+            let registered = self.id.is_some_and(|id| list.update(id, context.waker()));
+            if !registered {
+                self.id = Some(list.push(Some(context.waker().clone())));
+            }
+        }
+
+        // a wake between the first look and queueing would be lost
+        if let Some(result) = (self.ready)() {
+            self.finish();
+            return Poll::Ready(result);
+        }
+        Poll::Pending
+    }
+}
+
+impl<F> WaitUntil<'_, F> {
+    /// Leave the list after getting what was waited for
+    fn finish(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.list.lock().remove(id);
+        }
+    }
+}
+
+impl<F> Drop for WaitUntil<'_, F> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let mut list = self.list.lock();
+            if !list.remove(id) {
+                list.wake_one();
+            }
+        }
+    }
+}
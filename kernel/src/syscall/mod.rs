@@ -0,0 +1,239 @@
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use crate::api::syscall::{
+    Syscall, SyscallError, O_APPEND, O_CREATE, O_TRUNCATE, STDERR, STDIN, STDOUT,
+};
+use crate::fs::{self, File, FsError, SeekFrom};
+use crate::memory::vma::Protection;
+use crate::process::{self, address_space::AddressSpaceError};
+use crate::task::thread;
+
+type SyscallResult = Result<u64, SyscallError>;
+
+/// Most bytes one `read` or `write` moves through the kernel heap, longer
+/// ones return a short count
+const MAX_TRANSFER: u64 = 64 * 1024;
+
+/// Entry point for both `syscall` and `int 0x24`, returns the value for `rax`
+pub fn dispatch(number: u64, args: [u64; 6]) -> i64 {
+    let result = match Syscall::from_u64(number) {
+        Some(Syscall::Read) => sys_read(args[0], args[1], args[2]),
+        Some(Syscall::Write) => sys_write(args[0], args[1], args[2]),
+        Some(Syscall::Open) => sys_open(args[0], args[1], args[2]),
+        Some(Syscall::Close) => sys_close(args[0]),
+        Some(Syscall::Exit) => process::exit_current(args[0]),
+        Some(Syscall::GetPid) => sys_getpid(),
+        Some(Syscall::Sleep) => sys_sleep(args[0]),
+        Some(Syscall::Mmap) => sys_mmap(args[0], args[1], args[2]),
+        Some(Syscall::Munmap) => sys_munmap(args[0], args[1]),
+        Some(Syscall::Mprotect) => sys_mprotect(args[0], args[1], args[2]),
+        Some(Syscall::Yield) => {
+            thread::yield_now();
+            Ok(0)
+        }
+        None => {
+            log::debug!("unknown syscall {}", number);
+            Err(SyscallError::NoSys)
+        }
+    };
+
+    match result {
+        Ok(value) => value as i64,
+        Err(err) => err.as_result(),
+    }
+}
+
+/// Check that `[ptr, ptr + len)` is user memory of the calling process
+fn validate(ptr: u64, len: u64, write: bool) -> Result<VirtAddr, SyscallError> {
+    let addr = VirtAddr::try_new(ptr).map_err(|_| SyscallError::Fault)?;
+    let accessible = process::with_current(|process| {
+        process.address_space().is_accessible(addr, len, write)
+    });
+
+    match accessible {
+        Some(true) => Ok(addr),
+        _ => Err(SyscallError::Fault),
+    }
+}
+
+/// Copy a buffer out of the calling process, which is the active address
+/// space. A bad user page kills the process from inside the page fault
+/// handler, so user memory is only touched here and in `copy_to_user`, with
+/// no locks held, never while printing or inside a file system.
+fn copy_from_user(ptr: u64, len: u64) -> Result<Vec<u8>, SyscallError> {
+    let addr = validate(ptr, len, false)?;
+    let mut buf = kernel_buffer(len)?;
+    let user = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len as usize) };
+    buf.extend_from_slice(user);
+    Ok(buf)
+}
+
+fn copy_to_user(ptr: u64, data: &[u8]) -> Result<(), SyscallError> {
+    let addr = validate(ptr, data.len() as u64, true)?;
+    let user = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(), data.len()) };
+    user.copy_from_slice(data);
+    Ok(())
+}
+
+/// An empty buffer with room for `len` bytes, or `NoMemory` if the heap
+/// cannot hold them. `len` is at most `MAX_TRANSFER`.
+fn kernel_buffer(len: u64) -> Result<Vec<u8>, SyscallError> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len as usize).map_err(|_| SyscallError::NoMemory)?;
+    Ok(buf)
+}
+
+/// Run `f` on an open file of the calling process
+fn with_file<F>(fd: u64, f: F) -> SyscallResult
+where
+    F: FnOnce(&mut dyn File) -> Result<u64, FsError>,
+{
+    let mut file = process::with_current(|process| process.take_file(fd))
+        .flatten()
+        .ok_or(SyscallError::BadFd)?;
+    let result = f(&mut *file);
+    process::with_current(|process| process.return_file(fd, file));
+    result.map_err(fs_error)
+}
+
+fn fs_error(err: FsError) -> SyscallError {
+    match err {
+        FsError::NotFound => SyscallError::NoEntry,
+        FsError::AlreadyExists => SyscallError::Exists,
+        FsError::NotDirectory => SyscallError::NotDirectory,
+        FsError::IsDirectory => SyscallError::IsDirectory,
+        FsError::NotEmpty => SyscallError::NotEmpty,
+        FsError::NoSpace => SyscallError::NoSpace,
+        FsError::Unsupported => SyscallError::NoSys,
+        _ => SyscallError::Invalid,
+    }
+}
+
+fn sys_read(fd: u64, ptr: u64, len: u64) -> SyscallResult {
+    let len = len.min(MAX_TRANSFER);
+    validate(ptr, len, true)?;
+    match fd {
+        // the keyboard belongs to the shell, user programs see end of input
+        STDIN => Ok(0),
+        STDOUT | STDERR => Err(SyscallError::BadFd),
+        fd => {
+            let mut buf = kernel_buffer(len)?;
+            buf.resize(len as usize, 0);
+            let read = with_file(fd, |file| file.read(&mut buf).map(|n| n as u64))?;
+            copy_to_user(ptr, &buf[..read as usize])?;
+            Ok(read)
+        }
+    }
+}
+
+fn sys_write(fd: u64, ptr: u64, len: u64) -> SyscallResult {
+    let truncated = len > MAX_TRANSFER;
+    let buf = copy_from_user(ptr, len.min(MAX_TRANSFER))?;
+    match fd {
+        STDOUT | STDERR => {
+            let text = match core::str::from_utf8(&buf) {
+                Ok(text) => text,
+                // the cut may split a character, it goes out with the rest
+                Err(err) if truncated && err.error_len().is_none() && err.valid_up_to() > 0 => {
+                    core::str::from_utf8(&buf[..err.valid_up_to()]).unwrap()
+                }
+                Err(_) => return Err(SyscallError::Invalid),
+            };
+            crate::print!("{}", text);
+            Ok(text.len() as u64)
+        }
+        STDIN => Err(SyscallError::BadFd),
+        fd => with_file(fd, |file| file.write(&buf).map(|n| n as u64)),
+    }
+}
+
+fn sys_open(path: u64, path_len: u64, flags: u64) -> SyscallResult {
+    if path_len > MAX_TRANSFER {
+        return Err(SyscallError::Invalid);
+    }
+    let path = copy_from_user(path, path_len)?;
+    let path = core::str::from_utf8(&path).map_err(|_| SyscallError::Invalid)?;
+    let path = fs::path::normalize("/", path);
+
+    let mut file = if flags & O_CREATE != 0 {
+        fs::create(&path)
+    } else {
+        fs::open(&path)
+    }
+    .map_err(fs_error)?;
+
+    if flags & O_TRUNCATE != 0 {
+        file.truncate().map_err(fs_error)?;
+    }
+    if flags & O_APPEND != 0 {
+        file.seek(SeekFrom::End(0)).map_err(fs_error)?;
+    }
+
+    process::with_current(|process| process.add_file(file)).ok_or(SyscallError::Invalid)
+}
+
+fn sys_close(fd: u64) -> SyscallResult {
+    let mut file = process::with_current(|process| process.take_file(fd))
+        .flatten()
+        .ok_or(SyscallError::BadFd)?;
+    file.flush().map_err(fs_error)?;
+    Ok(0)
+}
+
+fn sys_getpid() -> SyscallResult {
+    thread::current_pid().map(|pid| pid.as_u64()).ok_or(SyscallError::Invalid)
+}
+
+fn sys_sleep(milliseconds: u64) -> SyscallResult {
+    thread::sleep_ms(milliseconds);
+    Ok(0)
+}
+
+fn sys_mmap(addr: u64, len: u64, prot: u64) -> SyscallResult {
+    if len == 0 {
+        return Err(SyscallError::Invalid);
+    }
+    // like munmap and mprotect, an unaligned `addr` is `Invalid`
+    let addr = match addr {
+        0 => None,
+        addr => Some(VirtAddr::try_new(addr).map_err(|_| SyscallError::Invalid)?),
+    };
+
+    process::with_current(|process| {
+        process.address_space_mut()
+            .mmap(addr, len, Protection::from_prot(prot))
+            .map(|start| start.as_u64())
+            .map_err(address_space_error)
+    })
+    .unwrap_or(Err(SyscallError::Invalid))
+}
+
+fn sys_munmap(addr: u64, len: u64) -> SyscallResult {
+    let start = VirtAddr::try_new(addr).map_err(|_| SyscallError::Invalid)?;
+    process::with_current(|process| {
+        process.address_space_mut()
+            .munmap(start, len)
+            .map(|_| 0)
+            .map_err(address_space_error)
+    })
+    .unwrap_or(Err(SyscallError::Invalid))
+}
+
+fn sys_mprotect(addr: u64, len: u64, prot: u64) -> SyscallResult {
+    let start = VirtAddr::try_new(addr).map_err(|_| SyscallError::Invalid)?;
+    process::with_current(|process| {
+        process.address_space_mut()
+            .mprotect(start, len, Protection::from_prot(prot))
+            .map(|_| 0)
+            .map_err(address_space_error)
+    })
+    .unwrap_or(Err(SyscallError::Invalid))
+}
+
+fn address_space_error(err: AddressSpaceError) -> SyscallError {
+    match err {
+        AddressSpaceError::FrameAllocationFailed => SyscallError::NoMemory,
+        _ => SyscallError::Invalid,
+    }
+}
@@ -0,0 +1,83 @@
+use core::arch::global_asm;
+use core::sync::atomic::AtomicBool;
+
+// Saves the callee-saved registers and rflags of the current thread on its
+// stack, stores the stack pointer in `*old_rsp`, clears `*old_on_cpu` and
+// resumes the thread whose saved stack pointer is `new_rsp`.
+global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdi], rsp
+    mov byte ptr [rdx], 0
+    mov rsp, rsi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global thread_trampoline
+thread_trampoline:
+    and rsp, -16
+    mov rdi, r12
+    call thread_entry
+    ud2
+"#
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64, old_on_cpu: *const AtomicBool);
+    fn thread_trampoline();
+}
+
+/// Number of `u64` slots `switch_context` pops off a fresh stack before
+/// returning into `thread_trampoline`.
+const INITIAL_FRAME_SLOTS: usize = 8;
+
+/// Switch from the current thread to another one. `old_on_cpu` is cleared
+/// once the old thread's stack is no longer in use, from then on another
+/// CPU may resume it.
+///
+/// # Safety
+///
+/// `old_rsp` and `old_on_cpu` must stay valid until the current thread is
+/// resumed and `new_rsp` must have been produced by `switch_context` or
+/// `init_stack`. Interrupts must be disabled.
+pub unsafe fn switch(old_rsp: *mut u64, new_rsp: u64, old_on_cpu: *const AtomicBool) {
+    crate::sync::lockdep::switch_thread();
+    switch_context(old_rsp, new_rsp, old_on_cpu);
+}
+
+/// Prepare a fresh stack so that the first switch to it calls
+/// `thread_entry(arg)`. Returns the initial stack pointer.
+///
+/// # Safety
+///
+/// `stack_top` must point one past the end of a writable stack.
+pub unsafe fn init_stack(stack_top: u64, arg: u64) -> u64 {
+    let top = (stack_top & !0xF) as *mut u64;
+    let frame = top.sub(INITIAL_FRAME_SLOTS + 1);
+
+    frame.add(0).write(0x2); // rflags, interrupts stay off until the trampoline
+    frame.add(1).write(0); // r15
+    frame.add(2).write(0); // r14
+    frame.add(3).write(0); // r13
+    frame.add(4).write(arg); // r12
+    frame.add(5).write(0); // rbx
+    frame.add(6).write(0); // rbp
+    frame.add(7).write(thread_trampoline as usize as u64); // return address
+    frame.add(8).write(0);
+
+    frame as u64
+}
@@ -1,55 +1,133 @@
+use super::thread::{self, ThreadId};
 use super::{Task, TaskId};
+use alloc::boxed::Box;
+use alloc::task::Wake;
+use alloc::vec::Vec;
 use alloc::{collections::BTreeMap, sync::Arc};
+use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
 use core::task::Waker;
 use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
 
 use core::task::{Context, Poll};
 
+/// Tasks one executor holds at once. Spawning more waits (or fails with
+/// `try_spawn`) until one finishes.
+pub const MAX_TASKS: usize = 1024;
+
+/// Executor state that spawners and wakers reach from outside `run`
+struct Shared {
+    /// Tasks to poll. A task is queued at most once, so this never holds
+    /// more than `MAX_TASKS` entries.
+    ready: ArrayQueue<TaskId>,
+    /// Spawned tasks `run` has not picked up yet
+    incoming: ArrayQueue<Task>,
+    /// Spawned tasks that have not finished, including `incoming` ones
+    live: AtomicUsize,
+    /// Spawners waiting for a free slot
+    waiting: Mutex<Vec<Waker>>,
+    /// Thread running `run`
+    thread: OnceCell<ThreadId>,
+    /// `run` found nothing to do and is about to park its thread, or has
+    parked: AtomicBool,
+}
+
+impl Shared {
+    fn reserve(&self) -> bool {
+        self.live
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| (live < MAX_TASKS).then_some(live + 1))
+            .is_ok()
+    }
+
+    /// Unpark the thread of `run` after queueing a task
+    fn notify(&self) {
+        // pairs with the fence in `sleep_if_idle`, either the queued task is
+        // seen there or `parked` is seen here
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::SeqCst) {
+            if let Some(&thread) = self.thread.get() {
+                thread::unpark(thread);
+            }
+        }
+    }
+
+    fn release(&self) {
+        self.live.fetch_sub(1, Ordering::AcqRel);
+        let waiting = core::mem::take(&mut *self.waiting.lock());
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    shared: Arc<Shared>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            shared: Arc::new(Shared {
+                ready: ArrayQueue::new(MAX_TASKS),
+                incoming: ArrayQueue::new(MAX_TASKS),
+                live: AtomicUsize::new(0),
+                waiting: Mutex::new(Vec::new()),
+                thread: OnceCell::uninit(),
+                parked: AtomicBool::new(false),
+            }),
             waker_cache: BTreeMap::new(),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same ID already in tasks");
+    /// A handle that adds tasks to this executor, also from inside its tasks
+    pub fn spawner(&self) -> Spawner {
+        Spawner { shared: self.shared.clone() }
+    }
+
+    /// Move newly spawned tasks into the task list and queue their first poll
+    fn accept_spawned(&mut self) {
+        while let Ok(task) = self.shared.incoming.pop() {
+            let task_id = task.id;
+            if self.tasks.insert(task_id, task).is_some() {
+                panic!("task with same ID already in tasks");
+            }
+            self.waker_cache.insert(task_id, TaskWaker::new(task_id, self.shared.clone()));
+            self.shared.ready.push(task_id).expect("more tasks queued than exist");
         }
-        self.task_queue.push(task_id).expect("queue full");
     }
 
     fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
-            task_queue,
+            shared,
             waker_cache,
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
+        while let Ok(task_id) = shared.ready.pop() {
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue, // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
+            let task_waker = &waker_cache[&task_id];
+            // wakes from here on have to queue the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
+                    // task done -> remove it and its cached waker, leftover
+                    // clones of the waker must not queue the stale ID
+                    task_waker.queued.store(true, Ordering::Release);
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    shared.release();
                 }
                 Poll::Pending => {}
             }
@@ -57,32 +135,55 @@ impl Executor {
     }
 
     pub fn run(&mut self) -> ! {
+        self.shared.thread.init_once(thread::current);
         loop {
+            self.accept_spawned();
             self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Park the thread if no task is ready, so the CPU goes to other
+    /// threads until a task is woken or spawned. Timers are woken from the
+    /// timer interrupt, so a sleeping task gets its turn on the first tick
+    /// after its deadline.
+    fn sleep_if_idle(&self) {
+        self.shared.parked.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        // a wake that did not see `parked` queued its task before this look,
+        // one that did unparks the thread, before or after it parks
+        if self.shared.ready.is_empty() && self.shared.incoming.is_empty() {
+            thread::park();
         }
+        self.shared.parked.store(false, Ordering::SeqCst);
     }
 }
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// The task is in the ready queue already
+    queued: AtomicBool,
+    shared: Arc<Shared>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    /// New tasks start out queued
+    fn new(task_id: TaskId, shared: Arc<Shared>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
-            task_queue,
-        }))
+            queued: AtomicBool::new(true),
+            shared,
+        })
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.shared.ready.push(self.task_id).expect("more tasks queued than exist");
+            self.shared.notify();
+        }
     }
 }
 
-use alloc::task::Wake;
-
 impl Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
         self.wake_task();
@@ -92,3 +193,217 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The executor already holds `MAX_TASKS` tasks
+    Full,
+    /// No global executor has been set
+    NoExecutor,
+}
+
+/// Adds tasks to an executor
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+impl Spawner {
+    /// Spawn `future` if the executor has room for it
+    pub fn try_spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        if !self.shared.reserve() {
+            return Err(SpawnError::Full);
+        }
+        Ok(self.push(future))
+    }
+
+    /// Spawn `future`, waiting for another task to finish first if the
+    /// executor is full
+    pub async fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        core::future::poll_fn(|context| {
+            if self.shared.reserve() {
+                return Poll::Ready(());
+            }
+            let mut waiting = self.shared.waiting.lock();
+            // a task may have finished before the waker went in
+            if self.shared.reserve() {
+                return Poll::Ready(());
+            }
+            waiting.push(context.waker().clone());
+            Poll::Pending
+        })
+        .await;
+        self.push(future)
+    }
+
+    /// Queue `future` on a slot that was already reserved
+    fn push<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState {
+            status: TaskStatus::Running,
+            output: None,
+            cancelled: false,
+            task_waker: None,
+            join_waker: None,
+        }));
+        let task = Task::new(Joinable { future: Box::pin(future), state: state.clone() });
+        let id = task.id;
+        if self.shared.incoming.push(task).is_err() {
+            unreachable!("more tasks spawned than reserved");
+        }
+        self.shared.notify();
+        JoinHandle { id, state }
+    }
+}
+
+static GLOBAL: OnceCell<Spawner> = OnceCell::uninit();
+
+/// Make `spawner` the one the free `spawn` functions use
+pub fn set_global(spawner: Spawner) {
+    GLOBAL.init_once(|| spawner);
+}
+
+/// Spawn `future` on the global executor if it has room
+pub fn try_spawn<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    GLOBAL.get().ok_or(SpawnError::NoExecutor)?.try_spawn(future)
+}
+
+/// Spawn `future` on the global executor, waiting for room if it is full
+pub async fn spawn<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let spawner = GLOBAL.get().ok_or(SpawnError::NoExecutor)?;
+    Ok(spawner.spawn(future).await)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    /// Spawned and not done yet
+    Running,
+    Finished,
+    Cancelled,
+}
+
+/// The task was cancelled before it finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+struct JoinState<T> {
+    status: TaskStatus,
+    output: Option<T>,
+    cancelled: bool,
+    /// Wakes the task so it notices a cancel
+    task_waker: Option<Waker>,
+    /// Wakes whoever awaits the join handle
+    join_waker: Option<Waker>,
+}
+
+/// Runs a spawned future and hands its output to the join handle
+struct Joinable<F: Future> {
+    future: Pin<Box<F>>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        {
+            let mut state = self.state.lock();
+            if state.cancelled {
+                state.status = TaskStatus::Cancelled;
+                let join_waker = state.join_waker.take();
+                drop(state);
+                if let Some(waker) = join_waker {
+                    waker.wake();
+                }
+                return Poll::Ready(());
+            }
+            state.task_waker = Some(context.waker().clone());
+        }
+
+        let output = match self.future.as_mut().poll(context) {
+            Poll::Ready(output) => output,
+            Poll::Pending => return Poll::Pending,
+        };
+        let mut state = self.state.lock();
+        state.status = TaskStatus::Finished;
+        state.output = Some(output);
+        state.task_waker = None;
+        let join_waker = state.join_waker.take();
+        drop(state);
+        if let Some(waker) = join_waker {
+            waker.wake();
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Awaits the output of a spawned task. Dropping the handle leaves the task
+/// running.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.state.lock().status
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.status() != TaskStatus::Running
+    }
+
+    /// Stop the task the next time the executor gets to it. A task that
+    /// already finished keeps its output.
+    pub fn cancel(&self) {
+        let mut state = self.state.lock();
+        if state.status != TaskStatus::Running {
+            return;
+        }
+        state.cancelled = true;
+        let task_waker = state.task_waker.take();
+        drop(state);
+        if let Some(waker) = task_waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.status {
+            TaskStatus::Running => {
+                state.join_waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+            TaskStatus::Finished => Poll::Ready(Ok(state.output.take().expect("join handle polled after completion"))),
+            TaskStatus::Cancelled => Poll::Ready(Err(Cancelled)),
+        }
+    }
+}
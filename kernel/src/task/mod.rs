@@ -1,4 +1,9 @@
 pub mod executor;
+pub mod context;
+pub mod scheduler;
+pub mod stack;
+pub mod thread;
+pub mod timer;
 
 use core::{future::Future, pin::Pin};
 use alloc::boxed::Box;
@@ -7,11 +12,11 @@ use core::sync::atomic::{AtomicU64, Ordering};
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
             future: Box::pin(future),
@@ -28,11 +33,15 @@ impl Task {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
\ No newline at end of file
@@ -0,0 +1,339 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use super::context;
+use super::stack::KernelStack;
+use crate::cpu::{self, percpu, smp};
+use crate::process::address_space;
+use crate::time::{self, Instant};
+use super::thread::{Thread, ThreadId, ThreadState};
+
+pub static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::empty());
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// What the scheduler keeps for each CPU
+#[derive(Default)]
+struct RunQueue {
+    current: Option<ThreadId>,
+    idle: Option<ThreadId>,
+    /// Ready threads waiting for this CPU, oldest first
+    ready: VecDeque<ThreadId>,
+}
+
+/// Threads and per-CPU run queues. Threads stay on the CPU they last ran on
+/// and idle CPUs steal from the busiest queue. Everything is behind the one
+/// `SCHEDULER` lock.
+pub struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    cpus: Vec<RunQueue>,
+    ticks: u64,
+}
+
+/// Stack pointers and flag `context::switch` needs
+struct Switch {
+    old_rsp: *mut u64,
+    new_rsp: u64,
+    old_on_cpu: *const AtomicBool,
+}
+
+impl Scheduler {
+    const fn empty() -> Self {
+        Scheduler {
+            threads: BTreeMap::new(),
+            cpus: Vec::new(),
+            ticks: 0,
+        }
+    }
+
+    /// Add a new thread to the CPU with the least work
+    pub(super) fn add(&mut self, mut thread: Box<Thread>) {
+        let id = thread.id;
+        let cpu = (0..self.cpus.len())
+            .filter(|&index| self.cpus[index].idle.is_some())
+            .min_by_key(|&index| {
+                let queue = &self.cpus[index];
+                queue.ready.len() + (queue.current != queue.idle) as usize
+            })
+            .unwrap_or(0);
+        thread.cpu = cpu;
+        self.threads.insert(id, thread);
+        self.enqueue(cpu, id);
+    }
+
+    /// Queue a ready thread on `cpu`, waking that CPU if it is idle
+    fn enqueue(&mut self, cpu: usize, id: ThreadId) {
+        let Some(queue) = self.cpus.get_mut(cpu) else {
+            return;
+        };
+        queue.ready.push_back(id);
+        if queue.current == queue.idle && cpu != percpu::current_index() {
+            smp::send_reschedule(cpu);
+        }
+    }
+
+    pub(super) fn current_id(&self) -> ThreadId {
+        self.cpus[percpu::current_index()].current.expect("scheduler not initialized")
+    }
+
+    pub(super) fn current_mut(&mut self) -> &mut Thread {
+        let id = self.current_id();
+        self.threads.get_mut(&id).expect("current thread missing")
+    }
+
+    pub(super) fn sleep_current(&mut self, wake_at: Instant) {
+        self.current_mut().state = ThreadState::Sleeping(wake_at);
+    }
+
+    pub(super) fn block_on(&mut self, target: ThreadId) {
+        let current = self.current_id();
+        if let Some(thread) = self.threads.get_mut(&target) {
+            thread.joiners.push(current);
+        }
+        self.current_mut().state = ThreadState::Blocked;
+    }
+
+    pub(super) fn wake(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            if matches!(thread.state, ThreadState::Blocked | ThreadState::Sleeping(_)) {
+                thread.state = ThreadState::Ready;
+                let cpu = thread.cpu;
+                self.enqueue(cpu, id);
+            }
+        }
+    }
+
+    pub(super) fn unpark(&mut self, id: ThreadId) {
+        match self.threads.get_mut(&id) {
+            Some(thread) if thread.state == ThreadState::Blocked => self.wake(id),
+            Some(thread) => thread.unparked = true,
+            None => {}
+        }
+    }
+
+    /// Take out the threads that have exited, dropping them frees their stacks.
+    /// A thread is only gone once the CPU it finished on has switched away.
+    pub(super) fn reap(&mut self) -> Vec<Box<Thread>> {
+        let finished: Vec<ThreadId> = self.threads.values()
+            .filter(|t| t.state == ThreadState::Finished && !t.on_cpu.load(Ordering::Acquire))
+            .map(|t| t.id)
+            .collect();
+        finished.into_iter()
+            .filter_map(|id| self.threads.remove(&id))
+            .collect()
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn threads(&self) -> impl Iterator<Item = &Thread> {
+        self.threads.values().map(|t| &**t)
+    }
+
+    /// The thread running on `cpu` and the number of threads waiting for it
+    pub fn cpu_load(&self, cpu: usize) -> Option<(&Thread, usize)> {
+        let queue = self.cpus.get(cpu)?;
+        let current = self.threads.get(&queue.current?)?;
+        Some((current, queue.ready.len()))
+    }
+
+    fn wake_sleepers(&mut self) {
+        let now = time::now();
+        let woken: Vec<ThreadId> = self.threads.values()
+            .filter(|thread| matches!(thread.state, ThreadState::Sleeping(wake_at) if wake_at <= now))
+            .map(|thread| thread.id)
+            .collect();
+        for id in woken {
+            self.wake(id);
+        }
+    }
+
+    /// Whether `cpu` may run `id` now. A thread that still runs elsewhere,
+    /// or is still switching away from another CPU, has to wait.
+    fn can_run(&self, cpu: usize, id: ThreadId) -> bool {
+        self.threads.get(&id).is_some_and(|thread| {
+            thread.state == ThreadState::Ready
+                && (Some(id) == self.cpus[cpu].current || !thread.on_cpu.load(Ordering::Acquire))
+        })
+    }
+
+    /// Round robin on the own queue, then steal from the back of the
+    /// longest other queue
+    fn pick_next(&mut self, cpu: usize) -> Option<ThreadId> {
+        while let Some(id) = self.cpus[cpu].ready.pop_front() {
+            if self.can_run(cpu, id) {
+                return Some(id);
+            }
+            // threads still switching away get another look next time
+            if self.threads.get(&id).is_some_and(|thread| thread.state == ThreadState::Ready) {
+                self.cpus[cpu].ready.push_back(id);
+                break;
+            }
+        }
+
+        let mut victims: Vec<usize> = (0..self.cpus.len()).filter(|&other| other != cpu).collect();
+        victims.sort_by_key(|&other| core::cmp::Reverse(self.cpus[other].ready.len()));
+        for victim in victims {
+            let position = self.cpus[victim].ready.iter().rposition(|&id| self.can_run(cpu, id));
+            if let Some(id) = position.and_then(|position| self.cpus[victim].ready.remove(position)) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// Update the thread states for a switch on `cpu` and return what
+    /// `context::switch` needs
+    fn prepare_switch(&mut self, cpu: usize) -> Option<Switch> {
+        let old = self.cpus.get(cpu)?.current?;
+        let idle = self.cpus[cpu].idle;
+
+        let old_thread = self.threads.get_mut(&old).unwrap();
+        let was_running = old_thread.state == ThreadState::Running;
+        if was_running {
+            old_thread.state = ThreadState::Ready;
+            if Some(old) != idle {
+                self.cpus[cpu].ready.push_back(old);
+            }
+        }
+
+        let new = match self.pick_next(cpu) {
+            Some(id) => id,
+            None if was_running => old,
+            None => idle?,
+        };
+        if new == old {
+            self.threads.get_mut(&old).unwrap().state = ThreadState::Running;
+            return None;
+        }
+
+        let old_thread = self.threads.get_mut(&old).unwrap();
+        let old_rsp = &mut old_thread.rsp as *mut u64;
+        let old_on_cpu = &old_thread.on_cpu as *const AtomicBool;
+
+        let new_thread = self.threads.get_mut(&new).unwrap();
+        new_thread.state = ThreadState::Running;
+        new_thread.on_cpu.store(true, Ordering::Release);
+        new_thread.cpu = cpu;
+        let new_rsp = new_thread.rsp;
+
+        address_space::switch_to(new_thread.address_space);
+        if let Some(stack_top) = new_thread.kernel_stack_top() {
+            cpu::set_kernel_stack(stack_top);
+        }
+
+        self.cpus[cpu].current = Some(new);
+        Some(Switch { old_rsp, new_rsp, old_on_cpu })
+    }
+
+    /// Start scheduling on `cpu`, where `running` is the context that runs
+    fn add_cpu(&mut self, cpu: usize, running: Box<Thread>, idle: ThreadId) {
+        if self.cpus.len() <= cpu {
+            self.cpus.resize_with(cpu + 1, RunQueue::default);
+        }
+        self.cpus[cpu].current = Some(running.id);
+        self.cpus[cpu].idle = Some(idle);
+        self.threads.insert(running.id, running);
+    }
+}
+
+/// Turn the currently running context into the first thread and start the
+/// idle thread. Preemption starts with the next timer interrupt.
+pub fn init() {
+    let boot = Thread::bootstrap("kernel", None);
+    let idle = Thread::new("idle/0", Box::new(|| { crate::hlt_loop(); }));
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        // never queued, it runs when the queues are empty
+        scheduler.add_cpu(0, boot, idle.id);
+        scheduler.threads.insert(idle.id, idle);
+    });
+
+    ENABLED.store(true, Ordering::Release);
+    log::debug!("scheduler initialized");
+}
+
+/// Called by an application processor once it can take interrupts. The
+/// context it runs on, on `stack`, becomes its idle thread.
+pub fn init_cpu(cpu: usize, stack: Option<KernelStack>) {
+    let idle = Thread::bootstrap(&alloc::format!("idle/{}", cpu), stack);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let id = idle.id;
+        SCHEDULER.lock().add_cpu(cpu, idle, id);
+    });
+}
+
+/// Switch to the next runnable thread, if there is one
+pub fn schedule() {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let cpu = percpu::current_index();
+        let switch = SCHEDULER.lock().prepare_switch(cpu);
+        if let Some(switch) = switch {
+            unsafe { context::switch(switch.old_rsp, switch.new_rsp, switch.old_on_cpu) };
+        }
+    });
+}
+
+/// Called from the timer interrupt after the end of interrupt has been sent
+pub fn tick() {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let cpu = percpu::current_index();
+    let switch = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => {
+            if cpu == 0 {
+                scheduler.ticks += 1;
+            }
+            scheduler.wake_sleepers();
+            scheduler.prepare_switch(cpu)
+        }
+        None => return,
+    };
+
+    if let Some(switch) = switch {
+        unsafe { context::switch(switch.old_rsp, switch.new_rsp, switch.old_on_cpu) };
+    }
+}
+
+/// Called from the reschedule IPI after the end of interrupt has been sent,
+/// picks up threads another CPU queued here
+pub fn preempt() {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let cpu = percpu::current_index();
+    let switch = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => scheduler.prepare_switch(cpu),
+        None => return,
+    };
+
+    if let Some(switch) = switch {
+        unsafe { context::switch(switch.old_rsp, switch.new_rsp, switch.old_on_cpu) };
+    }
+}
+
+/// Name of the thread running on `cpu` and how many wait for it
+pub fn cpu_status(cpu: usize) -> Option<(String, usize)> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let (current, waiting) = scheduler.cpu_load(cpu)?;
+        Some((current.name().into(), waiting))
+    })
+}
+
+pub fn ticks() -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| SCHEDULER.lock().ticks())
+}
@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use super::thread::THREAD_STACK_SIZE;
+use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+
+/// Kernel stacks live here, see `address_space::init_kernel_half`
+pub const STACKS_START: u64 = 0x_4460_0000_0000;
+/// Room for this many stacks at once
+const MAX_STACKS: u64 = 0x1_0000;
+
+const GUARD_SIZE: u64 = 4096;
+/// A stack and the unmapped guard page below it
+const SLOT_SIZE: u64 = THREAD_STACK_SIZE as u64 + GUARD_SIZE;
+pub const STACKS_END: u64 = STACKS_START + MAX_STACKS * SLOT_SIZE;
+
+struct Slots {
+    next: u64,
+    free: Vec<u64>,
+    /// Slots whose stack is still mapped, given back with interrupts off
+    mapped: Vec<u64>,
+}
+
+static SLOTS: Mutex<Slots> = Mutex::new(Slots { next: 0, free: Vec::new(), mapped: Vec::new() });
+
+/// A kernel thread stack. Running off its bottom hits an unmapped guard
+/// page instead of whatever memory comes next.
+pub struct KernelStack {
+    slot: u64,
+}
+
+impl KernelStack {
+    pub fn new() -> Option<KernelStack> {
+        let (slot, mapped) = without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            if let Some(slot) = slots.mapped.pop() {
+                return Some((slot, true));
+            }
+            match slots.free.pop() {
+                Some(slot) => Some((slot, false)),
+                None if slots.next < MAX_STACKS => {
+                    slots.next += 1;
+                    Some((slots.next - 1, false))
+                }
+                None => None,
+            }
+        })?;
+
+        let stack = KernelStack { slot };
+        if !mapped && !stack.map() {
+            // no other CPU has used the pages yet, so there is nothing to
+            // shoot down
+            stack.unmap();
+            without_interrupts(|| SLOTS.lock().free.push(slot));
+            core::mem::forget(stack);
+            return None;
+        }
+        Some(stack)
+    }
+
+    fn guard(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + self.slot * SLOT_SIZE)
+    }
+
+    /// Lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard() + GUARD_SIZE
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + THREAD_STACK_SIZE as u64
+    }
+
+    /// Whether `addr` is in this stack's guard page
+    pub fn guard_contains(&self, addr: VirtAddr) -> bool {
+        (self.guard()..self.bottom()).contains(&addr)
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(Page::containing_address(self.bottom()), Page::containing_address(self.top()))
+    }
+
+    fn map(&self) -> bool {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        without_interrupts(|| {
+            let mut mapper = MAPPER.try_get().unwrap().lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+            for page in self.pages() {
+                let Some(frame) = frame_allocator.allocate_frame() else {
+                    return false;
+                };
+                match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+                    Ok(flush) => flush.flush(),
+                    Err(_) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        return false;
+                    }
+                }
+            }
+            true
+        })
+    }
+
+    fn unmap(&self) {
+        without_interrupts(|| {
+            let mut mapper = MAPPER.try_get().unwrap().lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+            for page in self.pages() {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+    }
+}
+
+/// Unmapping a stack waits for every other CPU to flush it from its TLB,
+/// which they cannot do while this one keeps interrupts off. A stack given
+/// back then stays mapped and goes to the next thread instead.
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        if !interrupts::are_enabled() {
+            SLOTS.lock().mapped.push(self.slot);
+            return;
+        }
+        self.unmap();
+        // other CPUs may still have the stack in their TLB
+        crate::cpu::smp::shootdown(self.bottom(), self.top());
+        without_interrupts(|| SLOTS.lock().free.push(self.slot));
+    }
+}
+
+/// Whether `addr` is in the guard page of some kernel stack
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let offset = match addr.as_u64().checked_sub(STACKS_START) {
+        Some(offset) if offset < MAX_STACKS * SLOT_SIZE => offset,
+        _ => return false,
+    };
+    offset % SLOT_SIZE < GUARD_SIZE
+}
@@ -0,0 +1,352 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use super::scheduler::{self, SCHEDULER};
+use super::stack::KernelStack;
+use crate::process::Pid;
+use crate::process::address_space::AddressSpace;
+use crate::size::*;
+use crate::time::{self, Duration, Instant};
+
+pub const THREAD_STACK_SIZE: usize = Size::KiB(64).bytes();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Sleeping until the monotonic clock reaches the given time
+    Sleeping(Instant),
+    /// Waiting for another thread to wake it up (e.g. `join`)
+    Blocked,
+    Finished,
+}
+
+pub(super) struct JoinState {
+    finished: AtomicBool,
+    /// Task waiting in `JoinHandle::exited`
+    waker: AtomicWaker,
+}
+
+pub struct Thread {
+    pub(super) id: ThreadId,
+    pub(super) name: String,
+    pub(super) state: ThreadState,
+    /// Saved stack pointer while the thread is not running
+    pub(super) rsp: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack
+    pub(super) stack: Option<KernelStack>,
+    pub(super) joiners: Vec<ThreadId>,
+    pub(super) join_state: Arc<JoinState>,
+    /// `unpark` was called while the thread was not parked, the next `park`
+    /// returns right away
+    pub(super) unparked: bool,
+    /// Level 4 page table loaded while this thread runs
+    pub(super) address_space: PhysFrame,
+    /// The user process this thread belongs to, `None` for kernel threads
+    pub(super) pid: Option<Pid>,
+    /// CPU the thread last ran on, it is queued there when it wakes up
+    pub(super) cpu: usize,
+    /// Set while a CPU runs the thread or has not finished switching away
+    /// from it, no other CPU may pick it up until then
+    pub(super) on_cpu: AtomicBool,
+}
+
+impl Thread {
+    /// Wrap the currently running context, the one `kernel_main` or an
+    /// application processor starts on. `stack` is the stack it runs on, if
+    /// the thread should own it.
+    pub(super) fn bootstrap(name: &str, stack: Option<KernelStack>) -> Box<Thread> {
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            state: ThreadState::Running,
+            rsp: 0,
+            stack,
+            joiners: Vec::new(),
+            join_state: Arc::new(JoinState { finished: AtomicBool::new(false), waker: AtomicWaker::new() }),
+            unparked: false,
+            address_space: AddressSpace::kernel_frame(),
+            pid: None,
+            cpu: crate::cpu::percpu::current_index(),
+            on_cpu: AtomicBool::new(true),
+        })
+    }
+
+    pub(super) fn new(name: &str, entry: Box<dyn FnOnce() + Send + 'static>) -> Box<Thread> {
+        let stack = KernelStack::new().expect("out of memory for a kernel stack");
+
+        // double box so the trampoline only has to deal with a thin pointer
+        let arg = Box::into_raw(Box::new(entry)) as u64;
+        let rsp = unsafe { super::context::init_stack(stack.top().as_u64(), arg) };
+
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            state: ThreadState::Ready,
+            rsp,
+            stack: Some(stack),
+            joiners: Vec::new(),
+            join_state: Arc::new(JoinState { finished: AtomicBool::new(false), waker: AtomicWaker::new() }),
+            unparked: false,
+            address_space: AddressSpace::kernel_frame(),
+            pid: None,
+            cpu: 0,
+            on_cpu: AtomicBool::new(false),
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn pid(&self) -> Option<Pid> {
+        self.pid
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// Top of the kernel stack, loaded into the TSS for ring 3 -> ring 0 switches
+    pub fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(|stack| stack.top().align_down(16u64))
+    }
+
+    pub(super) fn is_runnable(&self) -> bool {
+        matches!(self.state, ThreadState::Ready | ThreadState::Running)
+    }
+}
+
+#[no_mangle]
+extern "C" fn thread_entry(arg: *mut Box<dyn FnOnce() + Send + 'static>) -> ! {
+    let entry = unsafe { Box::from_raw(arg) };
+    x86_64::instructions::interrupts::enable();
+    entry();
+    exit();
+}
+
+pub struct JoinHandle {
+    id: ThreadId,
+    state: Arc<JoinState>,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the thread has exited, for async tasks that must not
+    /// block the thread they run on
+    pub fn exited(&self) -> Exited<'_> {
+        Exited { state: &self.state }
+    }
+
+    /// Block the calling thread until the thread has exited
+    pub fn join(self) {
+        loop {
+            let reaped = x86_64::instructions::interrupts::without_interrupts(|| {
+                let mut scheduler = SCHEDULER.lock();
+                if self.is_finished() {
+                    return Some(scheduler.reap());
+                }
+                scheduler.block_on(self.id);
+                None
+            });
+
+            if reaped.is_some() {
+                return;
+            }
+            scheduler::schedule();
+        }
+    }
+}
+
+/// Future of `JoinHandle::exited`
+pub struct Exited<'a> {
+    state: &'a JoinState,
+}
+
+impl Future for Exited<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.state.finished.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        self.state.waker.register(context.waker());
+        // the thread may have exited before the waker went in
+        match self.state.finished.load(Ordering::Acquire) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+/// Start a new kernel thread running `f`
+pub fn spawn<F>(name: &str, f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    start(Thread::new(name, Box::new(f)))
+}
+
+/// Start a thread that runs in the address space of a user process
+pub fn spawn_process<F>(name: &str, pid: Pid, address_space: PhysFrame, f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut thread = Thread::new(name, Box::new(f));
+    thread.pid = Some(pid);
+    thread.address_space = address_space;
+    start(thread)
+}
+
+fn start(thread: Box<Thread>) -> JoinHandle {
+    let handle = JoinHandle {
+        id: thread.id,
+        state: thread.join_state.clone(),
+    };
+
+    // unmapping the stacks of the reaped threads takes the page table lock,
+    // so they are dropped after the scheduler lock is released
+    let _reaped = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let reaped = scheduler.reap();
+        scheduler.add(thread);
+        reaped
+    });
+
+    handle
+}
+
+/// Give up the rest of the current time slice
+pub fn yield_now() {
+    scheduler::schedule();
+}
+
+/// Block the current thread until `unpark` is called for it. An `unpark`
+/// that came first is used up instead of blocking.
+pub fn park() {
+    let blocked = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current_mut();
+        if core::mem::take(&mut current.unparked) {
+            return false;
+        }
+        current.state = ThreadState::Blocked;
+        true
+    });
+    if blocked {
+        scheduler::schedule();
+    }
+}
+
+/// Wake the thread `id` from `park`, or keep it from blocking in its next one
+pub fn unpark(id: ThreadId) {
+    x86_64::instructions::interrupts::without_interrupts(|| SCHEDULER.lock().unpark(id));
+}
+
+/// Sleep until the monotonic clock reaches `wake_at`. The thread is woken
+/// by the first scheduler tick after that.
+pub fn sleep_until(wake_at: Instant) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().sleep_current(wake_at);
+    });
+    scheduler::schedule();
+}
+
+/// Sleep for at least `duration`
+pub fn sleep(duration: Duration) {
+    sleep_until(time::now() + duration);
+}
+
+/// Terminate the current thread
+pub fn exit() -> ! {
+    let join_state = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current_mut();
+        current.state = ThreadState::Finished;
+        current.join_state.finished.store(true, Ordering::Release);
+        let joiners = core::mem::take(&mut current.joiners);
+        let join_state = current.join_state.clone();
+        for id in joiners {
+            scheduler.wake(id);
+        }
+        join_state
+    });
+    // the task's executor may have to wake its own thread through the scheduler
+    join_state.waker.wake();
+    drop(join_state);
+    scheduler::schedule();
+    unreachable!("finished thread was rescheduled");
+}
+
+/// Sleep for at least `milliseconds`
+pub fn sleep_ms(milliseconds: u64) {
+    sleep(Duration::from_millis(milliseconds));
+}
+
+/// The process the current thread belongs to
+pub fn current_pid() -> Option<Pid> {
+    x86_64::instructions::interrupts::without_interrupts(|| SCHEDULER.lock().current_mut().pid)
+}
+
+/// Move the current thread back to the kernel page table, used before its
+/// process is torn down
+pub fn detach_current() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current_mut();
+        current.pid = None;
+        current.address_space = AddressSpace::kernel_frame();
+        crate::process::address_space::switch_to(current.address_space);
+    });
+}
+
+/// Name of the thread whose stack guard page contains `addr`. Used from
+/// fault handlers, so it gives up instead of waiting for the scheduler lock.
+pub fn stack_owner(addr: VirtAddr) -> Option<String> {
+    let scheduler = SCHEDULER.try_lock()?;
+    scheduler.threads()
+        .find(|thread| thread.stack.as_ref().is_some_and(|stack| stack.guard_contains(addr)))
+        .map(|thread| thread.name.clone())
+}
+
+pub fn current() -> ThreadId {
+    x86_64::instructions::interrupts::without_interrupts(|| SCHEDULER.lock().current_id())
+}
@@ -0,0 +1,227 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time::{self, Duration, Instant};
+
+/// Width of a wheel slot
+const RESOLUTION_NS: u64 = 1_000_000;
+/// Slots in the wheel, a deadline further out than one turn waits in its
+/// slot until the wheel comes around again
+const SLOTS: usize = 256;
+
+struct Entry {
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+}
+
+/// Pending deadlines hashed into slots by the millisecond they expire in
+struct Wheel {
+    slots: Vec<Vec<Entry>>,
+    /// Slots before this one (in resolution units since boot) are processed
+    cursor: u64,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel { slots: Vec::new(), cursor: 0 });
+
+impl Wheel {
+    fn slot_of(deadline: Instant) -> usize {
+        (deadline.as_nanos() / RESOLUTION_NS) as usize % SLOTS
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        if self.slots.is_empty() {
+            self.slots.resize_with(SLOTS, Vec::new);
+        }
+        self.slots[Self::slot_of(entry.deadline)].push(entry);
+    }
+
+    fn remove(&mut self, id: u64, deadline: Instant) -> Option<Entry> {
+        let slot = self.slots.get_mut(Self::slot_of(deadline))?;
+        let index = slot.iter().position(|entry| entry.id == id)?;
+        Some(slot.swap_remove(index))
+    }
+
+    /// Wake every entry that expired by `now`
+    fn advance(&mut self, now: Instant) {
+        if self.slots.is_empty() {
+            return;
+        }
+        let target = now.as_nanos() / RESOLUTION_NS;
+        // after a long gap every slot is due for a look, but only once
+        let steps = (target + 1).saturating_sub(self.cursor).min(SLOTS as u64);
+        for step in 0..steps {
+            let slot = &mut self.slots[((self.cursor + step) % SLOTS as u64) as usize];
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].deadline <= now {
+                    slot.swap_remove(index).waker.wake();
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.cursor = target;
+    }
+}
+
+/// Called from the timer interrupt. Gives up if a task holds the wheel, the
+/// next tick catches up.
+pub fn on_tick() {
+    if let Some(mut wheel) = WHEEL.try_lock() {
+        wheel.advance(time::now());
+    }
+}
+
+/// A future that completes once the monotonic clock reaches its deadline
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Move the deadline, e.g. to wait again without a new future
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if self.registered {
+            without_interrupts(|| WHEEL.lock().remove(self.id, self.deadline));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if time::now() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+
+        let (id, deadline, registered) = (self.id, self.deadline, self.registered);
+        let waker = context.waker().clone();
+        without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            if registered {
+                wheel.remove(id, deadline);
+            }
+            wheel.insert(Entry { id, deadline, waker });
+        });
+        self.registered = true;
+
+        // the deadline may have passed while the entry went in
+        if time::now() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::now() + duration)
+}
+
+/// Fires every `period`. Missed ticks are skipped rather than fired in a
+/// burst.
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// Wait for the next tick and return when it was due
+    pub async fn tick(&mut self) -> Instant {
+        core::future::poll_fn(|context| self.poll_tick(context)).await
+    }
+
+    fn poll_tick(&mut self, context: &mut Context) -> Poll<Instant> {
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(()) => {
+                let due = self.sleep.deadline();
+                let mut next = due + self.period;
+                let now = time::now();
+                if next <= now {
+                    next = now + self.period;
+                }
+                self.sleep.reset(next);
+                Poll::Ready(due)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Instant>> {
+        self.poll_tick(context).map(Some)
+    }
+}
+
+/// The first tick is one `period` from now
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must not be zero");
+    Interval { period, sleep: sleep(period) }
+}
+
+/// The future given to `timeout` did not finish in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Run `future` for at most `duration`
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout { future: Box::pin(future), sleep: sleep(duration) }
+}
@@ -0,0 +1,100 @@
+use core::fmt;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// A calendar date and time of day in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    /// The time `nanos` nanoseconds after 1970-01-01 00:00:00
+    pub fn from_unix_nanos(nanos: u64) -> DateTime {
+        let seconds = nanos / 1_000_000_000;
+        let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+        let time = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            nanosecond: (nanos % 1_000_000_000) as u32,
+        }
+    }
+
+    /// Nanoseconds since 1970-01-01 00:00:00, zero for earlier dates
+    pub fn to_unix_nanos(&self) -> u64 {
+        let Some(days) = days_from_civil(self.year, self.month, self.day) else {
+            return 0;
+        };
+        let seconds = days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64;
+        seconds * 1_000_000_000 + self.nanosecond as u64
+    }
+
+    /// 0 for Sunday
+    pub fn weekday(&self) -> u8 {
+        let days = days_from_civil(self.year, self.month, self.day).unwrap_or(0);
+        // 1970-01-01 was a Thursday
+        ((days + 4) % 7) as u8
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"][self.weekday() as usize]
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// Days since 1970-01-01, after Howard Hinnant's `days_from_civil`
+fn days_from_civil(year: u16, month: u8, day: u8) -> Option<u64> {
+    if year < 1970 || !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some((era * 146_097 + day_of_era - 719_468) as u64)
+}
+
+/// The inverse of `days_from_civil`
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year as u16, month, day)
+}
@@ -0,0 +1,88 @@
+use core::hint::spin_loop;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::VirtAddr;
+
+const CAPABILITIES: u64 = 0x00;
+const CONFIG: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xF0;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CAP_COUNTER_64: u64 = 1 << 13;
+
+/// The HPET main counter, used as a clock source and to calibrate the LAPIC
+/// timer. Its comparators are left alone.
+pub struct Hpet {
+    base: VirtAddr,
+    /// Length of a counter tick in femtoseconds
+    period_fs: u64,
+    wide: bool,
+}
+
+impl Hpet {
+    /// Map the registers at `phys_addr` and start the main counter
+    pub fn new(phys_addr: u64) -> Option<Hpet> {
+        let base = crate::memory::map_mmio(phys_addr, 0x400);
+        let mut hpet = Hpet { base, period_fs: 0, wide: false };
+
+        let capabilities = hpet.read(CAPABILITIES);
+        hpet.period_fs = capabilities >> 32;
+        hpet.wide = capabilities & CAP_COUNTER_64 != 0;
+        // the spec caps the period at 100 ns
+        if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+            return None;
+        }
+
+        let config = hpet.read(CONFIG);
+        hpet.write(CONFIG, config | CONFIG_ENABLE);
+        Some(hpet)
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        unsafe { write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+
+    /// Whether the main counter is 64 bits wide. A 32 bit counter wraps
+    /// after a few minutes and is no good as a clock.
+    pub fn is_64_bit(&self) -> bool {
+        self.wide
+    }
+
+    pub fn counter(&self) -> u64 {
+        if self.wide {
+            self.read(MAIN_COUNTER)
+        } else {
+            self.read(MAIN_COUNTER) & 0xFFFF_FFFF
+        }
+    }
+
+    /// Counter ticks per second
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / 1_000_000) as u64
+    }
+
+    /// Ticks elapsed since `start`, accounting for a 32 bit counter wrapping
+    pub fn elapsed(&self, start: u64) -> u64 {
+        let now = self.counter();
+        if self.wide {
+            now.wrapping_sub(start)
+        } else {
+            now.wrapping_sub(start) & 0xFFFF_FFFF
+        }
+    }
+
+    pub fn wait_ms(&self, ms: u64) {
+        let start = self.counter();
+        let ticks = self.frequency() * ms / 1000;
+        while self.elapsed(start) < ticks {
+            spin_loop();
+        }
+    }
+}
@@ -0,0 +1,376 @@
+pub mod datetime;
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use conquer_once::spin::OnceCell;
+use x2apic::lapic::{TimerDivide, TimerMode as LapicTimerMode};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+
+pub use core::time::Duration;
+pub use self::datetime::DateTime;
+
+use self::hpet::Hpet;
+use crate::cpu::percpu;
+use crate::io::x2apic as apic;
+
+/// Scheduler ticks per second until `set_tick_frequency` says otherwise
+pub const DEFAULT_TICK_HZ: u64 = 100;
+
+/// Length of the calibration window
+const CALIBRATION_MS: u64 = 10;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// A point on the monotonic clock, counted in nanoseconds since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const fn from_nanos(nanos: u64) -> Instant {
+        Instant(nanos)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration.as_nanos() as u64))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// How the LAPIC timer produces scheduler ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerMode {
+    /// The LAPIC reloads the count by itself
+    Periodic,
+    /// Every tick arms the next one
+    OneShot,
+    /// Every tick writes the TSC value of the next one, needs a CPU with
+    /// TSC deadline support
+    TscDeadline,
+}
+
+/// Where `now` reads the time from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// A TSC that runs at a constant rate in every power state
+    Tsc,
+    Hpet,
+    /// A TSC that may change speed, only used when there is nothing better
+    UnstableTsc,
+}
+
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ClockSource::Tsc => "tsc",
+            ClockSource::Hpet => "hpet",
+            ClockSource::UnstableTsc => "tsc (unstable)",
+        })
+    }
+}
+
+struct Clock {
+    source: ClockSource,
+    hpet: Option<Hpet>,
+    /// Counter values of the source at boot
+    hpet_start: u64,
+    tsc_start: u64,
+    tsc_per_ms: u64,
+    /// LAPIC timer ticks per millisecond with a divide of 16
+    lapic_per_ms: u64,
+    tsc_deadline: bool,
+}
+
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+/// The timer settings every CPU follows. The timer interrupt reads them on
+/// all CPUs, so they are atomics rather than behind a lock.
+static TIMER_MODE: AtomicU8 = AtomicU8::new(TimerMode::Periodic as u8);
+static TICK_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TICK_HZ);
+/// Bumped on every settings change, a CPU whose LAPIC timer was programmed
+/// for an older generation reprograms it on its next tick
+static TIMER_GENERATION: AtomicU64 = AtomicU64::new(1);
+/// Latest time handed out, so `now` never goes backwards
+static LAST: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Unix time in nanoseconds when the monotonic clock read zero, from the RTC
+static BOOT_EPOCH: OnceCell<u64> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy)]
+pub enum TimeError {
+    /// The CPU cannot do TSC-deadline mode
+    Unsupported,
+    InvalidFrequency,
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn has_invariant_tsc() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+fn has_tsc_deadline() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 24) != 0
+}
+
+/// Calibrate the TSC and the LAPIC timer against the HPET, or the PIT if
+/// there is none, pick a clock source and start the scheduler tick
+pub fn init() {
+    let hpet = crate::io::acpi::HPET.get().and_then(|&base| Hpet::new(base));
+
+    let (tsc_per_ms, lapic_per_ms) = without_interrupts(|| {
+        let mut lapic = percpu::current().lapic().lock();
+        unsafe {
+            lapic.set_timer_mode(LapicTimerMode::OneShot);
+            lapic.set_timer_divide(TimerDivide::Div16);
+            lapic.set_timer_initial(u32::MAX);
+        }
+        let tsc_before = rdtsc();
+        match &hpet {
+            Some(hpet) => hpet.wait_ms(CALIBRATION_MS),
+            None => pit::wait_ms(CALIBRATION_MS),
+        }
+        let lapic_left = apic::timer_current();
+        let tsc_after = rdtsc();
+        unsafe { lapic.set_timer_initial(0) };
+
+        (
+            (tsc_after - tsc_before) / CALIBRATION_MS,
+            (u32::MAX - lapic_left) as u64 / CALIBRATION_MS,
+        )
+    });
+
+    let source = match &hpet {
+        _ if has_invariant_tsc() => ClockSource::Tsc,
+        Some(hpet) if hpet.is_64_bit() => ClockSource::Hpet,
+        _ => ClockSource::UnstableTsc,
+    };
+    log::info!(
+        "clock: {}, tsc {} MHz, lapic timer {} kHz, hpet {}",
+        source,
+        tsc_per_ms / 1000,
+        lapic_per_ms,
+        match &hpet {
+            Some(hpet) => alloc::format!("{} MHz", hpet.frequency() / 1_000_000),
+            None => alloc::string::String::from("absent"),
+        },
+    );
+
+    CLOCK.init_once(|| Clock {
+        source,
+        hpet_start: hpet.as_ref().map(|hpet| hpet.counter()).unwrap_or(0),
+        hpet,
+        tsc_start: rdtsc(),
+        tsc_per_ms,
+        lapic_per_ms,
+        tsc_deadline: has_tsc_deadline(),
+    });
+
+    without_interrupts(program);
+
+    let century = crate::io::acpi::RTC_CENTURY.get().copied();
+    let rtc = rtc::read(century);
+    BOOT_EPOCH.init_once(|| rtc.to_unix_nanos().saturating_sub(now().as_nanos()));
+    log::info!("rtc: {} UTC", rtc);
+}
+
+impl TimerMode {
+    fn load() -> TimerMode {
+        match TIMER_MODE.load(Ordering::Acquire) {
+            mode if mode == TimerMode::OneShot as u8 => TimerMode::OneShot,
+            mode if mode == TimerMode::TscDeadline as u8 => TimerMode::TscDeadline,
+            _ => TimerMode::Periodic,
+        }
+    }
+}
+
+/// Start the LAPIC timer of a CPU that came up after `init`
+pub fn init_cpu() {
+    without_interrupts(program);
+}
+
+/// Program the LAPIC timer of the current CPU for the current mode and
+/// frequency. Interrupts have to be off.
+fn program() {
+    let Ok(clock) = CLOCK.try_get() else {
+        return;
+    };
+    let cpu = percpu::current();
+    cpu.timer_generation.store(TIMER_GENERATION.load(Ordering::Acquire), Ordering::Relaxed);
+    let mode = TimerMode::load();
+    let hz = TICK_HZ.load(Ordering::Acquire);
+
+    let mut lapic = cpu.lapic().lock();
+    unsafe {
+        lapic.disable_timer();
+        match mode {
+            TimerMode::Periodic | TimerMode::OneShot => {
+                let count = (clock.lapic_per_ms * 1000 / hz).clamp(1, u32::MAX as u64);
+                lapic.set_timer_mode(match mode {
+                    TimerMode::Periodic => LapicTimerMode::Periodic,
+                    _ => LapicTimerMode::OneShot,
+                });
+                lapic.set_timer_divide(TimerDivide::Div16);
+                lapic.enable_timer();
+                lapic.set_timer_initial(count as u32);
+            }
+            TimerMode::TscDeadline => {
+                lapic.set_timer_mode(LapicTimerMode::TscDeadline);
+                lapic.enable_timer();
+                let deadline = rdtsc() + clock.tsc_per_ms * 1000 / hz;
+                cpu.timer_deadline.store(deadline, Ordering::Relaxed);
+                Msr::new(IA32_TSC_DEADLINE).write(deadline);
+            }
+        }
+    }
+}
+
+/// Called from the timer interrupt, arms the next tick in the modes that
+/// need it
+pub fn on_timer_interrupt() {
+    let cpu = percpu::current();
+    cpu.count_tick();
+    if cpu.is_bsp() {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let Ok(clock) = CLOCK.try_get() else {
+        return;
+    };
+    if cpu.timer_generation.load(Ordering::Relaxed) != TIMER_GENERATION.load(Ordering::Acquire) {
+        program();
+        return;
+    }
+    let hz = TICK_HZ.load(Ordering::Acquire);
+    match TimerMode::load() {
+        TimerMode::Periodic => {}
+        TimerMode::OneShot => {
+            let count = (clock.lapic_per_ms * 1000 / hz).clamp(1, u32::MAX as u64);
+            unsafe { cpu.lapic().lock().set_timer_initial(count as u32) };
+        }
+        TimerMode::TscDeadline => {
+            // keep the ticks on a grid instead of drifting by the handler latency
+            let period = clock.tsc_per_ms * 1000 / hz;
+            let deadline = (cpu.timer_deadline.load(Ordering::Relaxed) + period).max(rdtsc() + period / 2);
+            cpu.timer_deadline.store(deadline, Ordering::Relaxed);
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+        }
+    }
+}
+
+/// Apply changed timer settings here, the other CPUs follow on their next
+/// tick
+fn reprogram() {
+    TIMER_GENERATION.fetch_add(1, Ordering::AcqRel);
+    without_interrupts(program);
+}
+
+pub fn set_timer_mode(mode: TimerMode) -> Result<(), TimeError> {
+    if mode == TimerMode::TscDeadline && !CLOCK.try_get().is_ok_and(|clock| clock.tsc_deadline) {
+        return Err(TimeError::Unsupported);
+    }
+    TIMER_MODE.store(mode as u8, Ordering::Release);
+    reprogram();
+    Ok(())
+}
+
+pub fn timer_mode() -> TimerMode {
+    TimerMode::load()
+}
+
+/// Change how many scheduler ticks happen per second
+pub fn set_tick_frequency(hz: u64) -> Result<(), TimeError> {
+    if !(10..=10_000).contains(&hz) {
+        return Err(TimeError::InvalidFrequency);
+    }
+    TICK_HZ.store(hz, Ordering::Release);
+    reprogram();
+    Ok(())
+}
+
+pub fn tick_frequency() -> u64 {
+    TICK_HZ.load(Ordering::Acquire)
+}
+
+/// Timer interrupts on the boot CPU since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn clock_source() -> Option<ClockSource> {
+    CLOCK.try_get().ok().map(|clock| clock.source)
+}
+
+/// The current time on the monotonic clock. Reads zero until `init` ran.
+pub fn now() -> Instant {
+    let Ok(clock) = CLOCK.try_get() else {
+        return Instant(0);
+    };
+
+    let nanos = match (&clock.hpet, clock.source) {
+        (Some(hpet), ClockSource::Hpet) => hpet.ticks_to_ns(hpet.elapsed(clock.hpet_start)),
+        _ => {
+            let ticks = rdtsc().wrapping_sub(clock.tsc_start);
+            (ticks as u128 * 1_000_000 / clock.tsc_per_ms as u128) as u64
+        }
+    };
+    Instant(LAST.fetch_max(nanos, Ordering::AcqRel).max(nanos))
+}
+
+/// Time since the clock started
+pub fn uptime() -> Duration {
+    now().duration_since(Instant(0))
+}
+
+/// Nanoseconds since 1970-01-01 00:00:00 UTC, `None` until `init` read the
+/// RTC. Runs off the monotonic clock, so it never jumps backwards.
+pub fn unix_nanos() -> Option<u64> {
+    let epoch = BOOT_EPOCH.try_get().ok()?;
+    Some(epoch + now().as_nanos())
+}
+
+/// The current date and time in UTC
+pub fn wall_clock() -> Option<DateTime> {
+    unix_nanos().map(DateTime::from_unix_nanos)
+}
+
+/// Spin for `duration`, for drivers that need short delays before the
+/// scheduler runs
+pub fn busy_wait(duration: Duration) {
+    let until = now() + duration;
+    while now() < until {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,39 @@
+use core::hint::spin_loop;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the PIT in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B: bit 0 gates channel 2, bit 1 drives the
+/// speaker and bit 5 mirrors the channel 2 output
+const PORT_B: u16 = 0x61;
+
+/// Busy wait for `ms` milliseconds on channel 2, which is wired to nothing
+/// but the speaker. Only good for up to 54 ms and used to calibrate the
+/// other timers.
+pub fn wait_ms(ms: u64) {
+    let count = FREQUENCY * ms / 1000;
+    assert!(count <= 0xFFFF, "PIT wait of {} ms is too long", ms);
+
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2);
+    unsafe {
+        // gate low and the speaker off while programming
+        let value = port_b.read() & !0x03;
+        port_b.write(value);
+
+        // channel 2, low then high byte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        port_b.write(value | 0x01);
+        while port_b.read() & 0x20 == 0 {
+            spin_loop();
+        }
+        port_b.write(value);
+    }
+}
@@ -0,0 +1,105 @@
+use core::hint::spin_loop;
+use x86_64::instructions::port::Port;
+
+use super::datetime::DateTime;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Keeps NMIs disabled while a register is selected
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Registers as the chip reports them, before BCD and 12 hour decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn read_raw(century_register: Option<u8>) -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        spin_loop();
+    }
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: century_register.map(read_register).unwrap_or(0),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Read the date and time from the CMOS clock, which is assumed to run on
+/// UTC. `century_register` comes from the FADT, without it the years
+/// 2000-2099 are assumed.
+pub fn read(century_register: Option<u8>) -> DateTime {
+    // an update can land in the middle of a read, so read until two agree
+    let mut raw = read_raw(century_register);
+    loop {
+        let again = read_raw(century_register);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 hour clock: 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match century_register {
+        Some(_) if raw.century != 0 => decode(raw.century) as u16,
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+        nanosecond: 0,
+    }
+}
@@ -18,6 +18,19 @@ fn main() {
     } else {
         cmd.arg("-drive").arg(format!("format=raw,file={bios_path}"));
     }
+    // attach a FAT image as a SATA disk on an AHCI controller if there is one
+    let disk_path = "disk.img";
+    if std::path::Path::new(disk_path).exists() {
+        cmd.arg("-device").arg("ahci,id=ahci");
+        cmd.arg("-drive").arg(format!("if=none,id=disk,format=raw,file={disk_path}"));
+        cmd.arg("-device").arg("ide-hd,drive=disk,bus=ahci.0");
+    }
+    // and every image passed in VIRTIO_DISKS (colon separated) as a virtio-blk disk
+    if let Ok(disks) = std::env::var("VIRTIO_DISKS") {
+        for disk in disks.split(':').filter(|disk| !disk.is_empty()) {
+            cmd.arg("-drive").arg(format!("if=virtio,format=raw,file={disk}"));
+        }
+    }
     println!("command: {:?}", cmd);
     let mut child = cmd.spawn().unwrap();
     child.wait().unwrap();